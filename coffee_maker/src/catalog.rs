@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use crate::order::Order;
+
+/// Price and points of a drink as published by the local server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CatalogEntry {
+    pub price: u64,
+    pub points: u64,
+}
+
+/// Products received from the local server with `CATALOG` when the coffee
+/// maker connects, so orders can name a drink instead of a points amount
+/// and every machine values it the same way.
+#[derive(Debug, Default, PartialEq)]
+pub struct Catalog {
+    products: HashMap<String, CatalogEntry>,
+}
+
+impl Catalog {
+    /// Parses `CATALOG,<id>:<price>:<points>;...`.
+    pub fn parse(line: &str) -> Result<Catalog, String> {
+        let encoded = line
+            .trim()
+            .strip_prefix("CATALOG,")
+            .ok_or(format!("Invalid catalog response {:?}", line))?;
+        let mut products = HashMap::new();
+        for product in encoded.split(';').filter(|p| !p.trim().is_empty()) {
+            let fields: Vec<&str> = product.split(':').map(|f| f.trim()).collect();
+            if fields.len() != 3 {
+                return Err(format!("Invalid catalog product {:?}", product));
+            }
+            let entry = CatalogEntry {
+                price: fields[1].parse::<u64>().map_err(|e| e.to_string())?,
+                points: fields[2].parse::<u64>().map_err(|e| e.to_string())?,
+            };
+            products.insert(fields[0].to_string(), entry);
+        }
+        Ok(Catalog { products })
+    }
+
+    /// Points of an order: buying a product earns its points, paying it
+    /// with points costs its price. Orders without product keep their
+    /// `coffee_points`.
+    pub fn points_for(&self, order: &Order) -> Result<u64, String> {
+        let product = match &order.product {
+            Some(product) => product,
+            None => return Ok(order.coffee_points),
+        };
+        let entry = self
+            .products
+            .get(product)
+            .ok_or(format!("Product {} is not in the catalog", product))?;
+        match order.operation.as_str() {
+            "ADD" => Ok(entry.points),
+            _ => Ok(entry.price),
+        }
+    }
+}
+
+#[cfg(test)]
+mod catalog_test {
+    use super::*;
+
+    fn order(operation: &str, product: Option<&str>) -> Order {
+        Order {
+            account_id: 1,
+            coffee_points: 7,
+            operation: operation.to_string(),
+            to_account_id: None,
+            product: product.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test01_when_parsing_a_catalog_should_price_its_products() {
+        let catalog = Catalog::parse("CATALOG,espresso:30:10;latte:45:15\n").unwrap();
+
+        assert_eq!(catalog.points_for(&order("ADD", Some("latte"))), Ok(15));
+        assert_eq!(catalog.points_for(&order("SUBS", Some("espresso"))), Ok(30));
+        assert_eq!(catalog.points_for(&order("SUBS", None)), Ok(7));
+    }
+
+    #[test]
+    fn test02_when_ordering_an_unknown_product_should_return_error() {
+        let catalog = Catalog::parse("CATALOG,").unwrap();
+
+        assert!(catalog.points_for(&order("ADD", Some("mocha"))).is_err());
+    }
+
+    #[test]
+    fn test03_when_parsing_an_invalid_catalog_should_return_error() {
+        assert!(Catalog::parse("NOT OK,UNAVAILABLE").is_err());
+        assert!(Catalog::parse("CATALOG,espresso:30").is_err());
+    }
+}
@@ -33,10 +33,7 @@ impl CoffeeMaker {
             return Err("[error] - probability must be a float number between 0 - 1".to_string());
         }
 
-        let orders_vector = match order_parser.read_orders() {
-            Ok(read_orders) => read_orders,
-            Err(msg) => return Err(msg),
-        };
+        let orders_vector = order_parser.read_orders()?;
 
         Ok(Self {
             probability,
@@ -184,6 +181,8 @@ mod coffee_maker_test {
                 coffee_points: 11,
                 account_id: 1,
                 operation: "ADD".to_string(),
+                to_account_id: None,
+                product: None,
             }])
         });
 
@@ -213,11 +212,15 @@ mod coffee_maker_test {
                     coffee_points: 11,
                     account_id: 1,
                     operation: "ADD".to_string(),
+                    to_account_id: None,
+                    product: None,
                 },
                 Order {
                     coffee_points: 4,
                     account_id: 2,
                     operation: "SUBS".to_string(),
+                    to_account_id: None,
+                    product: None,
                 },
             ])
         });
@@ -248,6 +251,8 @@ mod coffee_maker_test {
                 coffee_points: 11,
                 account_id: 1,
                 operation: "ADD".to_string(),
+                to_account_id: None,
+                product: None,
             }])
         });
 
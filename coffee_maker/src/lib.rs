@@ -1,4 +1,6 @@
+pub mod catalog;
 pub mod coffee_maker;
 pub mod messages;
 pub mod order;
+pub mod server_response;
 pub mod utils;
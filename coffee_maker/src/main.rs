@@ -1,23 +1,28 @@
 use log::{debug, error, info, warn};
-use std::time::Duration;
+use std::time::Instant;
 use std::{
     env,
     io::{BufRead, BufReader, Write},
-    net::TcpStream,
-    thread,
 };
 
 use actix::Actor;
 use coffee_maker::{
+    catalog::Catalog,
     coffee_maker::CoffeeMaker,
     messages::{
         points_consuming_order::PointsConsumingOrder, points_earning_order::PointEarningOrder,
         take_order::TakeOrder,
     },
-    utils::{order_parser::OrderParser, probablity_calculator::ProbabilityCalculator},
+    order::Order,
+    server_response::{Rejection, ServerResponse},
+    utils::{
+        config::CoffeeMakerConfig, connection::Connection, history::OperationHistory,
+        order_parser::OrderParser, probablity_calculator::ProbabilityCalculator,
+    },
 };
 
-fn send(stream: &mut TcpStream, message: String) -> Result<(), String> {
+fn send(stream: &mut BufReader<Connection>, message: String) -> Result<(), String> {
+    let stream = stream.get_mut();
     match stream.write(message.as_bytes()) {
         Ok(_) => match stream.flush() {
             Ok(_) => {
@@ -31,10 +36,13 @@ fn send(stream: &mut TcpStream, message: String) -> Result<(), String> {
     Err(String::from("Error writting expected message"))
 }
 
-fn read(stream: &mut TcpStream) -> Result<String, String> {
-    let mut buff = BufReader::new(stream.try_clone().unwrap());
+fn read(stream: &mut BufReader<Connection>) -> Result<String, String> {
     let mut response = String::new();
-    match buff.read_line(&mut response) {
+    match stream.read_line(&mut response) {
+        Ok(0) => {
+            error!("Server closed the session");
+            Err(String::from("Server closed the session"))
+        }
         Ok(_) => {
             info!("Read from TCP Stream success");
             Ok(String::from(response.trim()))
@@ -46,6 +54,98 @@ fn read(stream: &mut TcpStream) -> Result<String, String> {
     }
 }
 
+/// Reads the answer to a REQ, which waits for the token to reach the
+/// server, with its own deadline instead of the read timeout.
+fn read_req_answer(
+    stream: &mut BufReader<Connection>,
+    config: &CoffeeMakerConfig,
+) -> Result<String, String> {
+    let set_timeout = |stream: &mut BufReader<Connection>, timeout| {
+        if let Err(e) = stream.get_ref().set_read_timeout(Some(timeout)) {
+            warn!("Could not set the read timeout: {}", e);
+        }
+    };
+    set_timeout(stream, config.req_timeout());
+    let response = read(stream);
+    set_timeout(stream, config.read_timeout());
+    response
+}
+
+/// Connects to the server, sends the `CH` handshake and reads the product
+/// catalog. Fails if the server rejects the coffee maker.
+fn open_session(
+    id: u8,
+    config: &CoffeeMakerConfig,
+    coffee_maker_id: &str,
+) -> Result<(BufReader<Connection>, Catalog), String> {
+    let connection = Connection::open(id, config.tls.as_ref())
+        .map_err(|e| format!("Could not connect to server {}: {}", id, e))?;
+    if let Err(e) = connection.set_read_timeout(Some(config.read_timeout())) {
+        warn!("Could not set the read timeout: {}", e);
+    }
+    let mut stream = BufReader::new(connection);
+    info!("Connected to the server!");
+    let handshake = format!(
+        "CH,{},{},{}\n",
+        coffee_maker_id,
+        config.location.as_deref().unwrap_or(""),
+        env!("CARGO_PKG_VERSION")
+    );
+    send(&mut stream, handshake.clone())?;
+    info!("Send {:?} message to Server", handshake);
+
+    let response = send(&mut stream, "CATALOG\n".to_string()).and_then(|_| read(&mut stream));
+    // The server answers the first message of an unregistered coffee maker,
+    // or of any coffee maker while it shuts down, with the rejection and
+    // closes the connection.
+    if let Ok(response) = &response {
+        match ServerResponse::parse(response).rejection() {
+            Some(Rejection::Unregistered) => {
+                return Err(format!("Server {} does not accept {}", id, coffee_maker_id))
+            }
+            Some(Rejection::ShuttingDown) => return Err(format!("Server {} is shutting down", id)),
+            _ => {}
+        }
+    }
+    let catalog = match response.and_then(|response| Catalog::parse(&response)) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            warn!("Could not get the product catalog: {}", e);
+            Catalog::default()
+        }
+    };
+    Ok((stream, catalog))
+}
+
+fn report_rejection(operation: &str, response: &ServerResponse) {
+    match response.rejection() {
+        Some(rejection) => error!(
+            "{} rejected by server: {} ({:?})",
+            operation,
+            rejection.customer_message(),
+            rejection
+        ),
+        None => error!("Unexpected response from server: {:?}", response),
+    }
+}
+
+/// Checks the session is still alive: the server must answer PONG.
+fn heartbeat(stream: &mut BufReader<Connection>) -> Result<(), String> {
+    send(stream, "PING\n".to_string())?;
+    match ServerResponse::parse(&read(stream)?) {
+        ServerResponse::Pong => Ok(()),
+        response => Err(format!("Unexpected heartbeat answer {:?}", response)),
+    }
+}
+
+/// Optional `, <product>` field of ADD and REQ messages.
+fn product_field(order: &Order) -> String {
+    match &order.product {
+        Some(product) => format!(", {}", product),
+        None => String::new(),
+    }
+}
+
 #[actix_rt::main]
 async fn main() {
     env_logger::init();
@@ -54,48 +154,82 @@ async fn main() {
     let id: u8 = args[1].parse::<u8>().expect("Could not parse number");
     let probability: f64 = args[2].parse::<f64>().expect("Could not parse number");
     let orders_file: String = args[3].clone();
+    let config = match args.get(4) {
+        Some(file_name) => CoffeeMakerConfig::from_file(file_name).expect("Could not read config"),
+        None => CoffeeMakerConfig::default(),
+    };
+    // Names this coffee maker in the server ledger, logs and history.
+    let coffee_maker_id = config
+        .machine_id
+        .clone()
+        .unwrap_or(format!("coffee-{}", std::process::id()));
+    let mut history = match &config.history_file {
+        Some(file_name) => OperationHistory::new(coffee_maker_id.clone(), file_name)
+            .expect("Could not open history file"),
+        None => OperationHistory::disabled(),
+    };
 
     debug!("WILL CONNECT TO SERVER id: {}, ", id);
 
     let probablity_calculator = ProbabilityCalculator::new();
-    let order_parser = OrderParser::new(String::from(orders_file));
+    let order_parser = OrderParser::new(orders_file);
 
     let coffee_maker_actor =
         CoffeeMaker::new(probability, probablity_calculator, order_parser).unwrap();
     let addr = coffee_maker_actor.start();
     info!("CoffeeMaker actor is active");
 
-    if let Ok(mut stream) = TcpStream::connect(format!("127.0.0.1:888{}", id)) {
-        info!("Connected to the server!");
-        let response_message = "CH\n".to_string();
-        match send(&mut stream, response_message.clone()) {
-            Ok(_) => info!("Send {:?} message to Server", response_message),
-            Err(e) => error!("{}", e),
+    let (mut stream, mut catalog) = match open_session(id, &config, &coffee_maker_id) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("{}", e);
+            return;
         }
+    };
+    // A read that failed or timed out leaves the answers of the server out
+    // of step with the requests, so the session is replaced before the next
+    // order. The server unblocks the REQ left without SUBS or UNBL.
+    let mut session_lost = false;
 
-        loop {
-            let mut next_order;
-            thread::sleep(Duration::from_secs(3));
+    let mut last_heartbeat = Instant::now();
+    loop {
+        let mut next_order;
+        actix_rt::time::sleep(config.order_delay()).await;
 
-            let take_order_result = addr.send(TakeOrder {}).await;
-            match take_order_result {
-                Ok(_next_order) => match _next_order {
-                    Some(order) => {
-                        info!("New order");
-                        next_order = order
-                    }
-                    None => {
-                        info!("There are no more orders left to prepare");
-                        let end_message = "BYE \n".to_string();
-                        match send(&mut stream, end_message) {
-                            Ok(_) => {}
-                            Err(e) => error!("{}", e),
-                        }
-                        break;
-                    }
-                },
-                Err(_) => {
-                    warn!("There are no more orders left to prepare");
+        if session_lost {
+            warn!("Session with server {} lost, reconnecting", id);
+            match open_session(id, &config, &coffee_maker_id) {
+                Ok((new_stream, new_catalog)) => {
+                    stream = new_stream;
+                    catalog = new_catalog;
+                    session_lost = false;
+                    last_heartbeat = Instant::now();
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        if last_heartbeat.elapsed() >= config.heartbeat_interval() {
+            if let Err(e) = heartbeat(&mut stream) {
+                error!("Session with server {} is dead: {}", id, e);
+                session_lost = true;
+                continue;
+            }
+            last_heartbeat = Instant::now();
+        }
+
+        let take_order_result = addr.send(TakeOrder {}).await;
+        match take_order_result {
+            Ok(_next_order) => match _next_order {
+                Some(order) => {
+                    info!("New order");
+                    next_order = order
+                }
+                None => {
+                    info!("There are no more orders left to prepare");
                     let end_message = "BYE \n".to_string();
                     match send(&mut stream, end_message) {
                         Ok(_) => {}
@@ -103,113 +237,219 @@ async fn main() {
                     }
                     break;
                 }
+            },
+            Err(_) => {
+                warn!("There are no more orders left to prepare");
+                let end_message = "BYE \n".to_string();
+                match send(&mut stream, end_message) {
+                    Ok(_) => {}
+                    Err(e) => error!("{}", e),
+                }
+                break;
             }
+        }
 
-            if next_order.operation == "ADD" {
-                if addr
-                    .send(PointEarningOrder {
-                        coffe_points: next_order.coffee_points,
-                    })
-                    .await
-                    .unwrap()
-                {
-                    let response_message = format!(
-                        "{}, {}, {} \n",
-                        next_order.operation, next_order.account_id, next_order.coffee_points
-                    );
-                    match send(&mut stream, response_message.clone()) {
-                        Ok(_) => info!("Send {:?} message to Server", response_message),
-                        Err(e) => error!("{}", e),
-                    }
+        match catalog.points_for(&next_order) {
+            Ok(points) => next_order.coffee_points = points,
+            Err(e) => {
+                error!("Skipping order: {}", e);
+                continue;
+            }
+        }
 
-                    // 4.  Waits for ACK
-                    info!("Wait for ACK response from server");
-                    match read(&mut stream) {
-                        Ok(response) => {
-                            info!("Read response from server after writing");
-                            if response == "ACK" {
-                                info!("ACK from server");
-                            } else {
-                                error!("Not ACK from server")
-                            }
-                        }
-                        Err(e) => error!("{}", e),
-                    }
-                } else {
-                    info!("The ADD operation could not be performed");
-                }
-            } else {
-                // 1. Ask for points
-                let request_message = format!(
-                    "REQ, {}, {} \n",
-                    next_order.account_id, next_order.coffee_points
+        if next_order.operation == "ADD" {
+            if addr
+                .send(PointEarningOrder {
+                    coffe_points: next_order.coffee_points,
+                })
+                .await
+                .unwrap()
+            {
+                let response_message = format!(
+                    "{}, {}, {}{} \n",
+                    next_order.operation,
+                    next_order.account_id,
+                    next_order.coffee_points,
+                    product_field(&next_order)
                 );
-                match send(&mut stream, request_message) {
-                    Ok(_) => info!("Send REQ message to Server"),
-                    Err(e) => error!("{}", e),
+                match send(&mut stream, response_message.clone()) {
+                    Ok(_) => info!("Send {:?} message to Server", response_message),
+                    Err(e) => {
+                        error!("{}", e);
+                        session_lost = true;
+                    }
                 }
 
-                // 2. Wait for OK response
-                info!("Wait for OK response from server");
+                // 4.  Waits for ACK
+                info!("Wait for ACK response from server");
+                let mut acknowledged = false;
                 match read(&mut stream) {
                     Ok(response) => {
-                        info!("Read response from server: {:?}", response);
-                        if response == "OK" {
-                            info!("OK from server");
-                            match next_order.operation.as_str() {
-                                "SUBS" => {
-                                    if !addr
-                                        .send(PointsConsumingOrder {
-                                            coffe_points: next_order.coffee_points,
-                                        })
-                                        .await
-                                        .unwrap()
-                                    {
-                                        next_order.operation = "UNBL".to_string();
-                                    }
-                                    info!("The SUBS operation could not be performed");
-                                }
-                                _ => {
-                                    error!("Invalid Order operation");
-                                    next_order.operation = "UNBL".to_string();
-                                }
-                            }
+                        info!("Read response from server after writing");
+                        let response = ServerResponse::parse(&response);
+                        if response == ServerResponse::Ack {
+                            info!("ACK from server");
+                            acknowledged = true;
                         } else {
-                            error!("Not OK from server");
-                            next_order.operation = "UNBL".to_string();
+                            report_rejection("ADD", &response);
                         }
                     }
                     Err(e) => {
                         error!("{}", e);
-                        next_order.operation = "UNBL".to_string();
+                        session_lost = true;
                     }
                 }
-                // 3. Send results
-                let response_message = format!(
-                    "{}, {}, {} \n",
-                    next_order.operation, next_order.account_id, next_order.coffee_points
+                history.record(
+                    "ADD",
+                    next_order.account_id,
+                    next_order.coffee_points,
+                    acknowledged,
                 );
-                match send(&mut stream, response_message.clone()) {
-                    Ok(_) => info!("Send {:?} message to Server", response_message),
-                    Err(e) => error!("{}", e),
+            } else {
+                info!("The ADD operation could not be performed");
+            }
+        } else if next_order.operation == "TRANSFER" {
+            let to_account_id = match next_order.to_account_id {
+                Some(to_account_id) => to_account_id,
+                None => {
+                    error!("TRANSFER order without destination account");
+                    continue;
+                }
+            };
+            let transfer_message = format!(
+                "TRANSFER, {}, {}, {} \n",
+                next_order.account_id, to_account_id, next_order.coffee_points
+            );
+            match send(&mut stream, transfer_message.clone()) {
+                Ok(_) => info!("Send {:?} message to Server", transfer_message),
+                Err(e) => {
+                    error!("{}", e);
+                    session_lost = true;
                 }
+            }
 
-                // 4.  Waits for ACK
-                info!("Wait for ACK response from server");
-                match read(&mut stream) {
-                    Ok(response) => {
-                        info!("Read response from server after writing");
-                        if response == "ACK" {
-                            info!("ACK from server");
-                        } else {
-                            error!("Not ACK from server")
+            info!("Wait for ACK response from server");
+            let mut acknowledged = false;
+            match read(&mut stream) {
+                Ok(response) => {
+                    let response = ServerResponse::parse(&response);
+                    if response == ServerResponse::Ack {
+                        info!("ACK from server");
+                        acknowledged = true;
+                    } else {
+                        report_rejection("TRANSFER", &response);
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    session_lost = true;
+                }
+            }
+            history.record(
+                "TRANSFER",
+                next_order.account_id,
+                next_order.coffee_points,
+                acknowledged,
+            );
+        } else {
+            // 1. Ask for points
+            let request_message = format!(
+                "REQ, {}, {}{} \n",
+                next_order.account_id,
+                next_order.coffee_points,
+                product_field(&next_order)
+            );
+            match send(&mut stream, request_message) {
+                Ok(_) => info!("Send REQ message to Server"),
+                Err(e) => {
+                    error!("{}", e);
+                    session_lost = true;
+                }
+            }
+
+            // 2. Wait for OK response
+            info!("Wait for OK response from server");
+            let response = read_req_answer(&mut stream, &config).map(|r| ServerResponse::parse(&r));
+            history.record(
+                "REQ",
+                next_order.account_id,
+                next_order.coffee_points,
+                matches!(response, Ok(ServerResponse::Ok)),
+            );
+            match response {
+                Ok(response) => {
+                    info!("Read response from server: {:?}", response);
+                    if response == ServerResponse::Ok {
+                        info!("OK from server");
+                        match next_order.operation.as_str() {
+                            "SUBS" => {
+                                if !addr
+                                    .send(PointsConsumingOrder {
+                                        coffe_points: next_order.coffee_points,
+                                    })
+                                    .await
+                                    .unwrap()
+                                {
+                                    next_order.operation = "UNBL".to_string();
+                                }
+                                info!("The SUBS operation could not be performed");
+                            }
+                            _ => {
+                                error!("Invalid Order operation");
+                                next_order.operation = "UNBL".to_string();
+                            }
                         }
+                    } else {
+                        report_rejection("REQ", &response);
+                        next_order.operation = "UNBL".to_string();
                     }
-                    Err(e) => error!("{}", e),
+                }
+                Err(e) => {
+                    // An UNBL now would be answered by the late reply
+                    // to the REQ.
+                    error!("{}", e);
+                    session_lost = true;
+                    continue;
+                }
+            }
+            // 3. Send results
+            let response_message = format!(
+                "{}, {}, {} \n",
+                next_order.operation, next_order.account_id, next_order.coffee_points
+            );
+            match send(&mut stream, response_message.clone()) {
+                Ok(_) => info!("Send {:?} message to Server", response_message),
+                Err(e) => {
+                    error!("{}", e);
+                    session_lost = true;
+                }
+            }
+
+            // 4.  Waits for ACK
+            info!("Wait for ACK response from server");
+            let mut acknowledged = false;
+            match read(&mut stream) {
+                Ok(response) => {
+                    info!("Read response from server after writing");
+                    let response = ServerResponse::parse(&response);
+                    if response == ServerResponse::Ack {
+                        info!("ACK from server");
+                        acknowledged = true;
+                    } else {
+                        report_rejection(&next_order.operation, &response);
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    session_lost = true;
                 }
             }
+            history.record(
+                &next_order.operation,
+                next_order.account_id,
+                next_order.coffee_points,
+                acknowledged,
+            );
         }
-    } else {
-        error!("Couldn't connect to server...");
     }
 }
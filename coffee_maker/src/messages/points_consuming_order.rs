@@ -3,5 +3,5 @@ use actix::Message;
 #[derive(Message)]
 #[rtype(result = "bool")]
 pub struct PointsConsumingOrder {
-    pub coffe_points: i32,
+    pub coffe_points: u64,
 }
@@ -1,7 +1,7 @@
-use actix::Message;
-
-#[derive(Message)]
-#[rtype(result = "bool")]
-pub struct PointEarningOrder {
-    pub coffe_points: i32,
-}
+use actix::Message;
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct PointEarningOrder {
+    pub coffe_points: u64,
+}
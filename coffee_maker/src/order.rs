@@ -2,7 +2,16 @@ use serde_derive::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct Order {
-    pub account_id: i32,
-    pub coffee_points: i32,
+    pub account_id: u32,
+    /// Points of the order, looked up in the catalog when `product` is set.
+    #[serde(default)]
+    pub coffee_points: u64,
     pub operation: String,
+    /// Destination account of a TRANSFER order.
+    #[serde(default)]
+    pub to_account_id: Option<u32>,
+    /// Product ordered, priced from the catalog and sent with ADD and REQ
+    /// so the server can apply promotions.
+    #[serde(default)]
+    pub product: Option<String>,
 }
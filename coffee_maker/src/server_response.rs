@@ -0,0 +1,162 @@
+/// Reason sent by the server when it rejects an operation.
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    AccountNotFound,
+    AccountFrozen,
+    AccountClosed,
+    InsufficientPoints,
+    NotBlocked,
+    NoReservation,
+    NotTokenHolder,
+    InvalidPoints,
+    Unavailable,
+    UnknownProduct,
+    RateLimited,
+    Unregistered,
+    ShuttingDown,
+    Unknown(String),
+}
+
+impl Rejection {
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "ACCOUNT_NOT_FOUND" => Rejection::AccountNotFound,
+            "ACCOUNT_FROZEN" => Rejection::AccountFrozen,
+            "ACCOUNT_CLOSED" => Rejection::AccountClosed,
+            "INSUFFICIENT_POINTS" => Rejection::InsufficientPoints,
+            "NOT_BLOCKED" => Rejection::NotBlocked,
+            "NO_RESERVATION" => Rejection::NoReservation,
+            "NOT_TOKEN_HOLDER" => Rejection::NotTokenHolder,
+            "INVALID_POINTS" => Rejection::InvalidPoints,
+            "UNAVAILABLE" => Rejection::Unavailable,
+            "UNKNOWN_PRODUCT" => Rejection::UnknownProduct,
+            "RATE_LIMITED" => Rejection::RateLimited,
+            "UNREGISTERED" => Rejection::Unregistered,
+            "SHUTTING_DOWN" => Rejection::ShuttingDown,
+            other => Rejection::Unknown(other.to_string()),
+        }
+    }
+
+    /// Message that can be shown to the customer at the coffee maker.
+    pub fn customer_message(&self) -> &str {
+        match self {
+            Rejection::AccountNotFound => "the loyalty account does not exist",
+            Rejection::AccountFrozen => "the loyalty account is frozen, points cannot be used",
+            Rejection::AccountClosed => "the loyalty account is closed",
+            Rejection::InsufficientPoints => "there are not enough points in the account",
+            Rejection::NotBlocked | Rejection::NoReservation => {
+                "the points were not reserved for this order"
+            }
+            Rejection::NotTokenHolder | Rejection::Unavailable => {
+                "the loyalty service is busy, please try again"
+            }
+            Rejection::InvalidPoints => "the points amount is not valid",
+            Rejection::UnknownProduct => "the product is not sold here",
+            Rejection::RateLimited => "too many operations with this account, please ask the staff",
+            Rejection::Unregistered => "this coffee maker is out of service",
+            Rejection::ShuttingDown => "the loyalty service is restarting, please try again later",
+            Rejection::Unknown(_) => "the operation could not be performed",
+        }
+    }
+}
+
+/// Response line read from the local server.
+#[derive(Debug, PartialEq)]
+pub enum ServerResponse {
+    Ok,
+    Ack,
+    NotOk(Rejection),
+    NotAck(Rejection),
+    /// Answer to a heartbeat.
+    Pong,
+    Unknown(String),
+}
+
+impl ServerResponse {
+    pub fn parse(line: &str) -> Self {
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        let code = parts.get(1).copied().unwrap_or("");
+        match parts[0] {
+            "OK" => ServerResponse::Ok,
+            "ACK" => ServerResponse::Ack,
+            "PONG" => ServerResponse::Pong,
+            "NOT OK" => ServerResponse::NotOk(Rejection::from_code(code)),
+            "NOT ACK" => ServerResponse::NotAck(Rejection::from_code(code)),
+            _ => ServerResponse::Unknown(line.to_string()),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, ServerResponse::Ok | ServerResponse::Ack)
+    }
+
+    pub fn rejection(&self) -> Option<&Rejection> {
+        match self {
+            ServerResponse::NotOk(rejection) | ServerResponse::NotAck(rejection) => Some(rejection),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod server_response_test {
+    use super::*;
+
+    #[test]
+    fn test01_when_parsing_ok_and_ack_should_be_successful() {
+        assert_eq!(ServerResponse::parse("OK"), ServerResponse::Ok);
+        assert_eq!(ServerResponse::parse("ACK"), ServerResponse::Ack);
+        assert!(ServerResponse::parse("OK").is_success());
+    }
+
+    #[test]
+    fn test02_when_parsing_a_rejection_should_return_its_reason() {
+        let response = ServerResponse::parse("NOT OK,INSUFFICIENT_POINTS");
+
+        assert_eq!(
+            response,
+            ServerResponse::NotOk(Rejection::InsufficientPoints)
+        );
+        assert!(!response.is_success());
+        assert_eq!(
+            response.rejection().unwrap().customer_message(),
+            "there are not enough points in the account"
+        );
+    }
+
+    #[test]
+    fn test03_when_parsing_a_rejection_without_code_should_be_unknown() {
+        assert_eq!(
+            ServerResponse::parse("NOT ACK"),
+            ServerResponse::NotAck(Rejection::Unknown(String::new()))
+        );
+    }
+
+    #[test]
+    fn test04_when_parsing_an_unexpected_line_should_be_unknown() {
+        assert_eq!(
+            ServerResponse::parse("UNK"),
+            ServerResponse::Unknown("UNK".to_string())
+        );
+    }
+
+    #[test]
+    fn test05_when_parsing_a_pong_should_not_be_a_success() {
+        let response = ServerResponse::parse("PONG");
+
+        assert_eq!(response, ServerResponse::Pong);
+        assert!(!response.is_success());
+        assert!(response.rejection().is_none());
+    }
+
+    #[test]
+    fn test06_when_parsing_a_shutdown_rejection_should_ask_to_retry_later() {
+        let response = ServerResponse::parse("NOT ACK,SHUTTING_DOWN");
+
+        assert_eq!(response, ServerResponse::NotAck(Rejection::ShuttingDown));
+        assert_eq!(
+            response.rejection().unwrap().customer_message(),
+            "the loyalty service is restarting, please try again later"
+        );
+    }
+}
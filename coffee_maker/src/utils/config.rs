@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+
+use super::connection::TlsSettings;
+use super::file_reader::FileReader;
+
+const DEFAULT_READ_TIMEOUT_MILLIS: u64 = 10000;
+const DEFAULT_REQ_TIMEOUT_MILLIS: u64 = 40000;
+const DEFAULT_HEARTBEAT_INTERVAL_MILLIS: u64 = 10000;
+const DEFAULT_ORDER_DELAY_MILLIS: u64 = 3000;
+
+/// Optional settings of a coffee maker, read from the JSON file given as
+/// fourth argument.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CoffeeMakerConfig {
+    pub history_file: Option<String>,
+    /// Id the coffee maker registers with, `coffee-<pid>` if absent.
+    pub machine_id: Option<String>,
+    pub location: Option<String>,
+    /// Certificates to talk to the server over mutual TLS.
+    pub tls: Option<TlsSettings>,
+    /// Time to wait for an answer of the server before giving up.
+    pub read_timeout_millis: Option<u64>,
+    /// Time to wait for the answer of a REQ, which waits for the token to
+    /// go around the ring. Must be at least the `token_timeout_millis` of
+    /// the servers.
+    pub req_timeout_millis: Option<u64>,
+    /// How often the coffee maker sends PING to keep its session alive.
+    pub heartbeat_interval_millis: Option<u64>,
+    /// Pause before taking each order.
+    pub order_delay_millis: Option<u64>,
+}
+
+impl CoffeeMakerConfig {
+    pub fn from_file(file_name: &String) -> Result<CoffeeMakerConfig, String> {
+        let contents = FileReader::read(file_name)?;
+        let config =
+            serde_json::from_str::<CoffeeMakerConfig>(&contents).map_err(|e| e.to_string())?;
+        if config.req_timeout() < config.read_timeout() {
+            return Err("The REQ timeout must be at least the read timeout".to_string());
+        }
+        Ok(config)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.read_timeout_millis
+                .unwrap_or(DEFAULT_READ_TIMEOUT_MILLIS),
+        )
+    }
+
+    pub fn req_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.req_timeout_millis
+                .unwrap_or(DEFAULT_REQ_TIMEOUT_MILLIS),
+        )
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(
+            self.heartbeat_interval_millis
+                .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MILLIS),
+        )
+    }
+
+    pub fn order_delay(&self) -> Duration {
+        Duration::from_millis(
+            self.order_delay_millis
+                .unwrap_or(DEFAULT_ORDER_DELAY_MILLIS),
+        )
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::CoffeeMakerConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test01_when_reading_an_empty_config_should_use_defaults() {
+        let file_name = String::from("resources/test/empty_config.json");
+        let config = CoffeeMakerConfig::from_file(&file_name).unwrap();
+
+        assert!(config.history_file.is_none());
+    }
+
+    #[test]
+    fn test02_when_reading_a_config_with_history_should_return_its_file() {
+        let file_name = String::from("resources/test/history_config.json");
+        let config = CoffeeMakerConfig::from_file(&file_name).unwrap();
+
+        assert_eq!(config.history_file, Some("history-coffee.log".to_string()));
+    }
+
+    #[test]
+    fn test03_when_reading_a_config_with_tls_should_return_its_certificates() {
+        let file_name = String::from("resources/test/tls_config.json");
+        let config = CoffeeMakerConfig::from_file(&file_name).unwrap();
+        let tls = config.tls.unwrap();
+
+        assert_eq!(tls.cert_file, "certs/coffee-1.pem");
+        assert_eq!(tls.key_file, "certs/coffee-1.key");
+        assert_eq!(tls.ca_file, "certs/ca.pem");
+    }
+
+    #[test]
+    fn test04_when_reading_a_config_with_registration_should_return_its_identity() {
+        let file_name = String::from("resources/test/registration_config.json");
+        let config = CoffeeMakerConfig::from_file(&file_name).unwrap();
+
+        assert_eq!(config.machine_id, Some("coffee-1".to_string()));
+        assert_eq!(config.location, Some("palermo".to_string()));
+    }
+
+    #[test]
+    fn test05_when_reading_a_config_with_session_times_should_return_them() {
+        let file_name = String::from("resources/test/session_config.json");
+        let config = CoffeeMakerConfig::from_file(&file_name).unwrap();
+
+        assert_eq!(config.read_timeout(), Duration::from_millis(2000));
+        assert_eq!(config.req_timeout(), Duration::from_millis(25000));
+        assert_eq!(config.heartbeat_interval(), Duration::from_millis(5000));
+        assert_eq!(config.order_delay(), Duration::from_millis(500));
+        assert_eq!(
+            CoffeeMakerConfig::default().read_timeout(),
+            Duration::from_millis(10000)
+        );
+        assert_eq!(
+            CoffeeMakerConfig::default().req_timeout(),
+            Duration::from_millis(40000)
+        );
+    }
+
+    #[test]
+    fn test06_when_the_req_timeout_is_shorter_than_the_read_timeout_should_fail() {
+        let file_name = String::from("resources/test/short_req_timeout_config.json");
+
+        assert!(CoffeeMakerConfig::from_file(&file_name).is_err());
+    }
+}
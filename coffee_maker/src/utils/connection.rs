@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_derive::Deserialize;
+
+/// Certificate of the coffee maker, named `coffee-<something>`, its private
+/// key and the certificate of the authority of the servers. All PEM files.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    pub ca_file: String,
+}
+
+/// Connection with the local server, encrypted if TLS is configured.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Connects to the server with the given id. With TLS the server must
+    /// present a certificate for `server-<id>`.
+    pub fn open(id: u8, tls: Option<&TlsSettings>) -> Result<Connection, String> {
+        let stream =
+            TcpStream::connect(format!("127.0.0.1:888{}", id)).map_err(|e| e.to_string())?;
+        let settings = match tls {
+            Some(settings) => settings,
+            None => return Ok(Connection::Plain(stream)),
+        };
+        let name = ServerName::try_from(format!("server-{}", id)).map_err(|e| e.to_string())?;
+        let connection =
+            ClientConnection::new(client_config(settings)?, name).map_err(|e| e.to_string())?;
+        Ok(Connection::Tls(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+
+    /// Makes reads fail instead of blocking forever on a dead server.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Plain(s) => s.set_read_timeout(timeout),
+            Connection::Tls(s) => s.sock.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(s) => s.read(buf),
+            Connection::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(s) => s.write(buf),
+            Connection::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(s) => s.flush(),
+            Connection::Tls(s) => s.flush(),
+        }
+    }
+}
+
+fn client_config(settings: &TlsSettings) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&settings.ca_file)? {
+        roots.add(cert).map_err(|e| e.to_string())?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(
+            load_certs(&settings.cert_file)?,
+            load_key(&settings.key_file)?,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path, e))?
+        .ok_or(format!("{}: no private key", path))
+}
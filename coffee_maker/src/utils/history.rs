@@ -0,0 +1,81 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+
+/// Append-only log of the operations sent to the server, using the same
+/// line format the local server history checker reads:
+/// `<timestamp>,<origin>,<operation>,<customer_id>,<points>,<outcome>,-`
+pub struct OperationHistory {
+    origin: String,
+    file: Option<File>,
+}
+
+impl OperationHistory {
+    pub fn new(origin: String, file_name: &str) -> Result<OperationHistory, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_name)
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            origin,
+            file: Some(file),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            origin: String::new(),
+            file: None,
+        }
+    }
+
+    pub fn record(&mut self, operation: &str, account_id: u32, points: u64, success: bool) {
+        if let Some(file) = self.file.as_mut() {
+            let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(duration) => duration.as_millis(),
+                Err(_) => 0,
+            };
+            let outcome = if success { "OK" } else { "ERR" };
+            if writeln!(
+                file,
+                "{},{},{},{},{},{},-",
+                timestamp, self.origin, operation, account_id, points, outcome
+            )
+            .is_err()
+            {
+                error!("Could not write history entry");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+    use super::OperationHistory;
+
+    #[test]
+    fn test01_when_recording_an_operation_should_append_a_line() {
+        let path = std::env::temp_dir().join("coffee_maker_history_test.log");
+        let _ = std::fs::remove_file(&path);
+        let mut history =
+            OperationHistory::new("coffee-1".to_string(), path.to_str().unwrap()).unwrap();
+
+        history.record("REQ", 1, 10, true);
+        history.record("SUBS", 1, 10, false);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(",coffee-1,REQ,1,10,OK,-"));
+        assert!(lines[1].ends_with(",coffee-1,SUBS,1,10,ERR,-"));
+    }
+
+    #[test]
+    fn test02_when_history_is_disabled_should_not_fail() {
+        let mut history = OperationHistory::disabled();
+        history.record("ADD", 1, 10, true);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod config;
+pub mod connection;
 pub mod file_reader;
+pub mod history;
 pub mod order_parser;
 pub mod probablity_calculator;
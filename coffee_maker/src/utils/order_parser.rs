@@ -73,4 +73,27 @@ mod order_parser_test {
         let result = order_parser.read_orders();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test05_when_parsing_a_file_with_negative_points_should_return_error() {
+        let order_parser = OrderParser::new(String::from("resources/test/negative_points.json"));
+        let result = order_parser.read_orders();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test06_when_parsing_a_transfer_order_should_return_its_destination() {
+        let order_parser = OrderParser::new(String::from("resources/test/transfer_order.json"));
+        let orders = order_parser.read_orders().unwrap();
+        assert_eq!(orders[0].operation, "TRANSFER");
+        assert_eq!(orders[0].to_account_id, Some(2));
+    }
+
+    #[test]
+    fn test07_when_parsing_an_order_with_product_should_return_it() {
+        let order_parser = OrderParser::new(String::from("resources/test/product_order.json"));
+        let orders = order_parser.read_orders().unwrap();
+        assert_eq!(orders[0].product, Some("espresso".to_string()));
+        assert!(orders[0].to_account_id.is_none());
+    }
 }
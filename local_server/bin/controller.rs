@@ -1,18 +1,39 @@
 use std::{
     env,
-    io::{self, Write},
+    io::{self, BufRead, BufReader, Read, Write},
     net::TcpStream,
 };
 
+use local_server::structs::controller_auth::Operator;
+use local_server::utils::config::ControllerConfig;
+use local_server::utils::tls::{self, TlsSettings};
 use log::{error, info};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+
+trait Channel: Read + Write {}
+
+impl<T: Read + Write> Channel for T {}
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
     let id: u8 = args[1].parse::<u8>().expect("Could not parse number");
+    let config = match args.get(2) {
+        Some(path) => ControllerConfig::from_file(path).expect("Could not read config file"),
+        None => ControllerConfig::default(),
+    };
 
-    if let Ok(mut stream) = TcpStream::connect(format!("127.0.0.1:888{}", id)) {
+    if let Ok(stream) = TcpStream::connect(format!("127.0.0.1:888{}", id)) {
+        let stream: Box<dyn Channel> = match &config.tls {
+            Some(settings) => Box::new(tls_stream(stream, id, settings)?),
+            None => Box::new(stream),
+        };
+        let mut stream = BufReader::new(stream);
         let init_message = "CTRL\n".to_string();
         send(&mut stream, init_message).expect("Send fail");
+        if let Some(operator) = &config.operator {
+            authenticate(&mut stream, operator)?;
+        }
 
         loop {
             let mut buff = String::new();
@@ -30,7 +51,39 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
-fn send(stream: &mut TcpStream, message: String) -> Result<(), String> {
+/// Connection authenticated with the controller certificate.
+fn tls_stream(
+    stream: TcpStream,
+    id: u8,
+    settings: &TlsSettings,
+) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let config = tls::client_config(settings).map_err(io::Error::other)?;
+    let name = ServerName::try_from(tls::server_name(id)).map_err(io::Error::other)?;
+    let connection = ClientConnection::new(config, name).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// Answers `CHALLENGE,<nonce>` with `AUTH,<operator>,<answer>`.
+fn authenticate(stream: &mut BufReader<Box<dyn Channel>>, operator: &Operator) -> io::Result<()> {
+    let mut challenge = String::new();
+    stream.read_line(&mut challenge)?;
+    let nonce = match challenge.trim().split_once(',') {
+        Some(("CHALLENGE", nonce)) => nonce,
+        _ => return Err(io::Error::other("Server did not send a challenge")),
+    };
+    let answer = format!("AUTH,{},{}\n", operator.name, operator.answer(nonce));
+    send(stream, answer).map_err(io::Error::other)?;
+    let mut response = String::new();
+    stream.read_line(&mut response)?;
+    if response.trim() != "ACK" {
+        return Err(io::Error::other(format!("Rejected: {}", response.trim())));
+    }
+    info!("Authenticated as {}", operator.name);
+    Ok(())
+}
+
+fn send(stream: &mut BufReader<Box<dyn Channel>>, message: String) -> Result<(), String> {
+    let stream = stream.get_mut();
     match stream.write(message.as_bytes()) {
         Ok(_) => match stream.flush() {
             Ok(_) => {
@@ -0,0 +1,45 @@
+use std::{env, fs, process};
+
+use local_server::structs::history::HistoryEntry;
+use local_server::utils::history_checker::HistoryChecker;
+
+fn main() {
+    let files: Vec<String> = env::args().skip(1).collect();
+    if files.is_empty() {
+        eprintln!("Usage: history_checker <history_file>...");
+        process::exit(2);
+    }
+
+    let mut entries = vec![];
+    for file in files.iter() {
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Could not read {}: {}", file, e);
+                process::exit(2);
+            }
+        };
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match HistoryEntry::parse(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => eprintln!("{}:{} skipped: {}", file, number + 1, e),
+            }
+        }
+    }
+
+    let total = entries.len();
+    let violations = HistoryChecker::new(entries).check();
+    println!("Checked {} operations from {} files", total, files.len());
+    if violations.is_empty() {
+        println!("No invariant violations found");
+        return;
+    }
+    for violation in violations.iter() {
+        print!("{}", violation);
+    }
+    println!("{} invariant violations found", violations.len());
+    process::exit(1);
+}
@@ -1,28 +1,326 @@
 extern crate actix;
 
-use actix::{Actor, Handler, SyncContext};
-use log::{error, info};
+use actix::{Actor, Handler, MessageResult, SyncContext};
+use log::{error, info, warn};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 
-use crate::structs::account::Account;
+use crate::structs::account::{today, Account};
+use crate::structs::account_status::{now_millis, AccountState, AccountStatus};
+use crate::structs::catalog::Catalog;
+use crate::structs::hash_ring::HashRing;
+use crate::structs::history::OperationHistory;
+use crate::structs::ledger::{LedgerEntry, LedgerKind, DEFAULT_LEDGER_SIZE};
 use crate::structs::messages::{
-    AddPoints, BlockPoints, SubtractPoints, SyncAccount, SyncNextServer, UnblockPoints,
+    AddPoints, AdjustPoints, BlockPoints, ChangeStatus, CheckCredit, CoffeeOrder, CreditPoints,
+    ExpirePoints, FlaggedAccounts, GetCatalog, GetLedger, GlobalBlockedPoints, MergeEarned,
+    PendingGossip, PendingReplication, RegisterCoffeeMaker, StopAccepting, SubtractPoints,
+    SyncAccount, SyncNextServer, UnblockPoints,
 };
+use crate::structs::points::{Points, PointsError};
+use crate::structs::promotions::{current_hour, OrderContext, PromotedOperation, Promotions};
+use crate::structs::rate_limits::RateLimiter;
+use crate::structs::registration::{check_registration, AllowedCoffeeMaker};
+use crate::structs::server_error::ServerError;
+use crate::structs::tiers::TierPolicy;
+use crate::utils::config::ServerConfig;
 
 #[allow(dead_code)]
 pub struct LocalServer {
+    pub id: u8,
     pub accounts: HashMap<u32, Account>,
-    pub global_blocked_points: u32,
+    pub global_blocked_points: Points,
+    history: OperationHistory,
+    /// Accounts whose earned points, status or ledger changed since the
+    /// last gossip round.
+    gossip_pending: HashSet<u32>,
+    /// With sharding, accounts are replicated to their other owner instead
+    /// of being gossiped and synced on every token pass.
+    ring: Option<Arc<HashRing>>,
+    replication_pending: HashSet<u32>,
+    expiration_days: Option<u32>,
+    tiers: TierPolicy,
+    promotions: Promotions,
+    catalog: Catalog,
+    /// Accounts must be opened with OPEN before earning points instead of
+    /// being opened by their first ADD.
+    explicit_account_opening: bool,
+    ledger_size: usize,
+    /// Tells apart the ledger entries this server makes in the same
+    /// millisecond.
+    ledger_seq: u64,
+    limiter: RateLimiter,
+    /// Accounts that broke an anomaly rule, kept for review.
+    flagged: BTreeMap<u32, BTreeSet<String>>,
+    /// Coffee makers allowed to connect, any if empty.
+    coffee_makers: Vec<AllowedCoffeeMaker>,
+    /// Set once the server started leaving the ring.
+    shutting_down: bool,
 }
 
 impl LocalServer {
     pub fn new() -> Result<LocalServer, String> {
+        Self::with_history(1, OperationHistory::disabled())
+    }
+
+    pub fn with_history(id: u8, history: OperationHistory) -> Result<LocalServer, String> {
         Ok(Self {
+            id,
             accounts: HashMap::new(),
-            global_blocked_points: 0,
+            global_blocked_points: Points::ZERO,
+            history,
+            gossip_pending: HashSet::new(),
+            ring: None,
+            replication_pending: HashSet::new(),
+            expiration_days: None,
+            tiers: TierPolicy::default(),
+            promotions: Promotions::default(),
+            catalog: Catalog::default(),
+            explicit_account_opening: false,
+            ledger_size: DEFAULT_LEDGER_SIZE,
+            ledger_seq: 0,
+            limiter: RateLimiter::default(),
+            flagged: BTreeMap::new(),
+            coffee_makers: vec![],
+            shutting_down: false,
         })
     }
+
+    pub fn with_config(
+        id: u8,
+        history: OperationHistory,
+        config: &ServerConfig,
+        ring: Option<Arc<HashRing>>,
+    ) -> Result<LocalServer, String> {
+        let mut server = Self::with_history(id, history)?;
+        server.ring = ring;
+        server.expiration_days = config.points_expiration_days;
+        server.tiers = config.tiers.clone();
+        server.promotions = config.promotions.clone();
+        server.catalog = config.catalog.clone();
+        server.explicit_account_opening = config.explicit_account_opening;
+        server.ledger_size = config.ledger_size();
+        server.limiter = RateLimiter::new(config.rate_limits.clone());
+        server.coffee_makers = config.coffee_makers.clone();
+        Ok(server)
+    }
+
+    fn is_sharded(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    fn mark_changed(&mut self, customer_id: u32) {
+        if self.is_sharded() {
+            self.replication_pending.insert(customer_id);
+        } else {
+            self.gossip_pending.insert(customer_id);
+        }
+    }
+
+    fn mark_redeemed(&mut self, customer_id: u32) {
+        if self.is_sharded() {
+            self.replication_pending.insert(customer_id);
+        }
+    }
+
+    fn get_or_create_account(&mut self, customer_id: u32) -> Result<&mut Account, String> {
+        match self.accounts.entry(customer_id) {
+            Entry::Occupied(o) => Ok(o.into_mut()),
+            Entry::Vacant(v) => Ok(v.insert(Account::new(customer_id)?)),
+        }
+    }
+
+    /// Transfers only credit existing accounts that are neither frozen nor
+    /// closed.
+    fn check_credit(&self, customer_id: u32) -> Result<(), ServerError> {
+        match self.accounts.get(&customer_id) {
+            Some(account) => account.check_redeemable(),
+            None => Err(ServerError::AccountNotFound(customer_id)),
+        }
+    }
+
+    /// Adds earned points to the account, opening it if needed and allowed.
+    /// Closed accounts cannot earn and purchases are rate limited. Coffee
+    /// orders of a catalog product earn the points of the catalog; purchases
+    /// are multiplied by the tier reached before the purchase and then by
+    /// the promotions that match the coffee order.
+    fn earn(
+        &mut self,
+        operation: &str,
+        customer_id: u32,
+        points: Points,
+        purchase: bool,
+        order: Option<&CoffeeOrder>,
+    ) -> Result<(), ServerError> {
+        let server_id = self.id;
+        let account = match self.accounts.entry(customer_id) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(_) if self.explicit_account_opening => {
+                error!(
+                    "Couldn't add {} to unopened account {}",
+                    points, customer_id
+                );
+                self.record(operation, customer_id, points.value() as i64, false);
+                return Err(ServerError::AccountNotFound(customer_id));
+            }
+            Entry::Vacant(v) => match Account::new(customer_id) {
+                Ok(account) => v.insert(account),
+                Err(err) => {
+                    error!("Error creating account with id {}: {}", customer_id, err);
+                    return Err(ServerError::AccountNotFound(customer_id));
+                }
+            },
+        };
+
+        let catalog = &self.catalog;
+        let tiers = &self.tiers;
+        let promotions = &self.promotions;
+        let mut applied = vec![];
+        let earned = account.check_open().and_then(|_| match order {
+            Some(order) => Ok(catalog.earned_points(order.product.as_deref(), points)?),
+            None => Ok(points),
+        });
+        let earned = earned.and_then(|earned| {
+            if !purchase {
+                return Ok(earned);
+            }
+            Ok(tiers.apply(account.lifetime_points()?, earned)?)
+        });
+        let earned = match order {
+            Some(order) => earned.and_then(|earned| {
+                let context = OrderContext {
+                    operation: PromotedOperation::Add,
+                    product: order.product.as_deref(),
+                    hour: current_hour(),
+                    order_number: account.orders + 1,
+                };
+                let (earned, names) = promotions.apply(&context, earned)?;
+                applied = names;
+                Ok(earned)
+            }),
+            None => earned,
+        };
+        let applied: Vec<String> = applied.into_iter().map(String::from).collect();
+        let limiter = &mut self.limiter;
+        let result = earned.and_then(|earned| {
+            if purchase {
+                limiter.check_earning(customer_id, earned, now_millis())?;
+            }
+            account.add_points(server_id, today(), earned)?;
+            Ok(earned)
+        });
+        match &result {
+            Ok(earned) => {
+                info!("Add {} to account {}", earned, customer_id);
+                let coffee_maker = order.and_then(|order| order.coffee_maker.clone());
+                self.log(
+                    customer_id,
+                    LedgerKind::Earned,
+                    earned.value() as i64,
+                    coffee_maker,
+                );
+                for name in applied {
+                    self.record(
+                        &format!("PROMO:{}", name),
+                        customer_id,
+                        earned.value() as i64,
+                        true,
+                    );
+                }
+            }
+            Err(e) => {
+                error!("Couldn't add {} to account {}: {}", points, customer_id, e);
+                self.flag(customer_id, e);
+            }
+        }
+        let recorded = result.as_ref().map(|p| p.value()).unwrap_or(points.value());
+        let coffee_maker = order.and_then(|order| order.coffee_maker.as_deref());
+        self.record_from(
+            operation,
+            customer_id,
+            recorded as i64,
+            result.is_ok(),
+            coffee_maker,
+        );
+        result.map(|_| ())
+    }
+
+    /// Adds an entry to the ledger of the account, which reaches the other
+    /// servers like earned points.
+    fn log(
+        &mut self,
+        customer_id: u32,
+        kind: LedgerKind,
+        points: i64,
+        coffee_maker: Option<String>,
+    ) {
+        let Some(account) = self.accounts.get_mut(&customer_id) else {
+            return;
+        };
+        self.ledger_seq += 1;
+        let entry = LedgerEntry {
+            timestamp: now_millis(),
+            server_id: self.id,
+            seq: self.ledger_seq,
+            kind,
+            points,
+            coffee_maker,
+        };
+        account.ledger.record(entry, self.ledger_size);
+        self.mark_changed(customer_id);
+    }
+
+    /// Keeps the account for review if the error is an anomaly rule.
+    fn flag(&mut self, customer_id: u32, error: &ServerError) {
+        let ServerError::RateLimited { rule, .. } = error else {
+            return;
+        };
+        warn!("Account {} flagged for review: {}", customer_id, rule);
+        self.flagged
+            .entry(customer_id)
+            .or_default()
+            .insert(rule.clone());
+        self.record(&format!("FLAG:{}", rule), customer_id, 0, true);
+    }
+
+    fn record(&mut self, operation: &str, customer_id: u32, points: i64, success: bool) {
+        self.record_from(operation, customer_id, points, success, None);
+    }
+
+    /// Records an operation sent by a coffee maker, tagged with its id.
+    fn record_from(
+        &mut self,
+        operation: &str,
+        customer_id: u32,
+        points: i64,
+        success: bool,
+        coffee_maker: Option<&str>,
+    ) {
+        let balance = self
+            .accounts
+            .get(&customer_id)
+            .and_then(|a| a.total_points().ok())
+            .map(|p| p.value());
+        self.history.record(
+            operation,
+            customer_id,
+            points,
+            success,
+            balance,
+            coffee_maker,
+        );
+    }
+
+    fn release_blocked_points(&mut self, points: Points) {
+        self.global_blocked_points = match self.global_blocked_points.checked_sub(points) {
+            Ok(left) => left,
+            Err(e) => {
+                error!("Global blocked points out of sync: {}", e);
+                Points::ZERO
+            }
+        };
+    }
 }
 
 impl Actor for LocalServer {
@@ -30,124 +328,352 @@ impl Actor for LocalServer {
 }
 
 impl Handler<AddPoints> for LocalServer {
-    type Result = Result<(), ()>;
+    type Result = Result<(), ServerError>;
 
+    /// Points of a purchase, multiplied by the tier of the customer.
     fn handle(&mut self, msg: AddPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let customer_id = msg.customer_id;
-        let points = msg.points;
+        self.earn("ADD", msg.customer_id, msg.points, true, msg.order.as_ref())
+    }
+}
 
-        let account;
-        match self.accounts.entry(customer_id) {
-            Entry::Occupied(o) => account = o.into_mut(),
-            Entry::Vacant(v) => {
-                let id_clone = customer_id;
-                match Account::new(id_clone) {
-                    Ok(new_account) => {
-                        info!("Add {} to account {}", points, customer_id);
-                        account = v.insert(new_account)
-                    }
-                    Err(err) => {
-                        println!("Error al crear la cuenta: {}", err);
-                        return Err(());
-                    }
-                }
-            }
-        };
+impl Handler<CreditPoints> for LocalServer {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: CreditPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        if let Err(e) = self.check_credit(msg.customer_id) {
+            error!(
+                "Couldn't credit {} to account {}: {}",
+                msg.points, msg.customer_id, e
+            );
+            self.record("CREDIT", msg.customer_id, msg.points.value() as i64, false);
+            return Err(e);
+        }
+        self.earn("CREDIT", msg.customer_id, msg.points, false, None)
+    }
+}
 
-        account.add_points(points);
-        Ok(())
+impl Handler<CheckCredit> for LocalServer {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: CheckCredit, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.check_credit(msg.customer_id)
     }
 }
 
 impl Handler<BlockPoints> for LocalServer {
-    type Result = Result<u32, ()>;
+    type Result = Result<Points, ServerError>;
 
+    /// Blocks the points of the order, priced from the catalog and with
+    /// promotions applied, and returns the points actually blocked, which
+    /// later settle the REQ.
     fn handle(&mut self, msg: BlockPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        if self.shutting_down {
+            warn!("Rejected REQ of account {}, shutting down", msg.customer_id);
+            return Err(ServerError::ShuttingDown);
+        }
         let customer_id = msg.customer_id;
-        let points = msg.points;
-        let mut result = Err(());
+        let requested = msg.points;
+        let catalog = &self.catalog;
+        let promotions = &self.promotions;
+        let mut applied = vec![];
 
-        match self.accounts.get_mut(&customer_id) {
+        let result = match self.accounts.get_mut(&customer_id) {
             Some(account) => {
-                account.register_added_points();
-                let block_result = account.block_points(points);
-
-                if block_result.is_ok() {
-                    info!("{} points blocked from account {}", points, customer_id);
-                    self.global_blocked_points += msg.points;
-                    result = Ok(msg.points);
-                } else {
-                    error!(
-                        "Couldn't block {} points from account {}",
-                        points, customer_id
+                if let Err(e) = account.register_adjustments() {
+                    error!("Couldn't register points of account {}: {}", customer_id, e);
+                }
+                let limited = self.limiter.check_redemption(customer_id, now_millis());
+                let points = limited.and_then(|_| match &msg.order {
+                    Some(order) => {
+                        let context = OrderContext {
+                            operation: PromotedOperation::Req,
+                            product: order.product.as_deref(),
+                            hour: current_hour(),
+                            order_number: account.orders + 1,
+                        };
+                        catalog
+                            .price_points(order.product.as_deref(), requested)
+                            .and_then(|price| {
+                                let (points, names) = promotions.apply(&context, price)?;
+                                applied = names;
+                                Ok(points)
+                            })
+                    }
+                    None => Ok(requested),
+                });
+                points
+                    .and_then(|points| {
+                        self.global_blocked_points
+                            .checked_add(points)
+                            .map_err(ServerError::from)
+                            .map(|global| (points, global))
+                    })
+                    .and_then(|(points, global)| {
+                        account.block_points(points)?;
+                        if msg.order.is_some() {
+                            account.orders += 1;
+                        }
+                        Ok((points, global))
+                    })
+            }
+            None => Err(ServerError::AccountNotFound(customer_id)),
+        };
+        let applied: Vec<String> = applied.into_iter().map(String::from).collect();
+
+        let result = match result {
+            Ok((points, global)) => {
+                info!("{} points blocked from account {}", points, customer_id);
+                self.global_blocked_points = global;
+                self.mark_redeemed(customer_id);
+                for name in applied {
+                    self.record(
+                        &format!("PROMO:{}", name),
+                        customer_id,
+                        points.value() as i64,
+                        true,
                     );
                 }
+                Ok(points)
             }
-            None => {
-                error!("The requested account does not exist");
+            Err(e) => {
+                error!(
+                    "Couldn't block {} points from account {}: {}",
+                    requested, customer_id, e
+                );
+                self.flag(customer_id, &e);
+                Err(e)
             }
-        }
+        };
+        let recorded = result.as_ref().unwrap_or(&requested).value();
+        let coffee_maker = msg.order.as_ref().and_then(|o| o.coffee_maker.as_deref());
+        self.record_from(
+            "REQ",
+            customer_id,
+            recorded as i64,
+            result.is_ok(),
+            coffee_maker,
+        );
         result
     }
 }
 
 impl Handler<SubtractPoints> for LocalServer {
-    type Result = Result<u32, ()>;
+    type Result = Result<Points, ServerError>;
 
     fn handle(&mut self, msg: SubtractPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let customer_id = msg.customer_id;
         let points = msg.points;
 
-        match self.accounts.get_mut(&customer_id) {
-            Some(account) => {
-                let substract_result = account.subtract_points(points);
-                if substract_result.is_ok() {
-                    info!("{} points consumed from account {}", points, customer_id);
-                    self.global_blocked_points -= msg.points;
-                    return Ok(self.global_blocked_points);
-                } else {
-                    error!(
-                        "Couldn't consume {} points from account {}",
-                        points, customer_id
-                    );
-                    return Err(());
-                }
+        let result = match self.accounts.get_mut(&customer_id) {
+            Some(account) => account.subtract_points(points),
+            None => Err(ServerError::AccountNotFound(customer_id)),
+        };
+
+        let result = match result {
+            Ok(_) => {
+                info!("{} points consumed from account {}", points, customer_id);
+                self.release_blocked_points(points);
+                self.mark_redeemed(customer_id);
+                self.log(
+                    customer_id,
+                    LedgerKind::Redeemed,
+                    points.value() as i64,
+                    msg.coffee_maker.clone(),
+                );
+                Ok(self.global_blocked_points)
             }
-            None => {
-                error!("Account {} does not exist", customer_id);
-                return Err(());
+            Err(e) => {
+                error!(
+                    "Couldn't consume {} points from account {}: {}",
+                    points, customer_id, e
+                );
+                Err(e)
             }
-        }
+        };
+        self.record_from(
+            "SUBS",
+            customer_id,
+            points.value() as i64,
+            result.is_ok(),
+            msg.coffee_maker.as_deref(),
+        );
+        result
     }
 }
 
 impl Handler<UnblockPoints> for LocalServer {
-    type Result = Result<u32, ()>;
+    type Result = Result<Points, ServerError>;
 
     fn handle(&mut self, msg: UnblockPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let customer_id = msg.customer_id;
         let points = msg.points;
 
-        match self.accounts.get_mut(&customer_id) {
-            Some(account) => {
-                let unblock_result = account.unblock_points(points);
-                if unblock_result.is_ok() {
-                    info!("{} points unblocked from account {}", points, customer_id);
-                    self.global_blocked_points -= msg.points;
-                    return Ok(self.global_blocked_points);
-                } else {
-                    error!(
-                        "Couldn't unblock {} points from account {}",
-                        points, customer_id
-                    );
-                    return Err(());
+        let result = match self.accounts.get_mut(&customer_id) {
+            Some(account) => account.unblock_points(points),
+            None => Err(ServerError::AccountNotFound(customer_id)),
+        };
+
+        let result = match result {
+            Ok(_) => {
+                info!("{} points unblocked from account {}", points, customer_id);
+                self.release_blocked_points(points);
+                self.log(
+                    customer_id,
+                    LedgerKind::Unblocked,
+                    points.value() as i64,
+                    msg.coffee_maker.clone(),
+                );
+                Ok(self.global_blocked_points)
+            }
+            Err(e) => {
+                error!(
+                    "Couldn't unblock {} points from account {}: {}",
+                    points, customer_id, e
+                );
+                Err(e)
+            }
+        };
+        self.record_from(
+            "UNBL",
+            customer_id,
+            points.value() as i64,
+            result.is_ok(),
+            msg.coffee_maker.as_deref(),
+        );
+        result
+    }
+}
+
+impl Handler<AdjustPoints> for LocalServer {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: AdjustPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let customer_id = msg.customer_id;
+        let adjustment = msg.points;
+
+        let result = match self.accounts.get_mut(&customer_id) {
+            Some(account) => account.adjust_points(self.id, today(), adjustment),
+            None => Err(ServerError::AccountNotFound(customer_id)),
+        };
+
+        match &result {
+            Ok(_) => {
+                info!("Account {} adjusted by {} points", customer_id, adjustment);
+                self.log(customer_id, LedgerKind::Adjusted, adjustment, None);
+            }
+            Err(e) => error!(
+                "Couldn't adjust account {} by {} points: {}",
+                customer_id, adjustment, e
+            ),
+        }
+        self.record("ADJ", customer_id, adjustment, result.is_ok());
+        result
+    }
+}
+
+impl Handler<ChangeStatus> for LocalServer {
+    type Result = Result<(), ServerError>;
+
+    /// Changes the status of an account, opening it if it does not exist
+    /// yet. The change reaches the other servers like earned points.
+    fn handle(&mut self, msg: ChangeStatus, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let customer_id = msg.customer_id;
+        let status = AccountStatus::new(msg.state, now_millis(), self.id);
+
+        let result = match self.accounts.entry(customer_id) {
+            Entry::Occupied(o) => o.into_mut().change_status(status),
+            Entry::Vacant(v) if msg.state == AccountState::Open => {
+                match Account::new(customer_id) {
+                    Ok(mut account) => {
+                        account.status = status;
+                        v.insert(account);
+                        Ok(())
+                    }
+                    Err(err) => {
+                        error!("Error creating account with id {}: {}", customer_id, err);
+                        Err(ServerError::AccountNotFound(customer_id))
+                    }
                 }
             }
-            None => {
-                error!("Account {} does not exist", customer_id);
-                return Err(());
+            Entry::Vacant(_) => Err(ServerError::AccountNotFound(customer_id)),
+        };
+
+        match &result {
+            Ok(_) => {
+                info!("Account {} status changed to {}", customer_id, status);
+                self.mark_changed(customer_id);
             }
+            Err(e) => error!(
+                "Couldn't {} account {}: {}",
+                msg.state.code(),
+                customer_id,
+                e
+            ),
+        }
+        self.record(msg.state.code(), customer_id, 0, result.is_ok());
+        result
+    }
+}
+
+impl Handler<GlobalBlockedPoints> for LocalServer {
+    type Result = MessageResult<GlobalBlockedPoints>;
+
+    fn handle(&mut self, _msg: GlobalBlockedPoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        MessageResult(self.global_blocked_points)
+    }
+}
+
+impl Handler<FlaggedAccounts> for LocalServer {
+    type Result = MessageResult<FlaggedAccounts>;
+
+    fn handle(&mut self, _msg: FlaggedAccounts, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        MessageResult(
+            self.flagged
+                .iter()
+                .flat_map(|(customer_id, rules)| {
+                    rules.iter().map(|rule| (*customer_id, rule.clone()))
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Handler<RegisterCoffeeMaker> for LocalServer {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: RegisterCoffeeMaker, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let result = match self.shutting_down {
+            true => Err(ServerError::ShuttingDown),
+            false => check_registration(&self.coffee_makers, msg.registration.as_ref()),
+        };
+        match (&msg.registration, &result) {
+            (Some(r), Ok(_)) => info!(
+                "Coffee maker {} registered from {} with firmware {}",
+                r.machine_id,
+                r.location.as_deref().unwrap_or("-"),
+                r.firmware.as_deref().unwrap_or("-")
+            ),
+            (None, Ok(_)) => info!("Unregistered coffee maker connected"),
+            (_, Err(e)) => warn!("Coffee maker rejected: {}", e),
         }
+        result
+    }
+}
+
+impl Handler<StopAccepting> for LocalServer {
+    type Result = ();
+
+    fn handle(&mut self, _msg: StopAccepting, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        info!("No longer accepting reservations nor coffee makers");
+        self.shutting_down = true;
+    }
+}
+
+impl Handler<GetCatalog> for LocalServer {
+    type Result = String;
+
+    fn handle(&mut self, _msg: GetCatalog, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.catalog.encode()
     }
 }
 
@@ -156,58 +682,230 @@ impl Handler<SyncAccount> for LocalServer {
 
     fn handle(&mut self, msg: SyncAccount, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let customer_id = msg.customer_id;
-        let points = msg.points;
+        let ledger_size = self.ledger_size;
+        let redeemed = msg.redeemed;
+        let expired = msg.expired;
+        let orders = msg.orders;
 
-        let account;
-        match self.accounts.entry(customer_id) {
-            Entry::Occupied(o) => account = o.into_mut(),
-            Entry::Vacant(v) => {
-                let id_clone = customer_id;
-                match Account::new(id_clone) {
-                    Ok(new_account) => account = v.insert(new_account),
-                    Err(err) => {
-                        error!("Error creating account with id {}: {}", id_clone, err);
-                        return "ERROR".to_string();
-                    }
-                }
+        let account = match self.get_or_create_account(customer_id) {
+            Ok(account) => account,
+            Err(err) => {
+                error!("Error creating account with id {}: {}", customer_id, err);
+                return "ERROR".to_string();
             }
         };
 
-        let _ = account.sync(points);
-        info!("Account {} synched {} points", customer_id, points);
+        account.sync(redeemed, expired, orders, &msg.earned, &msg.status);
+        account.ledger.merge(&msg.ledger, ledger_size);
+        info!(
+            "Account {} synched {} redeemed points, {} expired, earned {} and status {}",
+            customer_id,
+            redeemed,
+            expired,
+            msg.earned.encode(),
+            msg.status
+        );
+        self.record("SYNC", customer_id, redeemed.value() as i64, true);
         "OK".to_string()
     }
 }
 
+impl Handler<MergeEarned> for LocalServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MergeEarned, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let customer_id = msg.customer_id;
+        let ledger_size = self.ledger_size;
+
+        let changed = match self.get_or_create_account(customer_id) {
+            Ok(account) => {
+                let status_changed = account.merge_status(&msg.status);
+                let ledger_changed = account.ledger.merge(&msg.ledger, ledger_size);
+                account.merge_earned(&msg.earned) || status_changed || ledger_changed
+            }
+            Err(err) => {
+                error!("Error creating account with id {}: {}", customer_id, err);
+                return;
+            }
+        };
+
+        if changed {
+            info!(
+                "Account {} merged earned points {} and status {}",
+                customer_id,
+                msg.earned.encode(),
+                msg.status
+            );
+            self.gossip_pending.insert(customer_id);
+            let earned = msg
+                .earned
+                .value()
+                .and_then(|p| i64::try_from(p.value()).map_err(|_| PointsError::Overflow));
+            match earned {
+                Ok(earned) => self.record("GOSSIP", customer_id, earned, true),
+                Err(err) => error!(
+                    "Not recording GOSSIP of account {} in history: {}",
+                    customer_id, err
+                ),
+            }
+        }
+    }
+}
+
+impl Handler<PendingGossip> for LocalServer {
+    type Result = Vec<Account>;
+
+    fn handle(&mut self, _msg: PendingGossip, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let pending: Vec<u32> = self.gossip_pending.drain().collect();
+        pending
+            .into_iter()
+            .filter_map(|customer_id| self.accounts.get(&customer_id).cloned())
+            .collect()
+    }
+}
+
+impl Handler<GetLedger> for LocalServer {
+    type Result = Result<Vec<LedgerEntry>, ServerError>;
+
+    fn handle(&mut self, msg: GetLedger, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        match self.accounts.get(&msg.customer_id) {
+            Some(account) => Ok(account.ledger.last(msg.entries)),
+            None => Err(ServerError::AccountNotFound(msg.customer_id)),
+        }
+    }
+}
+
+impl Handler<PendingReplication> for LocalServer {
+    type Result = Vec<Account>;
+
+    fn handle(&mut self, _msg: PendingReplication, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let pending: Vec<u32> = self.replication_pending.drain().collect();
+        pending
+            .into_iter()
+            .filter_map(|customer_id| self.accounts.get(&customer_id).cloned())
+            .collect()
+    }
+}
+
 impl Handler<SyncNextServer> for LocalServer {
     type Result = Vec<Account>;
 
-    fn handle(&mut self, _msg: SyncNextServer, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: SyncNextServer, _ctx: &mut Self::Context) -> Self::Result {
         let mut accounts = vec![];
+        let mut adjusted = vec![];
         for (_, account) in self.accounts.iter_mut() {
-            account.register_added_points();
-            let mut account_dup =
-                Account::new(account.customer_id).expect("No se pudo crear el account");
-            account_dup.points = account.points;
-            accounts.push(account_dup);
+            let in_shards = msg
+                .shards
+                .as_ref()
+                .map(|shards| shards.contains(account.customer_id))
+                .unwrap_or(true);
+            if !in_shards {
+                if !account.points_to_remove.is_zero() {
+                    info!(
+                        "Keeping {} points to remove from account {} until its shard is held",
+                        account.points_to_remove, account.customer_id
+                    );
+                }
+                accounts.push(account.clone());
+                continue;
+            }
+            if !account.points_to_remove.is_zero() {
+                adjusted.push(account.customer_id);
+            }
+            if let Err(e) = account.register_adjustments() {
+                error!(
+                    "Couldn't register points of account {}: {}",
+                    account.customer_id, e
+                );
+            }
+            accounts.push(account.clone());
+        }
+        if self.is_sharded() {
+            adjusted
+                .into_iter()
+                .for_each(|customer_id| self.mark_redeemed(customer_id));
+            return vec![];
         }
         info!("Accounts State: {:?}", accounts);
         accounts
     }
 }
 
+impl Handler<ExpirePoints> for LocalServer {
+    type Result = MessageResult<ExpirePoints>;
+
+    /// Only the server allowed to redeem from an account expires its lots:
+    /// the caller holds the token or the shard, and with account sharding
+    /// the primary owner sweeps. Others learn the expired points by SYNC.
+    fn handle(&mut self, msg: ExpirePoints, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let cutoff = match self
+            .expiration_days
+            .and_then(|days| msg.today.checked_sub(days))
+        {
+            Some(cutoff) => cutoff,
+            None => return MessageResult(Points::ZERO),
+        };
+
+        let id = self.id;
+        let ring = self.ring.clone();
+        let mut expired = vec![];
+        for (customer_id, account) in self.accounts.iter_mut() {
+            let is_primary = ring
+                .as_ref()
+                .map(|ring| ring.owners(*customer_id).first() == Some(&id))
+                .unwrap_or(true);
+            let in_shards = msg
+                .shards
+                .as_ref()
+                .map(|shards| shards.contains(*customer_id))
+                .unwrap_or(true);
+            if !is_primary || !in_shards {
+                continue;
+            }
+            match account.expire_until(cutoff) {
+                Ok(points) if !points.is_zero() => expired.push((*customer_id, points)),
+                Ok(_) => {}
+                Err(e) => error!("Couldn't expire points of account {}: {}", customer_id, e),
+            }
+        }
+
+        let mut total = Points::ZERO;
+        for (customer_id, points) in expired {
+            self.mark_redeemed(customer_id);
+            self.log(
+                customer_id,
+                LedgerKind::Expired,
+                points.value() as i64,
+                None,
+            );
+            self.record("EXPIRE", customer_id, points.value() as i64, true);
+            total = total.checked_add(points).unwrap_or(total);
+        }
+        MessageResult(total)
+    }
+}
+
 #[cfg(test)]
 mod local_server_test {
     use actix::SyncArbiter;
 
     use super::*;
+    use crate::structs::catalog::Product;
+    use crate::structs::g_counter::GCounter;
+    use crate::structs::ledger::Ledger;
+    use crate::structs::promotions::Promotion;
+    use crate::structs::rate_limits::{RateLimits, POINTS_EARNED_PER_HOUR, REDEMPTIONS_PER_MINUTE};
+    use crate::structs::registration::Registration;
+    use crate::structs::shard_locks::{ShardFilter, ShardLocks};
+    use crate::structs::tiers::Tier;
 
     #[actix_rt::test]
     async fn test_add_points() {
         let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
         let msg = AddPoints {
             customer_id: 123,
-            points: 10,
+            points: Points::new(10),
+            order: None,
         };
 
         let result = server_addr.send(msg).await.unwrap();
@@ -221,12 +919,13 @@ mod local_server_test {
 
         let block_msg = BlockPoints {
             customer_id: 123,
-            points: 10,
+            points: Points::new(10),
+            order: None,
         };
 
         let result = server_addr.send(block_msg).await.unwrap();
 
-        assert_eq!(result, Err(()));
+        assert_eq!(result, Err(ServerError::AccountNotFound(123)));
     }
 
     #[actix_rt::test]
@@ -234,12 +933,13 @@ mod local_server_test {
         let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
         let sub_msg = SubtractPoints {
             customer_id: 123,
-            points: 10,
+            points: Points::new(10),
+            coffee_maker: None,
         };
 
         let result = server_addr.send(sub_msg).await.unwrap();
 
-        assert_eq!(result, Err(()));
+        assert_eq!(result, Err(ServerError::AccountNotFound(123)));
     }
 
     #[actix_rt::test]
@@ -247,12 +947,13 @@ mod local_server_test {
         let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
         let sub_msg = UnblockPoints {
             customer_id: 123,
-            points: 10,
+            points: Points::new(10),
+            coffee_maker: None,
         };
 
         let result = server_addr.send(sub_msg).await.unwrap();
 
-        assert_eq!(result, Err(()));
+        assert_eq!(result, Err(ServerError::AccountNotFound(123)));
     }
 
     #[actix_rt::test]
@@ -260,11 +961,959 @@ mod local_server_test {
         let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
         let sync_msg = SyncAccount {
             customer_id: 123,
-            points: 15,
+            redeemed: Points::new(15),
+            expired: Points::ZERO,
+            orders: 0,
+            earned: GCounter::new(),
+            status: AccountStatus::default(),
+            ledger: Ledger::new(),
         };
 
         let result = server_addr.send(sync_msg).await.unwrap();
 
         assert_eq!(result, "OK".to_string());
     }
+
+    #[actix_rt::test]
+    async fn test_block_points_beyond_u32_success() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let points = Points::new(u32::MAX as u64 + 5);
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points,
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let result = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points,
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result, Ok(points));
+    }
+
+    #[actix_rt::test]
+    async fn test_global_blocked_points_counts_every_reservation() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        for customer_id in [1, 2] {
+            let _ = server_addr
+                .send(AddPoints {
+                    customer_id,
+                    points: Points::new(10),
+                    order: None,
+                })
+                .await
+                .unwrap();
+            let _ = server_addr
+                .send(BlockPoints {
+                    customer_id,
+                    points: Points::new(4),
+                    order: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let blocked = server_addr.send(GlobalBlockedPoints {}).await.unwrap();
+
+        assert_eq!(blocked, Points::new(8));
+    }
+
+    #[actix_rt::test]
+    async fn test_adjust_points_nonexistent_account() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let adjust_msg = AdjustPoints {
+            customer_id: 123,
+            points: -10,
+        };
+
+        let result = server_addr.send(adjust_msg).await.unwrap();
+
+        assert_eq!(result, Err(ServerError::AccountNotFound(123)));
+    }
+
+    #[actix_rt::test]
+    async fn test_negative_adjustment_reduces_blockable_points() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(15),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let adjust_result = server_addr
+            .send(AdjustPoints {
+                customer_id: 123,
+                points: -10,
+            })
+            .await
+            .unwrap();
+        let block_result = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(adjust_result, Ok(()));
+        assert_eq!(
+            block_result,
+            Err(ServerError::InsufficientPoints {
+                customer_id: 123,
+                requested: Points::new(10),
+                available: Points::new(5),
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_operations_are_recorded_in_history() {
+        let path = std::env::temp_dir().join("local_server_history_test.log");
+        let _ = std::fs::remove_file(&path);
+        let file = path.to_str().unwrap().to_string();
+        let server_addr = SyncArbiter::start(1, move || {
+            LocalServer::with_history(
+                1,
+                OperationHistory::new("server-1".to_string(), &file).unwrap(),
+            )
+            .unwrap()
+        });
+
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 1,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let _ = server_addr
+            .send(BlockPoints {
+                customer_id: 2,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("server-1,ADD,1,10,OK,10"));
+        assert!(lines[1].ends_with("server-1,REQ,2,10,ERR,-"));
+    }
+
+    #[actix_rt::test]
+    async fn test_gossiped_points_can_be_blocked() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let mut earned = GCounter::new();
+        earned.increment(2, today(), Points::new(10)).unwrap();
+        server_addr
+            .send(MergeEarned {
+                customer_id: 123,
+                earned,
+                status: AccountStatus::default(),
+                ledger: Ledger::new(),
+            })
+            .await
+            .unwrap();
+
+        let result = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result, Ok(Points::new(10)));
+    }
+
+    #[actix_rt::test]
+    async fn test_gossip_too_large_for_history_is_not_recorded() {
+        let path = std::env::temp_dir().join("local_server_gossip_overflow_test.log");
+        let _ = std::fs::remove_file(&path);
+        let file = path.to_str().unwrap().to_string();
+        let server_addr = SyncArbiter::start(1, move || {
+            LocalServer::with_history(
+                1,
+                OperationHistory::new("server-1".to_string(), &file).unwrap(),
+            )
+            .unwrap()
+        });
+        let mut earned = GCounter::new();
+        earned
+            .increment(2, today(), Points::new(i64::MAX as u64 + 1))
+            .unwrap();
+        server_addr
+            .send(MergeEarned {
+                customer_id: 123,
+                earned,
+                status: AccountStatus::default(),
+                ledger: Ledger::new(),
+            })
+            .await
+            .unwrap();
+        let pending = server_addr.send(PendingGossip {}).await.unwrap();
+
+        assert_eq!(pending.len(), 1);
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(!contents.contains("GOSSIP"));
+    }
+
+    #[actix_rt::test]
+    async fn test_pending_gossip_is_sent_once() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let first = server_addr.send(PendingGossip {}).await.unwrap();
+        let second = server_addr.send(PendingGossip {}).await.unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].customer_id, 123);
+        assert_eq!(first[0].earned.encode(), format!("1@{}:10", today()));
+        assert!(second.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_sharded_server_replicates_changed_accounts_instead_of_syncing() {
+        let server_addr = SyncArbiter::start(1, || {
+            LocalServer::with_config(
+                1,
+                OperationHistory::disabled(),
+                &ServerConfig::default(),
+                Some(Arc::new(HashRing::new(1, 4))),
+            )
+            .unwrap()
+        });
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let synced = server_addr
+            .send(SyncNextServer { shards: None })
+            .await
+            .unwrap();
+        let replicated = server_addr.send(PendingReplication {}).await.unwrap();
+        let gossiped = server_addr.send(PendingGossip {}).await.unwrap();
+
+        assert!(synced.is_empty());
+        assert_eq!(replicated.len(), 1);
+        assert_eq!(replicated[0].total_points(), Ok(Points::new(10)));
+        assert!(gossiped.is_empty());
+    }
+
+    fn shard_filter(owned: &[u32]) -> ShardFilter {
+        let mut locks = ShardLocks::new(1, 2);
+        owned
+            .iter()
+            .for_each(|customer_id| locks.wait_for(*customer_id));
+        locks.update_table("").unwrap();
+        locks.filter()
+    }
+
+    fn sync_of(account: &Account) -> SyncAccount {
+        SyncAccount {
+            customer_id: account.customer_id,
+            redeemed: account.redeemed,
+            expired: account.expired,
+            orders: account.orders,
+            earned: account.earned.clone(),
+            status: account.status,
+            ledger: account.ledger.clone(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_adjustment_on_a_shard_held_elsewhere_survives_the_sync() {
+        let here = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let holder = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        for server in [&here, &holder] {
+            let _ = server
+                .send(AddPoints {
+                    customer_id: 123,
+                    points: Points::new(100),
+                    order: None,
+                })
+                .await
+                .unwrap();
+        }
+        let _ = here
+            .send(AdjustPoints {
+                customer_id: 123,
+                points: -30,
+            })
+            .await
+            .unwrap();
+        let _ = holder
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(20),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let _ = holder
+            .send(SubtractPoints {
+                customer_id: 123,
+                points: Points::new(20),
+                coffee_maker: None,
+            })
+            .await
+            .unwrap();
+
+        let kept = here
+            .send(SyncNextServer {
+                shards: Some(shard_filter(&[])),
+            })
+            .await
+            .unwrap();
+        let synced = holder
+            .send(SyncNextServer {
+                shards: Some(shard_filter(&[123])),
+            })
+            .await
+            .unwrap();
+        for account in synced.iter() {
+            let _ = here.send(sync_of(account)).await.unwrap();
+        }
+        let registered = here
+            .send(SyncNextServer {
+                shards: Some(shard_filter(&[123])),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(kept[0].points_to_remove, Points::new(30));
+        assert_eq!(kept[0].redeemed, Points::ZERO);
+        assert_eq!(registered[0].redeemed, Points::new(50));
+        assert_eq!(registered[0].total_points(), Ok(Points::new(50)));
+    }
+
+    fn expiring_server(days: u32) -> LocalServer {
+        let config = ServerConfig {
+            points_expiration_days: Some(days),
+            ..ServerConfig::default()
+        };
+        LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_lots_older_than_expiration_days_expire() {
+        let server_addr = SyncArbiter::start(1, || expiring_server(30));
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let too_soon = server_addr
+            .send(ExpirePoints {
+                today: today() + 29,
+                shards: None,
+            })
+            .await
+            .unwrap();
+        let expired = server_addr
+            .send(ExpirePoints {
+                today: today() + 30,
+                shards: None,
+            })
+            .await
+            .unwrap();
+        let synced = server_addr
+            .send(SyncNextServer { shards: None })
+            .await
+            .unwrap();
+
+        assert_eq!(too_soon, Points::ZERO);
+        assert_eq!(expired, Points::new(10));
+        assert_eq!(synced[0].expired, Points::new(10));
+        assert_eq!(synced[0].total_points(), Ok(Points::ZERO));
+    }
+
+    #[actix_rt::test]
+    async fn test_points_never_expire_without_policy() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let expired = server_addr
+            .send(ExpirePoints {
+                today: today() + 10000,
+                shards: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(expired, Points::ZERO);
+    }
+
+    #[actix_rt::test]
+    async fn test_purchases_are_multiplied_by_the_tier_and_credits_are_not() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                tiers: TierPolicy::new(vec![Tier {
+                    name: "gold".to_string(),
+                    min_lifetime_points: 10,
+                    multiplier_percent: 200,
+                }]),
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        for _ in 0..2 {
+            let _ = server_addr
+                .send(AddPoints {
+                    customer_id: 123,
+                    points: Points::new(10),
+                    order: None,
+                })
+                .await
+                .unwrap();
+        }
+        let _ = server_addr
+            .send(CreditPoints {
+                customer_id: 123,
+                points: Points::new(10),
+            })
+            .await
+            .unwrap();
+
+        let synced = server_addr
+            .send(SyncNextServer { shards: None })
+            .await
+            .unwrap();
+
+        assert_eq!(synced[0].total_points(), Ok(Points::new(40)));
+    }
+
+    #[actix_rt::test]
+    async fn test_every_nth_order_of_a_product_is_free() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                promotions: Promotions::new(vec![Promotion {
+                    name: "second_free".to_string(),
+                    operation: PromotedOperation::Req,
+                    hours: None,
+                    product: Some("espresso".to_string()),
+                    every_nth_order: Some(2),
+                    multiplier_percent: 0,
+                }]),
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let espresso = Some(CoffeeOrder {
+            product: Some("espresso".to_string()),
+            coffee_maker: None,
+        });
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(30),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        let mut blocked = vec![];
+        for _ in 0..3 {
+            blocked.push(
+                server_addr
+                    .send(BlockPoints {
+                        customer_id: 123,
+                        points: Points::new(10),
+                        order: espresso.clone(),
+                    })
+                    .await
+                    .unwrap(),
+            );
+        }
+        let result = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(1),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            blocked,
+            vec![Ok(Points::new(10)), Ok(Points::ZERO), Ok(Points::new(10))]
+        );
+        assert_eq!(result, Ok(Points::new(1)));
+        let synced = server_addr
+            .send(SyncNextServer { shards: None })
+            .await
+            .unwrap();
+        assert_eq!(synced[0].orders, 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_catalog_products_are_priced_by_the_server() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                catalog: Catalog::new(vec![Product {
+                    id: "latte".to_string(),
+                    price: 45,
+                    points: 15,
+                }]),
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let latte = Some(CoffeeOrder {
+            product: Some("latte".to_string()),
+            coffee_maker: None,
+        });
+        for _ in 0..3 {
+            let _ = server_addr
+                .send(AddPoints {
+                    customer_id: 123,
+                    points: Points::new(1),
+                    order: latte.clone(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let blocked = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(1),
+                order: latte,
+            })
+            .await
+            .unwrap();
+        let unknown = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(1),
+                order: Some(CoffeeOrder {
+                    product: Some("mocha".to_string()),
+                    coffee_maker: None,
+                }),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(blocked, Ok(Points::new(45)));
+        assert_eq!(
+            unknown,
+            Err(ServerError::UnknownProduct("mocha".to_string()))
+        );
+        assert_eq!(
+            server_addr.send(GetCatalog {}).await.unwrap(),
+            "latte:45:15"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_frozen_account_earns_but_cannot_redeem() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let add = AddPoints {
+            customer_id: 123,
+            points: Points::new(10),
+            order: None,
+        };
+        let _ = server_addr.send(add).await.unwrap();
+
+        let frozen = server_addr
+            .send(ChangeStatus {
+                customer_id: 123,
+                state: AccountState::Frozen,
+            })
+            .await
+            .unwrap();
+        let earned = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(5),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let blocked = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(frozen, Ok(()));
+        assert_eq!(earned, Ok(()));
+        assert_eq!(blocked, Err(ServerError::AccountFrozen(123)));
+    }
+
+    #[actix_rt::test]
+    async fn test_closed_account_is_gossiped_as_a_tombstone() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let _ = server_addr.send(PendingGossip {}).await.unwrap();
+
+        let closed = server_addr
+            .send(ChangeStatus {
+                customer_id: 123,
+                state: AccountState::Closed,
+            })
+            .await
+            .unwrap();
+        let earned = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(5),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let reopened = server_addr
+            .send(ChangeStatus {
+                customer_id: 123,
+                state: AccountState::Open,
+            })
+            .await
+            .unwrap();
+        let gossiped = server_addr.send(PendingGossip {}).await.unwrap();
+        let synced = server_addr
+            .send(SyncNextServer { shards: None })
+            .await
+            .unwrap();
+
+        assert_eq!(closed, Ok(()));
+        assert_eq!(earned, Err(ServerError::AccountClosed(123)));
+        assert_eq!(reopened, Err(ServerError::AccountClosed(123)));
+        assert_eq!(gossiped.len(), 1);
+        assert!(gossiped[0].is_closed());
+        assert_eq!(synced[0].total_points(), Ok(Points::ZERO));
+    }
+
+    #[actix_rt::test]
+    async fn test_explicit_opening_rejects_points_of_unopened_accounts() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                explicit_account_opening: true,
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let add = || AddPoints {
+            customer_id: 123,
+            points: Points::new(10),
+            order: None,
+        };
+
+        let unopened = server_addr.send(add()).await.unwrap();
+        let frozen = server_addr
+            .send(ChangeStatus {
+                customer_id: 123,
+                state: AccountState::Frozen,
+            })
+            .await
+            .unwrap();
+        let opened = server_addr
+            .send(ChangeStatus {
+                customer_id: 123,
+                state: AccountState::Open,
+            })
+            .await
+            .unwrap();
+        let earned = server_addr.send(add()).await.unwrap();
+
+        assert_eq!(unopened, Err(ServerError::AccountNotFound(123)));
+        assert_eq!(frozen, Err(ServerError::AccountNotFound(123)));
+        assert_eq!(opened, Ok(()));
+        assert_eq!(earned, Ok(()));
+    }
+
+    #[actix_rt::test]
+    async fn test_ledger_keeps_the_latest_movements_of_the_account() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                ledger_size: Some(3),
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(2, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let order = Some(CoffeeOrder {
+            product: None,
+            coffee_maker: Some("coffee-7".to_string()),
+        });
+        for _ in 0..2 {
+            let _ = server_addr
+                .send(AddPoints {
+                    customer_id: 123,
+                    points: Points::new(10),
+                    order: order.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        let _ = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(5),
+                order: order.clone(),
+            })
+            .await
+            .unwrap();
+        let _ = server_addr
+            .send(SubtractPoints {
+                customer_id: 123,
+                points: Points::new(5),
+                coffee_maker: Some("coffee-7".to_string()),
+            })
+            .await
+            .unwrap();
+        let _ = server_addr
+            .send(AdjustPoints {
+                customer_id: 123,
+                points: -3,
+            })
+            .await
+            .unwrap();
+
+        let ledger = server_addr
+            .send(GetLedger {
+                customer_id: 123,
+                entries: 10,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let missing = server_addr
+            .send(GetLedger {
+                customer_id: 7,
+                entries: 10,
+            })
+            .await
+            .unwrap();
+
+        let kinds: Vec<LedgerKind> = ledger.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LedgerKind::Adjusted,
+                LedgerKind::Redeemed,
+                LedgerKind::Earned
+            ]
+        );
+        assert_eq!(ledger[0].points, -3);
+        assert_eq!(ledger[0].coffee_maker, None);
+        assert_eq!(ledger[1].coffee_maker, Some("coffee-7".to_string()));
+        assert!(ledger.iter().all(|e| e.server_id == 2));
+        assert_eq!(missing, Err(ServerError::AccountNotFound(7)));
+    }
+
+    #[actix_rt::test]
+    async fn test_gossiped_ledger_entries_are_merged() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        let mut ledger = Ledger::new();
+        ledger.record(LedgerEntry::decode("5:2:1:EARN:10:coffee-3").unwrap(), 20);
+        let _ = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(1),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        server_addr
+            .send(MergeEarned {
+                customer_id: 123,
+                earned: GCounter::new(),
+                status: AccountStatus::default(),
+                ledger,
+            })
+            .await
+            .unwrap();
+        let entries = server_addr
+            .send(GetLedger {
+                customer_id: 123,
+                entries: 10,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].encode(), "5:2:1:EARN:10:coffee-3");
+    }
+
+    #[actix_rt::test]
+    async fn test_anomalies_are_rejected_and_flagged() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                rate_limits: RateLimits {
+                    max_redemptions_per_minute: Some(1),
+                    max_points_earned_per_hour: Some(50),
+                },
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let add = |points| AddPoints {
+            customer_id: 123,
+            points: Points::new(points),
+            order: None,
+        };
+        let block = || BlockPoints {
+            customer_id: 123,
+            points: Points::new(5),
+            order: None,
+        };
+
+        let first_add = server_addr.send(add(40)).await.unwrap();
+        let second_add = server_addr.send(add(20)).await.unwrap();
+        let credit = server_addr
+            .send(CreditPoints {
+                customer_id: 123,
+                points: Points::new(20),
+            })
+            .await
+            .unwrap();
+        let first_block = server_addr.send(block()).await.unwrap();
+        let second_block = server_addr.send(block()).await.unwrap();
+        let flagged = server_addr.send(FlaggedAccounts {}).await.unwrap();
+
+        assert_eq!(first_add, Ok(()));
+        assert_eq!(
+            second_add,
+            Err(ServerError::RateLimited {
+                customer_id: 123,
+                rule: POINTS_EARNED_PER_HOUR.to_string(),
+            })
+        );
+        assert_eq!(credit, Ok(()));
+        assert_eq!(first_block, Ok(Points::new(5)));
+        assert_eq!(second_block.unwrap_err().code(), "RATE_LIMITED");
+        assert_eq!(
+            flagged,
+            vec![
+                (123, POINTS_EARNED_PER_HOUR.to_string()),
+                (123, REDEMPTIONS_PER_MINUTE.to_string())
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_only_allowed_coffee_makers_register() {
+        let server_addr = SyncArbiter::start(1, || {
+            let config = ServerConfig {
+                coffee_makers: vec![AllowedCoffeeMaker {
+                    id: "coffee-1".to_string(),
+                    location: None,
+                    firmware: vec![],
+                }],
+                ..ServerConfig::default()
+            };
+            LocalServer::with_config(1, OperationHistory::disabled(), &config, None).unwrap()
+        });
+        let register = |machine_id: &str| RegisterCoffeeMaker {
+            registration: Some(Registration {
+                machine_id: machine_id.to_string(),
+                location: Some("palermo".to_string()),
+                firmware: Some("1.0.0".to_string()),
+            }),
+        };
+
+        let allowed = server_addr.send(register("coffee-1")).await.unwrap();
+        let unknown = server_addr.send(register("coffee-2")).await.unwrap();
+        let anonymous = server_addr
+            .send(RegisterCoffeeMaker { registration: None })
+            .await
+            .unwrap();
+
+        assert_eq!(allowed, Ok(()));
+        assert_eq!(
+            unknown,
+            Err(ServerError::Unregistered("coffee-2".to_string()))
+        );
+        assert_eq!(anonymous.unwrap_err().code(), "UNREGISTERED");
+    }
+
+    #[actix_rt::test]
+    async fn test_stopped_server_takes_no_reservations_nor_coffee_makers() {
+        let server_addr = SyncArbiter::start(1, || LocalServer::new().unwrap());
+        server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        server_addr.send(StopAccepting {}).await.unwrap();
+        let blocked = server_addr
+            .send(BlockPoints {
+                customer_id: 123,
+                points: Points::new(5),
+                order: None,
+            })
+            .await
+            .unwrap();
+        let registered = server_addr
+            .send(RegisterCoffeeMaker { registration: None })
+            .await
+            .unwrap();
+        let earned = server_addr
+            .send(AddPoints {
+                customer_id: 123,
+                points: Points::new(10),
+                order: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(blocked, Err(ServerError::ShuttingDown));
+        assert_eq!(registered.unwrap_err().code(), "SHUTTING_DOWN");
+        assert_eq!(earned, Ok(()));
+    }
 }
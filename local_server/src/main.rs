@@ -1,20 +1,43 @@
 use actix::{Addr, SyncArbiter};
+use local_server::structs::account::Account;
+use local_server::structs::audit_log::AuditLog;
+use local_server::structs::controller_auth::ControllerAuth;
+use local_server::structs::hash_ring::HashRing;
+use local_server::structs::history::OperationHistory;
+use local_server::structs::shard_locks::ConcurrencyMode;
+use local_server::structs::timing::Timing;
 use local_server::structs::token::Token;
-use local_server::utils::handlers_messages::handlers_messager::handle_coffe_connection;
+use local_server::utils::config::ServerConfig;
 use local_server::utils::handlers_messages::handlers_messager::handle_controller_connection;
+use local_server::utils::handlers_messages::handlers_messager::handle_replica_connection;
 use local_server::utils::handlers_messages::handlers_messager::handle_server_connection;
+use local_server::utils::handlers_messages::handlers_messager::{
+    accept_server_handshake, leave_ring,
+};
+use local_server::utils::handlers_messages::handlers_messager::{
+    coffee_maker_registration, handle_coffe_connection,
+};
+use local_server::utils::handlers_messages::handlers_messager::{gossip_message, sync_message};
+use local_server::utils::handlers_messages::handlers_messager::{
+    handle_transfer_connection, LocalParticipant,
+};
+use local_server::utils::protocol;
+use local_server::utils::signer::{self, Signer};
+use local_server::utils::tls::{self, Role, Stream, Tls};
 use log::{debug, error, info, warn};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::join;
 
 use std::sync::Arc;
 use std::time::Duration;
 
 use local_server::local_server::LocalServer;
-use local_server::structs::messages::SyncNextServer;
-use std::{env, thread};
+use local_server::structs::messages::{PendingGossip, PendingReplication, SyncNextServer};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
 use tokio::io::{self, split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::{Mutex, Notify};
 
@@ -24,23 +47,96 @@ async fn main() {
 
     let args: Vec<String> = env::args().collect();
     let id: u8 = args[1].parse::<u8>().expect("Could not parse number");
+    let config = match args.get(2) {
+        Some(path) => ServerConfig::from_file(path).expect("Could not read config file"),
+        None => ServerConfig::default(),
+    };
+    if let Some(settings) = &config.tls {
+        let context = Tls::from_settings(settings).expect("Could not load TLS certificates");
+        tls::install(context).expect("Could not install TLS");
+        info!(
+            "Accepting only peers with certificates of {}",
+            settings.ca_file
+        );
+    }
+
+    if let Some(key) = &config.cluster_key {
+        signer::install(Signer::new(key, id)).expect("Could not install cluster key");
+        info!("Signing messages to other servers");
+    }
+    let auth = Arc::new(ControllerAuth::new(config.operators.clone()));
+    let audit = match &config.audit_file {
+        Some(path) => AuditLog::new(path).expect("Could not open audit file"),
+        None => AuditLog::disabled(),
+    };
+    let audit = Arc::new(Mutex::new(audit));
 
     let listener = TcpListener::bind(format!("127.0.0.1:888{}", id))
         .await
         .expect("Failed to bind listener");
-    let server_actor_address = SyncArbiter::start(1, || LocalServer::new().unwrap());
+    let servers = config.servers();
+    let ring = config
+        .account_sharding
+        .map(|sharding| Arc::new(HashRing::new(servers, sharding.virtual_nodes)));
+    let history_file = config.history_file.clone();
+    let server_config = config.clone();
+    let server_ring = ring.clone();
+    let server_actor_address = SyncArbiter::start(1, move || {
+        let history = match &history_file {
+            Some(path) => OperationHistory::new(format!("server-{}", id), path)
+                .expect("Could not open history file"),
+            None => OperationHistory::disabled(),
+        };
+        LocalServer::with_config(id, history, &server_config, server_ring.clone()).unwrap()
+    });
 
-    let token: Arc<Mutex<Token>> = Arc::new(Mutex::new(Token::new()));
+    let token = match config.concurrency {
+        ConcurrencyMode::GlobalToken => Token::with_policy(config.token_hold),
+        ConcurrencyMode::ShardLocks { shards } => Token::with_shards(config.token_hold, id, shards),
+    };
+    let token: Arc<Mutex<Token>> = Arc::new(Mutex::new(token));
     let notify: Arc<Notify> = Arc::new(Notify::new());
     let coffee_makers = Arc::new(Mutex::new(0));
     let state: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
     let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel(1);
     let server_actor_copy_1 = server_actor_address.clone();
+    let fingerprint = config.rules_fingerprint();
+    let idle_timeout = config.coffee_maker_idle_timeout();
+    let timing = config.timing;
     let state_clone = state.clone();
+    let token_clone = token.clone();
     let rn = tokio::spawn(async move {
-        handle_right_neighbor(id, 3, rx, state_clone, server_actor_copy_1).await;
+        handle_right_neighbor(
+            id,
+            servers,
+            fingerprint,
+            rx,
+            state_clone,
+            token_clone,
+            server_actor_copy_1,
+            timing,
+        )
+        .await;
     });
 
+    let gossip_sender = tx.clone();
+    let gossip_state = state.clone();
+    let gossip_actor = server_actor_address.clone();
+    let gossip_interval = config.gossip_interval();
+    match ring.clone() {
+        Some(ring) => tokio::spawn(async move {
+            replicate_accounts(id, ring, gossip_interval, gossip_state, gossip_actor).await;
+        }),
+        None => tokio::spawn(async move {
+            gossip_earned_points(gossip_interval, gossip_sender, gossip_state, gossip_actor).await;
+        }),
+    };
+
+    let shutdown_token = token.clone();
+    let shutdown_coffee_makers = coffee_makers.clone();
+    let shutdown_actor = server_actor_address.clone();
+    let shutdown_sender = tx.clone();
+    let drain_timeout = config.shutdown_drain_timeout();
     let server = tokio::spawn(async move {
         info!("Waiting for coffee_makers!");
         loop {
@@ -53,9 +149,20 @@ async fn main() {
                     let coffee_makers_copy: Arc<Mutex<i32>> = coffee_makers.clone();
                     let sender: Sender<String> = tx.clone();
                     let state_clone = state.clone();
+                    let ring_copy = ring.clone();
+                    let auth_copy = auth.clone();
+                    let audit_copy = audit.clone();
                     tokio::spawn(async move {
+                        let (connection, role) = match tls::accept(tcp_connection).await {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                warn!("Rejected peer: {}", e);
+                                return;
+                            }
+                        };
                         handle_connection(
-                            tcp_connection,
+                            connection,
+                            role,
                             token_copy,
                             notify_copy,
                             coffee_makers_copy,
@@ -63,6 +170,13 @@ async fn main() {
                             sender,
                             state_clone,
                             id,
+                            servers,
+                            ring_copy,
+                            fingerprint,
+                            idle_timeout,
+                            timing,
+                            auth_copy,
+                            audit_copy,
                         )
                         .await;
                     });
@@ -75,27 +189,60 @@ async fn main() {
         }
     });
 
-    let _ = join!(rn, server);
+    shutdown_signal().await;
+    info!("Leaving the ring");
+    leave_ring(
+        id,
+        shutdown_token,
+        shutdown_coffee_makers,
+        shutdown_actor,
+        shutdown_sender,
+        drain_timeout,
+    )
+    .await;
+    if tokio::time::timeout(timing.token_timeout(), rn)
+        .await
+        .is_err()
+    {
+        warn!("LEAVE did not go around the ring, leaving anyway");
+    }
+    server.abort();
+}
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+        _ = terminate.recv() => info!("SIGTERM received"),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_right_neighbor(
     id: u8,
     mut servers: u8,
+    fingerprint: u64,
     mut rx: Receiver<String>,
     state: Arc<Mutex<bool>>,
+    token: Arc<Mutex<Token>>,
     server_actor_address: Addr<LocalServer>,
+    timing: Timing,
 ) {
     let mut last_message = String::new();
     let mut port_last_number = id;
     let mut last_timestamp: u128 = 0;
     let mut last_accounts_updated: u128 = 0;
     let mut election_sent = false;
+    let mut last_locks = String::new();
+    // Set once this server sent its own LEAVE.
+    let mut leaving = false;
     loop {
         let mut conn;
-        match connect_right_neigbor(id, servers, &mut port_last_number).await {
+        match connect_right_neigbor(id, servers, &mut port_last_number, &timing).await {
             Ok(connection) => conn = connection,
             Err(err) => {
-                if err == String::from("ONE_SERVER") {
+                if err == "ONE_SERVER" {
                     error!("Only one server left");
                     break;
                 }
@@ -105,14 +252,15 @@ async fn handle_right_neighbor(
             }
         }
 
-        conn.write_all(b"SH\n")
+        conn.write_all(format!("SH,{}\n", fingerprint).as_bytes())
             .await
             .expect("Falla la escritura tcp");
 
         if id == 1 && last_message.is_empty() {
             debug!("Sending token to next server");
             last_timestamp = get_timestime_now();
-            conn.write_all(format!("TOKEN,{},{}\n", servers, last_timestamp).as_bytes())
+            let token = token_message(servers, last_timestamp, &last_locks);
+            conn.write_all(signer::sign(&token).as_bytes())
                 .await
                 .expect("could not send token");
 
@@ -121,15 +269,17 @@ async fn handle_right_neighbor(
             let res = String::from_utf8_lossy(&buffer);
             debug!("1er BUFFER:{}", res);
         }
-        if last_message.starts_with("REC") {
+        // A LEAVE is not resent, the new neighbor would send it around the
+        // ring again.
+        if last_message.starts_with("REC") || last_message.starts_with("LEAVE") {
             last_message.clear();
         }
 
         if !last_message.is_empty() {
             if last_message.starts_with("TOKEN") || last_message.starts_with("SEND") {
-                last_message = format!("TOKEN,{},{}\n", servers, last_timestamp);
+                last_message = token_message(servers, last_timestamp, &last_locks);
             }
-            conn.write_all(last_message.as_bytes())
+            conn.write_all(signer::sign(&last_message).as_bytes())
                 .await
                 .expect("Could not send last message");
         }
@@ -156,7 +306,13 @@ async fn handle_right_neighbor(
                 "RECOVERY" => {
                     warn!("Recovery from sender");
 
-                    let id_recovery = parts[1].parse::<u8>().expect("Could not parse number");
+                    let id_recovery = match protocol::field::<u8>(&parts, 1, "server") {
+                        Ok(id_recovery) => id_recovery,
+                        Err(e) => {
+                            protocol::record("RING", &e);
+                            continue;
+                        }
+                    };
                     info!("recover port {}", id_recovery);
                     conn.shutdown().await.expect("shutdown fail");
                     debug!("SUMO SERVER");
@@ -171,7 +327,13 @@ async fn handle_right_neighbor(
                     break;
                 }
                 "RECONNECT" => {
-                    let id_recovery = parts[1].parse::<u8>().expect("Could not parse number");
+                    let id_recovery = match protocol::field::<u8>(&parts, 1, "server") {
+                        Ok(id_recovery) => id_recovery,
+                        Err(e) => {
+                            protocol::record("RING", &e);
+                            continue;
+                        }
+                    };
 
                     if id < servers {
                         port_last_number = id;
@@ -181,12 +343,12 @@ async fn handle_right_neighbor(
                     break;
                 }
                 "SEND" => {
-                    let response = format!("TOKEN,{},{}\n", servers, last_timestamp);
+                    let response = token_message(servers, last_timestamp, &last_locks);
                     last_message = response.clone();
                     match wait_ok(response, &mut conn, &mut disconnected, alive).await {
                         Ok(_) => info!("OK from next server"),
                         Err(_) => {
-                            if matches!(alive, true) {
+                            if alive {
                                 break;
                             }
                         }
@@ -195,18 +357,28 @@ async fn handle_right_neighbor(
                 "TOKEN" => {
                     last_accounts_updated = get_timestime_now();
                     election_sent = false;
-                    let s: u8 = parts[1].parse::<u8>().expect("Could not parse number");
-                    let timestamp = parts[2].parse::<u128>().expect("Could not parse number");
+                    let fields = protocol::field::<u8>(&parts, 1, "servers")
+                        .and_then(|s| Ok((s, protocol::field::<u128>(&parts, 2, "timestamp")?)));
+                    let (s, timestamp) = match fields {
+                        Ok(fields) => fields,
+                        Err(e) => {
+                            protocol::record("RING", &e);
+                            continue;
+                        }
+                    };
                     if last_timestamp < timestamp {
                         servers = s;
                         last_timestamp = timestamp;
                     }
-                    let response = format!("TOKEN,{},{}\n", servers, last_timestamp);
+                    if let Some(locks) = parts.get(3) {
+                        last_locks = locks.to_string();
+                    }
+                    let response = token_message(servers, last_timestamp, &last_locks);
                     last_message = response.clone();
                     match wait_ok(response, &mut conn, &mut disconnected, alive).await {
                         Ok(_) => info!("OK from next server"),
                         Err(_) => {
-                            if matches!(alive, true) {
+                            if alive {
                                 break;
                             }
                         }
@@ -214,7 +386,13 @@ async fn handle_right_neighbor(
                 }
                 "ELECTION" => {
                     debug!("Recibi un ELECTION, se lo mando a {}", port_last_number);
-                    let timestamp = parts[1].parse::<u128>().expect("Could not parse number");
+                    let timestamp = match protocol::field::<u128>(&parts, 1, "timestamp") {
+                        Ok(timestamp) => timestamp,
+                        Err(e) => {
+                            protocol::record("RING", &e);
+                            continue;
+                        }
+                    };
                     let mut response = message.clone();
                     if last_accounts_updated > timestamp && !election_sent {
                         debug!("Yo las tengo mas actualizadas");
@@ -223,34 +401,28 @@ async fn handle_right_neighbor(
                     } else if last_accounts_updated == timestamp && election_sent {
                         debug!("Es mi mensaje");
                         info!("Soy el nuevo portador del token");
-                        match server_actor_address.send(SyncNextServer {}).await {
+                        let shards = token.lock().await.locks_mut().map(|locks| locks.filter());
+                        match server_actor_address.send(SyncNextServer { shards }).await {
                             Ok(accounts) => {
                                 for account in accounts {
-                                    let message = format!(
-                                        "SYNC,{},{}\n",
-                                        account.customer_id, account.points
-                                    );
-                                    debug!(
-                                        "Sync {} to customer id {}",
-                                        account.customer_id, account.points
-                                    );
+                                    let message = sync_message(&account);
+                                    debug!("Sync customer id {}", account.customer_id);
                                     match wait_ok(message, &mut conn, &mut disconnected, alive)
                                         .await
                                     {
                                         Ok(_) => info!("OK from next server"),
                                         Err(_) => {
-                                            if matches!(alive, true) {
+                                            if alive {
                                                 break;
                                             }
                                         }
                                     }
-                                    thread::sleep(Duration::from_secs(1));
                                 }
                                 info!("Sync accounts to next neighbor finished");
                             }
                             Err(_) => error!("Fail trying to sync next server"),
                         }
-                        response = format!("TOKEN,{},{}\n", servers, last_timestamp);
+                        response = token_message(servers, last_timestamp, &last_locks);
                         election_sent = false;
                     } else if election_sent {
                         debug!("Me llego un election duplicado");
@@ -260,7 +432,39 @@ async fn handle_right_neighbor(
                     match wait_ok(response, &mut conn, &mut disconnected, alive).await {
                         Ok(_) => debug!("OK from next server"),
                         Err(_) => {
-                            if matches!(alive, true) {
+                            if alive {
+                                break;
+                            }
+                        }
+                    }
+                }
+                "LEAVE" => {
+                    let leaving_id = match protocol::field::<u8>(&parts, 1, "server") {
+                        Ok(leaving_id) => leaving_id,
+                        Err(e) => {
+                            protocol::record("RING", &e);
+                            continue;
+                        }
+                    };
+                    if leaving_id == id && leaving {
+                        info!("LEAVE went around the ring");
+                        return;
+                    }
+                    if leaving_id == port_last_number && leaving_id != id {
+                        // Sent back so the leaving server knows nothing else
+                        // comes from here, then the next server is joined.
+                        let _ = wait_ok(message, &mut conn, &mut disconnected, alive).await;
+                        info!("Right neighbor {} left the ring", leaving_id);
+                        let _ = conn.shutdown().await;
+                        last_message.clear();
+                        disconnected = true;
+                        break;
+                    }
+                    leaving |= leaving_id == id;
+                    match wait_ok(message, &mut conn, &mut disconnected, alive).await {
+                        Ok(_) => info!("OK from next server"),
+                        Err(_) => {
+                            if alive {
                                 break;
                             }
                         }
@@ -269,7 +473,7 @@ async fn handle_right_neighbor(
                 _ => match wait_ok(message, &mut conn, &mut disconnected, alive).await {
                     Ok(_) => info!("OK from next server"),
                     Err(_) => {
-                        if matches!(alive, true) {
+                        if alive {
                             break;
                         }
                     }
@@ -284,8 +488,109 @@ async fn handle_right_neighbor(
     }
 }
 
+/// Periodically sends the earned points that changed since the last round
+/// to the right neighbor, independently of where the token is.
+async fn gossip_earned_points(
+    interval: Duration,
+    sender: Sender<String>,
+    state: Arc<Mutex<bool>>,
+    server_actor_address: Addr<LocalServer>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if !*state.lock().await {
+            continue;
+        }
+        match server_actor_address.send(PendingGossip {}).await {
+            Ok(pending) => {
+                for account in pending {
+                    debug!(
+                        "Gossip earned points of customer id {}",
+                        account.customer_id
+                    );
+                    if sender.send(gossip_message(&account)).await.is_err() {
+                        error!("Could not send gossip message through channel");
+                        return;
+                    }
+                }
+            }
+            Err(_) => error!("Fail asking pending gossip to server actor"),
+        }
+    }
+}
+
+/// Periodically sends the accounts changed since the last round to their
+/// other owner when sharding is enabled.
+async fn replicate_accounts(
+    id: u8,
+    ring: Arc<HashRing>,
+    interval: Duration,
+    state: Arc<Mutex<bool>>,
+    server_actor_address: Addr<LocalServer>,
+) {
+    let mut replicas: HashMap<u8, BufReader<Stream>> = HashMap::new();
+    loop {
+        tokio::time::sleep(interval).await;
+        if !*state.lock().await {
+            continue;
+        }
+        match server_actor_address.send(PendingReplication {}).await {
+            Ok(accounts) => {
+                for account in accounts {
+                    for owner in ring.owners(account.customer_id) {
+                        if owner != id {
+                            replicate_account(&mut replicas, owner, &account).await;
+                        }
+                    }
+                }
+            }
+            Err(_) => error!("Fail asking pending replication to server actor"),
+        }
+    }
+}
+
+async fn replicate_account(
+    replicas: &mut HashMap<u8, BufReader<Stream>>,
+    owner: u8,
+    account: &Account,
+) {
+    let conn = match replicas.entry(owner) {
+        Entry::Occupied(o) => o.into_mut(),
+        Entry::Vacant(v) => match tls::connect(owner).await {
+            Ok(mut stream) => {
+                if stream.write_all(b"REPL\n").await.is_err() {
+                    warn!("Could not open replica connection with {}", owner);
+                    return;
+                }
+                v.insert(BufReader::new(stream))
+            }
+            Err(e) => {
+                warn!("Replica {} unreachable: {}", owner, e);
+                return;
+            }
+        },
+    };
+    let mut response = String::new();
+    let message = signer::sign(&sync_message(account));
+    let sent = match conn.write_all(message.as_bytes()).await {
+        Ok(_) => conn.read_line(&mut response).await.unwrap_or(0) > 0,
+        Err(_) => false,
+    };
+    if sent {
+        debug!("Account {} replicated to {}", account.customer_id, owner);
+    } else {
+        warn!(
+            "Could not replicate account {} to {}",
+            account.customer_id, owner
+        );
+        replicas.remove(&owner);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
-    tcp_connection: TcpStream,
+    connection: Stream,
+    role: Option<Role>,
     token_copy: Arc<Mutex<Token>>,
     notify_copy: Arc<Notify>,
     connections: Arc<Mutex<i32>>,
@@ -293,10 +598,17 @@ async fn handle_connection(
     sender: Sender<String>,
     state: Arc<Mutex<bool>>,
     id: u8,
+    servers: u8,
+    ring: Option<Arc<HashRing>>,
+    fingerprint: u64,
+    idle_timeout: Duration,
+    timing: Timing,
+    auth: Arc<ControllerAuth>,
+    audit: Arc<Mutex<AuditLog>>,
 ) {
-    let (r, w): (io::ReadHalf<TcpStream>, io::WriteHalf<TcpStream>) = split(tcp_connection);
+    let (r, mut w): (io::ReadHalf<Stream>, io::WriteHalf<Stream>) = split(connection);
 
-    let mut reader: BufReader<io::ReadHalf<TcpStream>> = BufReader::new(r);
+    let mut reader: BufReader<io::ReadHalf<Stream>> = BufReader::new(r);
 
     info!("Waiting for reading");
     let mut line = String::new();
@@ -304,6 +616,10 @@ async fn handle_connection(
         Ok(_u) => {
             info!("line {} ", line);
             let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+            if let Some(role) = role.filter(|role| !role.allows(parts[0])) {
+                error!("A {:?} cannot open a {} connection", role, parts[0]);
+                return;
+            }
             match parts[0] {
                 "CH" => {
                     info!("Coffee Connection");
@@ -315,11 +631,18 @@ async fn handle_connection(
                         connections,
                         server_actor_address,
                         sender,
+                        id,
+                        ring,
+                        coffee_maker_registration(&parts),
+                        idle_timeout,
                     )
                     .await;
                 }
                 "SH" => {
                     info!("Server Connection");
+                    if !accept_server_handshake(&parts, fingerprint, &mut w).await {
+                        return;
+                    }
                     handle_server_connection(
                         reader,
                         w,
@@ -329,20 +652,50 @@ async fn handle_connection(
                         server_actor_address,
                         sender,
                         state,
+                        timing,
                     )
                     .await;
                 }
                 "CTRL" => {
                     info!("Controller Connection");
 
-                    handle_controller_connection(reader, w, sender, state, id, 3).await;
+                    handle_controller_connection(
+                        reader,
+                        w,
+                        server_actor_address,
+                        sender,
+                        state,
+                        id,
+                        servers,
+                        auth,
+                        audit,
+                    )
+                    .await;
+                }
+                "TX" => {
+                    info!("Transfer Connection");
+                    let local = LocalParticipant::new(
+                        server_actor_address,
+                        token_copy,
+                        notify_copy,
+                        connections,
+                        sender,
+                    );
+                    handle_transfer_connection(reader, w, local).await;
+                }
+                "REPL" => {
+                    info!("Replica Connection");
+                    handle_replica_connection(reader, w, server_actor_address).await;
                 }
                 "RECOVERY" => {
                     info!("Recovery Connection");
-                    sender
-                        .send(line)
-                        .await
-                        .expect("fail sending recovery to sender");
+                    match signer::verify(&line) {
+                        Ok(line) => sender
+                            .send(line)
+                            .await
+                            .expect("fail sending recovery to sender"),
+                        Err(e) => error!("Rejected recovery message: {}", e),
+                    }
                 }
                 _ => {
                     error!("Unknown Connection type ");
@@ -359,7 +712,8 @@ async fn connect_right_neigbor(
     id: u8,
     servers: u8,
     port_last_number: &mut u8,
-) -> Result<TcpStream, String> {
+    timing: &Timing,
+) -> Result<Stream, String> {
     if servers == 1 {
         return Err(String::from("ONE_SERVER"));
     }
@@ -371,8 +725,8 @@ async fn connect_right_neigbor(
     let socket = format!("127.0.0.1:888{}", port_last_number);
     info!("Trying to connect {:?}", socket);
     let mut attemps = 0;
-    while attemps < 5 {
-        match TcpStream::connect(socket.clone()).await {
+    while attemps < timing.reconnect_attempts {
+        match tls::connect(*port_last_number).await {
             Ok(s) => {
                 info!("RIGHT NEIGHBOR - connected to {:?}", socket);
                 return Ok(s);
@@ -380,34 +734,50 @@ async fn connect_right_neigbor(
             Err(e) => {
                 error!("{}", e);
                 warn!("RIGHT NEIGHBOR - could not connect ");
+                let delay = timing.reconnect_delay(attemps);
                 attemps += 1;
-                thread::sleep(Duration::from_secs(1))
+                if attemps < timing.reconnect_attempts {
+                    debug!("RIGHT NEIGHBOR - retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
             }
         }
     }
-    warn!("RIGHT NEIGHBOR - could not connect in 5 attemps ");
-    Err(String::from(
-        "RIGHT NEIGHBOR - could not connect in 5 attemps",
+    warn!(
+        "RIGHT NEIGHBOR - could not connect in {} attemps ",
+        timing.reconnect_attempts
+    );
+    Err(format!(
+        "RIGHT NEIGHBOR - could not connect in {} attemps",
+        timing.reconnect_attempts
     ))
 }
 
+/// `TOKEN,<servers>,<timestamp>[,<shard table>]`, the table is only sent in
+/// shard lock mode.
+fn token_message(servers: u8, timestamp: u128, locks: &str) -> String {
+    if locks.is_empty() {
+        format!("TOKEN,{},{}\n", servers, timestamp)
+    } else {
+        format!("TOKEN,{},{},{}\n", servers, timestamp, locks)
+    }
+}
+
 fn get_timestime_now() -> u128 {
     let now = SystemTime::now();
     match now.duration_since(UNIX_EPOCH) {
-        Ok(duration) => {
-            return duration.as_millis();
-        }
+        Ok(duration) => duration.as_millis(),
         Err(_) => 0,
     }
 }
 
 async fn wait_ok(
     message: String,
-    conn: &mut TcpStream,
+    conn: &mut Stream,
     disconnected: &mut bool,
     alive: bool,
 ) -> Result<(), ()> {
-    match conn.write_all(message.as_bytes()).await {
+    match conn.write_all(signer::sign(&message).as_bytes()).await {
         Ok(_) => {
             debug!("Enviado. Esperando respuesta");
             let mut buffer = [0; 1024];
@@ -420,6 +790,13 @@ async fn wait_ok(
                         *disconnected = true;
                         Err(())
                     } else {
+                        if res.starts_with("ERR") || res.starts_with("NOT OK") {
+                            error!(
+                                "Right neighbor rejected {:?}: {}",
+                                message.trim(),
+                                res.trim_end_matches('\0').trim()
+                            );
+                        }
                         debug!("Mensaje enviado");
                         Ok(())
                     }
@@ -433,7 +810,7 @@ async fn wait_ok(
         }
         Err(_) => {
             debug!("Falla la escritura tcp");
-            if matches!(alive, true) {
+            if alive {
                 error!("Server disconnecteed");
                 *disconnected = true;
             }
@@ -1,161 +1,603 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use log::info;
 
+use super::account_status::{AccountState, AccountStatus};
+use super::g_counter::GCounter;
+use super::ledger::Ledger;
+use super::points::{Points, PointsError};
+use super::server_error::ServerError;
+use super::tiers::{Tier, TierPolicy};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days since the Unix epoch, used to date the lots of earned points.
+pub fn today() -> u32 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (seconds / SECONDS_PER_DAY) as u32
+}
+
+/// Balance of a customer. Earned points are a G-Counter dated by day so
+/// every server can apply ADDs without the token and replicas converge by
+/// gossip; redeemed and expired points only grow and are only changed by
+/// the token holder, so SYNC keeps the highest value seen. Both consume
+/// the lots of earned points oldest-first. The status is a last-writer-wins
+/// register changed by any server; a closed account has no balance.
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Account {
     pub customer_id: u32,
-    pub points: u32,
-    pub blocked_points: u32,
-    pub points_to_add: u32,
+    pub earned: GCounter,
+    pub redeemed: Points,
+    pub expired: Points,
+    /// Coffee orders paid with points, counted when their points are
+    /// blocked. Only grows and is changed by the token holder like
+    /// `redeemed`.
+    pub orders: u64,
+    pub blocked_points: Points,
+    pub points_to_remove: Points,
+    pub status: AccountStatus,
+    /// Latest movements, answered to `HIST` queries.
+    pub ledger: Ledger,
 }
 
 impl Account {
     pub fn new(customer_id: u32) -> Result<Account, String> {
         Ok(Self {
             customer_id,
-            points: 0,
-            blocked_points: 0,
-            points_to_add: 0,
+            earned: GCounter::new(),
+            redeemed: Points::ZERO,
+            expired: Points::ZERO,
+            orders: 0,
+            blocked_points: Points::ZERO,
+            points_to_remove: Points::ZERO,
+            status: AccountStatus::default(),
+            ledger: Ledger::new(),
         })
     }
 
-    pub fn add_points(&mut self, points: u32) {
-        self.points_to_add += points;
+    pub fn add_points(
+        &mut self,
+        server_id: u8,
+        day: u32,
+        points: Points,
+    ) -> Result<(), PointsError> {
+        self.earned.increment(server_id, day, points)
     }
 
-    pub fn register_added_points(&mut self) {
+    /// Applies an administrative adjustment. Positive adjustments are
+    /// earned points, negative ones are buffered until the token arrives and
+    /// can only take points that are not blocked by an ongoing redemption.
+    pub fn adjust_points(
+        &mut self,
+        server_id: u8,
+        day: u32,
+        adjustment: i64,
+    ) -> Result<(), ServerError> {
+        self.check_open()?;
+        let amount = Points::new(adjustment.unsigned_abs());
+        if adjustment >= 0 {
+            return Ok(self.add_points(server_id, day, amount)?);
+        }
+        let available = self.available_points()?;
+        if available < amount {
+            return Err(ServerError::InsufficientPoints {
+                customer_id: self.customer_id,
+                requested: amount,
+                available,
+            });
+        }
+        self.points_to_remove = self.points_to_remove.checked_add(amount)?;
+        Ok(())
+    }
+
+    pub fn register_adjustments(&mut self) -> Result<(), PointsError> {
+        if self.points_to_remove.is_zero() {
+            return Ok(());
+        }
         info!(
-            "Registering {} points to account id: {}",
-            self.points_to_add, self.customer_id
+            "Removing {} points from account id: {}",
+            self.points_to_remove, self.customer_id
         );
-        self.points += self.points_to_add;
-        self.points_to_add = 0;
+        self.redeemed = self.redeemed.checked_add(self.points_to_remove)?;
+        self.points_to_remove = Points::ZERO;
+        Ok(())
+    }
+
+    /// Consumes blocked points. Redeemed points take the oldest lots first,
+    /// so they are no longer candidates to expire.
+    pub fn subtract_points(&mut self, points: Points) -> Result<(), ServerError> {
+        if self.blocked_points < points {
+            return Err(self.not_blocked(points));
+        }
+        let blocked_points = self.blocked_points.checked_sub(points)?;
+        self.redeemed = self.redeemed.checked_add(points)?;
+        self.blocked_points = blocked_points;
+        Ok(())
+    }
+
+    pub fn block_points(&mut self, points: Points) -> Result<(), ServerError> {
+        self.check_redeemable()?;
+        let available = self.available_points()?;
+        if available < points {
+            return Err(ServerError::InsufficientPoints {
+                customer_id: self.customer_id,
+                requested: points,
+                available,
+            });
+        }
+        self.blocked_points = self.blocked_points.checked_add(points)?;
+        Ok(())
+    }
+
+    pub fn unblock_points(&mut self, points: Points) -> Result<(), ServerError> {
+        if self.blocked_points < points {
+            return Err(self.not_blocked(points));
+        }
+        self.blocked_points = self.blocked_points.checked_sub(points)?;
+        Ok(())
+    }
+
+    /// Every point ever earned, which decides the loyalty tier. It is
+    /// derived from the replicated earned counter, so the tier travels with
+    /// the account.
+    pub fn lifetime_points(&self) -> Result<Points, PointsError> {
+        self.earned.value()
+    }
+
+    pub fn tier<'a>(&self, tiers: &'a TierPolicy) -> Result<Option<&'a Tier>, PointsError> {
+        Ok(tiers.tier_for(self.lifetime_points()?))
+    }
+
+    pub fn available_points(&self) -> Result<Points, PointsError> {
+        if self.is_closed() {
+            return Ok(Points::ZERO);
+        }
+        self.total_points()?.checked_sub(self.blocked_points)
+    }
+
+    pub fn total_points(&self) -> Result<Points, PointsError> {
+        if self.is_closed() {
+            return Ok(Points::ZERO);
+        }
+        self.earned.value()?.checked_sub(self.consumed_points()?)
     }
 
-    pub fn subtract_points(&mut self, points: u32) -> Result<(), String> {
-        if self.blocked_points >= points {
-            self.blocked_points -= points;
-            if self.points_to_add > points {
-                self.points_to_add -= points;
-            } else {
-                self.points -= points;
+    /// Points left in every lot, oldest first, once redeemed, expired and
+    /// pending removed points are taken from the oldest lots.
+    pub fn lots(&self) -> Result<Vec<(u32, Points)>, PointsError> {
+        let mut consumed = self.consumed_points()?;
+        let mut lots = vec![];
+        for (day, points) in self.earned.lots()? {
+            let taken = points.min(consumed);
+            consumed = consumed.checked_sub(taken)?;
+            let left = points.checked_sub(taken)?;
+            if !left.is_zero() {
+                lots.push((day, left));
             }
-            Ok(())
-        } else {
-            Err("No se han bloqueado los puntos con anterioridad".to_string())
         }
+        Ok(lots)
     }
 
-    pub fn block_points(&mut self, points: u32) -> Result<(), String> {
-        if (self.points + self.points_to_add) - self.blocked_points >= points {
-            self.blocked_points += points;
-            Ok(())
-        } else {
-            Err("No hay suficientes puntos disponibles".to_string())
+    /// Expires what is left of the lots earned on `day` or before. Points
+    /// blocked by an ongoing redemption are kept, the next sweep expires
+    /// them if the redemption is cancelled. Returns the expired points.
+    pub fn expire_until(&mut self, day: u32) -> Result<Points, PointsError> {
+        let expirable = self
+            .earned
+            .value_until(day)?
+            .checked_sub(self.consumed_points()?)
+            .unwrap_or(Points::ZERO)
+            .min(self.available_points()?);
+        if !expirable.is_zero() {
+            info!(
+                "Expiring {} points of account id: {}",
+                expirable, self.customer_id
+            );
+            self.expired = self.expired.checked_add(expirable)?;
         }
+        Ok(expirable)
     }
 
-    pub fn unblock_points(&mut self, points: u32) -> Result<(), String> {
-        if self.blocked_points >= points {
-            self.blocked_points -= points;
-            Ok(())
-        } else {
-            Err("No hay suficientes puntos bloqueados".to_string())
+    /// Merges the earned points gossiped by another server. Returns true if
+    /// the balance changed.
+    pub fn merge_earned(&mut self, earned: &GCounter) -> bool {
+        self.earned.merge(earned)
+    }
+
+    pub fn sync(
+        &mut self,
+        redeemed: Points,
+        expired: Points,
+        orders: u64,
+        earned: &GCounter,
+        status: &AccountStatus,
+    ) {
+        self.redeemed = self.redeemed.max(redeemed);
+        self.expired = self.expired.max(expired);
+        self.orders = self.orders.max(orders);
+        self.earned.merge(earned);
+        self.status.merge(status);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.status.is_closed()
+    }
+
+    /// Fails if the account is closed, the only state that cannot earn.
+    pub fn check_open(&self) -> Result<(), ServerError> {
+        if self.is_closed() {
+            return Err(ServerError::AccountClosed(self.customer_id));
         }
+        Ok(())
     }
 
-    pub fn sync(&mut self, points: u32) -> Result<(), String> {
-        self.points = points;
+    /// Fails unless the account is open, frozen accounts cannot redeem.
+    pub fn check_redeemable(&self) -> Result<(), ServerError> {
+        match self.status.state {
+            AccountState::Open => Ok(()),
+            AccountState::Frozen => Err(ServerError::AccountFrozen(self.customer_id)),
+            AccountState::Closed => Err(ServerError::AccountClosed(self.customer_id)),
+        }
+    }
+
+    /// Applies a status change made on this server. Closed accounts cannot
+    /// be changed anymore.
+    pub fn change_status(&mut self, status: AccountStatus) -> Result<(), ServerError> {
+        self.check_open()?;
+        self.status.merge(&status);
         Ok(())
     }
+
+    /// Merges the status changed by another server. Returns true if it
+    /// changed.
+    pub fn merge_status(&mut self, status: &AccountStatus) -> bool {
+        self.status.merge(status)
+    }
+
+    fn consumed_points(&self) -> Result<Points, PointsError> {
+        self.redeemed
+            .checked_add(self.expired)?
+            .checked_add(self.points_to_remove)
+    }
+
+    fn not_blocked(&self, requested: Points) -> ServerError {
+        ServerError::NotBlocked {
+            customer_id: self.customer_id,
+            requested,
+            blocked: self.blocked_points,
+        }
+    }
 }
 
 #[cfg(test)]
 mod account_test {
     use super::*;
 
+    fn account_with_points(points: u64) -> Account {
+        let mut account = Account::new(123).unwrap();
+        account.add_points(1, 0, Points::new(points)).unwrap();
+        account
+    }
+
     #[test]
     fn test_subtract_points_with_enough_blocked_points_success() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 30;
-        account.blocked_points = 15;
-        let result = account.subtract_points(10);
-        assert_eq!(account.points, 20);
-        assert_eq!(account.blocked_points, 5);
+        let mut account = account_with_points(30);
+        account.blocked_points = Points::new(15);
+        let result = account.subtract_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(20)));
+        assert_eq!(account.redeemed, Points::new(10));
+        assert_eq!(account.blocked_points, Points::new(5));
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_subtract_points_with_not_enough_blocked_points_fails() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 30;
-        account.blocked_points = 5;
-        let result = account.subtract_points(10);
-        assert_eq!(account.points, 30);
-        assert_eq!(account.blocked_points, 5);
+        let mut account = account_with_points(30);
+        account.blocked_points = Points::new(5);
+        let result = account.subtract_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(30)));
+        assert_eq!(account.blocked_points, Points::new(5));
         assert!(result.is_err());
     }
 
     #[test]
     fn test_block_points_with_enough_points_success() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 15;
-        let result = account.block_points(10);
-        assert_eq!(account.points, 15);
-        assert_eq!(account.blocked_points, 10);
+        let mut account = account_with_points(15);
+        let result = account.block_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(15)));
+        assert_eq!(account.blocked_points, Points::new(10));
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_block_points_with_enough_left_points_fails() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 15;
-        let _ = account.block_points(10);
-        let result = account.block_points(10);
-        assert_eq!(account.points, 15);
-        assert_eq!(account.blocked_points, 10);
+        let mut account = account_with_points(15);
+        let _ = account.block_points(Points::new(10));
+        let result = account.block_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(15)));
+        assert_eq!(account.blocked_points, Points::new(10));
         assert!(result.is_err());
     }
 
     #[test]
     fn test_block_points_with_not_enough_points_fails() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 5;
-        let result = account.block_points(10);
-        assert_eq!(account.points, 5);
-        assert_eq!(account.blocked_points, 0);
+        let mut account = account_with_points(5);
+        let result = account.block_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(5)));
+        assert_eq!(account.blocked_points, Points::new(0));
         assert!(result.is_err());
     }
 
     #[test]
     fn test_unblock_points_with_enough_blocked_points_success() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 15;
-        account.blocked_points = 10;
-        let result = account.unblock_points(10);
-        assert_eq!(account.points, 15);
-        assert_eq!(account.blocked_points, 0);
+        let mut account = account_with_points(15);
+        account.blocked_points = Points::new(10);
+        let result = account.unblock_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(15)));
+        assert_eq!(account.blocked_points, Points::new(0));
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_unblock_points_with_not_enough_blocked_points_fails() {
-        let mut account = Account::new(123).unwrap();
-        account.points = 15;
-        account.blocked_points = 5;
-        let result = account.unblock_points(10);
-        assert_eq!(account.points, 15);
-        assert_eq!(account.blocked_points, 5);
+        let mut account = account_with_points(15);
+        account.blocked_points = Points::new(5);
+        let result = account.unblock_points(Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(15)));
+        assert_eq!(account.blocked_points, Points::new(5));
         assert!(result.is_err());
     }
 
     #[test]
     fn test_sync_account_success() {
         let mut account = Account::new(123).unwrap();
-        let points = 20;
-        let result = account.sync(points);
-        assert_eq!(account.points, 20);
-        assert_eq!(account.blocked_points, 0);
+        let mut earned = GCounter::new();
+        earned.increment(2, 0, Points::new(30)).unwrap();
+        account.sync(
+            Points::new(10),
+            Points::ZERO,
+            0,
+            &earned,
+            &AccountStatus::default(),
+        );
+        assert_eq!(account.total_points(), Ok(Points::new(20)));
+        assert_eq!(account.blocked_points, Points::new(0));
+    }
+
+    #[test]
+    fn test_sync_account_keeps_the_highest_redeemed_points() {
+        let mut account = account_with_points(30);
+        account.redeemed = Points::new(10);
+        account.sync(
+            Points::new(4),
+            Points::ZERO,
+            0,
+            &GCounter::new(),
+            &AccountStatus::default(),
+        );
+        assert_eq!(account.redeemed, Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(20)));
+    }
+
+    #[test]
+    fn test_merge_earned_adds_points_from_other_servers() {
+        let mut account = account_with_points(10);
+        let mut earned = GCounter::new();
+        earned.increment(2, 0, Points::new(5)).unwrap();
+        assert!(account.merge_earned(&earned));
+        assert!(!account.merge_earned(&earned));
+        assert_eq!(account.total_points(), Ok(Points::new(15)));
+    }
+
+    #[test]
+    fn test_add_points_beyond_u32_success() {
+        let mut account = account_with_points(u32::MAX as u64);
+        let result = account.add_points(1, 0, Points::new(10));
         assert!(result.is_ok());
+        assert_eq!(
+            account.total_points(),
+            Ok(Points::new(u32::MAX as u64 + 10))
+        );
+    }
+
+    #[test]
+    fn test_add_points_overflow_fails() {
+        let mut account = account_with_points(u64::MAX);
+        let result = account.add_points(1, 0, Points::new(1));
+        assert_eq!(result, Err(PointsError::Overflow));
+        assert_eq!(account.total_points(), Ok(Points::new(u64::MAX)));
+    }
+
+    #[test]
+    fn test_negative_adjustment_with_enough_points_success() {
+        let mut account = account_with_points(15);
+        let result = account.adjust_points(1, 0, -10);
+        assert!(result.is_ok());
+        assert_eq!(account.total_points(), Ok(Points::new(5)));
+        assert!(account.register_adjustments().is_ok());
+        assert_eq!(account.redeemed, Points::new(10));
+        assert_eq!(account.total_points(), Ok(Points::new(5)));
+    }
+
+    #[test]
+    fn test_negative_adjustment_over_blocked_points_fails() {
+        let mut account = account_with_points(15);
+        account.blocked_points = Points::new(10);
+        let result = account.adjust_points(1, 0, -10);
+        assert_eq!(
+            result,
+            Err(ServerError::InsufficientPoints {
+                customer_id: 123,
+                requested: Points::new(10),
+                available: Points::new(5),
+            })
+        );
+        assert_eq!(account.points_to_remove, Points::ZERO);
+    }
+
+    #[test]
+    fn test_positive_adjustment_is_earned() {
+        let mut account = Account::new(123).unwrap();
+        let result = account.adjust_points(2, 0, 7);
+        assert!(result.is_ok());
+        assert_eq!(account.earned.encode(), "2@0:7");
+    }
+
+    #[test]
+    fn test_subtract_points_underflow_fails_without_changes() {
+        let mut account = account_with_points(5);
+        account.blocked_points = Points::new(10);
+        account.redeemed = Points::new(u64::MAX);
+        let result = account.subtract_points(Points::new(10));
+        assert!(result.is_err());
+        assert_eq!(account.redeemed, Points::new(u64::MAX));
+        assert_eq!(account.blocked_points, Points::new(10));
+    }
+
+    #[test]
+    fn test_block_points_with_not_enough_points_returns_insufficient_points() {
+        let mut account = account_with_points(5);
+        let result = account.block_points(Points::new(10));
+        assert_eq!(
+            result,
+            Err(ServerError::InsufficientPoints {
+                customer_id: 123,
+                requested: Points::new(10),
+                available: Points::new(5),
+            })
+        );
+    }
+
+    #[test]
+    fn test_unblock_points_without_blocked_points_returns_not_blocked() {
+        let mut account = account_with_points(15);
+        let result = account.unblock_points(Points::new(10));
+        assert_eq!(
+            result,
+            Err(ServerError::NotBlocked {
+                customer_id: 123,
+                requested: Points::new(10),
+                blocked: Points::ZERO,
+            })
+        );
+    }
+
+    #[test]
+    fn test_redeemed_points_consume_oldest_lots_first() {
+        let mut account = Account::new(123).unwrap();
+        account.add_points(1, 10, Points::new(5)).unwrap();
+        account.add_points(2, 12, Points::new(8)).unwrap();
+        account.block_points(Points::new(7)).unwrap();
+        account.subtract_points(Points::new(7)).unwrap();
+        assert_eq!(account.lots(), Ok(vec![(12, Points::new(6))]));
+    }
+
+    #[test]
+    fn test_expire_only_what_is_left_of_old_lots() {
+        let mut account = Account::new(123).unwrap();
+        account.add_points(1, 10, Points::new(5)).unwrap();
+        account.add_points(1, 12, Points::new(8)).unwrap();
+        account.redeemed = Points::new(3);
+        assert_eq!(account.expire_until(11), Ok(Points::new(2)));
+        assert_eq!(account.expire_until(11), Ok(Points::ZERO));
+        assert_eq!(account.total_points(), Ok(Points::new(8)));
+        assert_eq!(account.lots(), Ok(vec![(12, Points::new(8))]));
+    }
+
+    #[test]
+    fn test_expire_keeps_blocked_points() {
+        let mut account = Account::new(123).unwrap();
+        account.add_points(1, 10, Points::new(5)).unwrap();
+        account.block_points(Points::new(4)).unwrap();
+        assert_eq!(account.expire_until(10), Ok(Points::new(1)));
+        account.unblock_points(Points::new(4)).unwrap();
+        assert_eq!(account.expire_until(10), Ok(Points::new(4)));
+        assert_eq!(account.total_points(), Ok(Points::ZERO));
+    }
+
+    #[test]
+    fn test_sync_keeps_the_highest_expired_points_and_orders() {
+        let mut account = account_with_points(30);
+        account.expired = Points::new(10);
+        account.sync(
+            Points::ZERO,
+            Points::new(4),
+            3,
+            &GCounter::new(),
+            &AccountStatus::default(),
+        );
+        assert_eq!(account.expired, Points::new(10));
+        assert_eq!(account.orders, 3);
+        account.sync(
+            Points::ZERO,
+            Points::new(12),
+            1,
+            &GCounter::new(),
+            &AccountStatus::default(),
+        );
+        assert_eq!(account.orders, 3);
+        assert_eq!(account.total_points(), Ok(Points::new(18)));
+    }
+
+    #[test]
+    fn test_tier_follows_lifetime_points() {
+        let tiers = TierPolicy::new(vec![Tier {
+            name: "silver".to_string(),
+            min_lifetime_points: 100,
+            multiplier_percent: 150,
+        }]);
+        let mut account = account_with_points(120);
+        assert_eq!(account.tier(&tiers).unwrap().unwrap().name, "silver");
+        account.redeemed = Points::new(100);
+        assert_eq!(account.lifetime_points(), Ok(Points::new(120)));
+        assert!(account.tier(&tiers).unwrap().is_some());
+        assert!(account_with_points(99).tier(&tiers).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_frozen_account_cannot_block_points() {
+        let mut account = account_with_points(30);
+        account
+            .change_status(AccountStatus::new(AccountState::Frozen, 1, 1))
+            .unwrap();
+        assert_eq!(
+            account.block_points(Points::new(10)),
+            Err(ServerError::AccountFrozen(123))
+        );
+        assert_eq!(account.total_points(), Ok(Points::new(30)));
+        assert!(account.adjust_points(1, 0, 5).is_ok());
+    }
+
+    #[test]
+    fn test_closed_account_has_no_balance_and_cannot_change() {
+        let mut account = account_with_points(30);
+        account.blocked_points = Points::new(10);
+        account
+            .change_status(AccountStatus::new(AccountState::Closed, 1, 1))
+            .unwrap();
+        assert_eq!(account.total_points(), Ok(Points::ZERO));
+        assert_eq!(account.available_points(), Ok(Points::ZERO));
+        assert_eq!(
+            account.change_status(AccountStatus::new(AccountState::Open, 2, 1)),
+            Err(ServerError::AccountClosed(123))
+        );
+        assert_eq!(
+            account.adjust_points(1, 0, 5),
+            Err(ServerError::AccountClosed(123))
+        );
+        assert!(account.subtract_points(Points::new(10)).is_ok());
+    }
+
+    #[test]
+    fn test_sync_merges_the_status() {
+        let mut account = account_with_points(30);
+        let closed = AccountStatus::new(AccountState::Closed, 5, 2);
+        account.sync(Points::ZERO, Points::ZERO, 0, &GCounter::new(), &closed);
+        assert!(account.is_closed());
+        assert!(!account.merge_status(&AccountStatus::new(AccountState::Open, 9, 1)));
     }
 }
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lifecycle of a customer account. Frozen accounts keep earning points but
+/// cannot redeem them; closed accounts are tombstones with no balance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountState {
+    Open,
+    Frozen,
+    Closed,
+}
+
+impl AccountState {
+    pub fn code(&self) -> &str {
+        match self {
+            AccountState::Open => "OPEN",
+            AccountState::Frozen => "FREEZE",
+            AccountState::Closed => "CLOSE",
+        }
+    }
+}
+
+impl FromStr for AccountState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "OPEN" => Ok(AccountState::Open),
+            "FREEZE" => Ok(AccountState::Frozen),
+            "CLOSE" => Ok(AccountState::Closed),
+            _ => Err(format!("Unknown account state {}", s)),
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, used to order status changes.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Last-writer-wins register with the state of an account. Any server can
+/// change it without the token; replicas keep the latest change, ties
+/// broken by server id, except that a close always wins so a closed account
+/// is never reopened by a concurrent change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountStatus {
+    pub state: AccountState,
+    pub timestamp: u64,
+    pub server_id: u8,
+}
+
+impl Default for AccountStatus {
+    /// Status of accounts opened implicitly, older than any explicit
+    /// change.
+    fn default() -> Self {
+        Self {
+            state: AccountState::Open,
+            timestamp: 0,
+            server_id: 0,
+        }
+    }
+}
+
+impl AccountStatus {
+    pub fn new(state: AccountState, timestamp: u64, server_id: u8) -> Self {
+        Self {
+            state,
+            timestamp,
+            server_id,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == AccountState::Closed
+    }
+
+    /// Keeps the winning status. Returns true if it changed.
+    pub fn merge(&mut self, other: &AccountStatus) -> bool {
+        if other.key() > self.key() {
+            *self = *other;
+            return true;
+        }
+        false
+    }
+
+    /// `<state>@<timestamp>@<server id>`
+    pub fn encode(&self) -> String {
+        format!(
+            "{}@{}@{}",
+            self.state.code(),
+            self.timestamp,
+            self.server_id
+        )
+    }
+
+    pub fn decode(encoded: &str) -> Result<AccountStatus, String> {
+        let fields: Vec<&str> = encoded.split('@').collect();
+        if fields.len() != 3 {
+            return Err(format!("Invalid account status {}", encoded));
+        }
+        Ok(Self {
+            state: fields[0].parse()?,
+            timestamp: fields[1].parse::<u64>().map_err(|e| e.to_string())?,
+            server_id: fields[2].parse::<u8>().map_err(|e| e.to_string())?,
+        })
+    }
+
+    fn key(&self) -> (bool, u64, u8) {
+        (self.is_closed(), self.timestamp, self.server_id)
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+#[cfg(test)]
+mod account_status_test {
+    use super::*;
+
+    #[test]
+    fn test_latest_change_wins() {
+        let mut status = AccountStatus::new(AccountState::Frozen, 10, 1);
+        assert!(status.merge(&AccountStatus::new(AccountState::Open, 20, 2)));
+        assert!(!status.merge(&AccountStatus::new(AccountState::Frozen, 15, 3)));
+        assert_eq!(status.state, AccountState::Open);
+    }
+
+    #[test]
+    fn test_concurrent_changes_are_broken_by_server_id() {
+        let first = AccountStatus::new(AccountState::Open, 10, 1);
+        let second = AccountStatus::new(AccountState::Frozen, 10, 2);
+        let mut a = first;
+        let mut b = second;
+        a.merge(&second);
+        b.merge(&first);
+        assert_eq!(a, b);
+        assert_eq!(a.state, AccountState::Frozen);
+    }
+
+    #[test]
+    fn test_close_wins_over_later_changes() {
+        let mut status = AccountStatus::new(AccountState::Closed, 10, 1);
+        assert!(!status.merge(&AccountStatus::new(AccountState::Open, 20, 2)));
+        assert!(status.is_closed());
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let status = AccountStatus::new(AccountState::Frozen, 1234, 3);
+        assert_eq!(status.encode(), "FREEZE@1234@3");
+        assert_eq!(AccountStatus::decode("FREEZE@1234@3"), Ok(status));
+        assert!(AccountStatus::decode("FROZEN@1@1").is_err());
+        assert!(AccountStatus::decode("OPEN@1").is_err());
+    }
+}
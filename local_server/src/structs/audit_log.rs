@@ -0,0 +1,39 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use log::{error, info};
+
+use super::history::timestamp_now;
+
+/// Append-only log of controller commands, one line per command:
+/// `<timestamp>,<operator>,<answer>,<command>`. Without file commands are
+/// only logged.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<File>,
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> Result<AuditLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        Ok(Self { file: Some(file) })
+    }
+
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, operator: &str, command: &str, answer: &str) {
+        info!("Operator {} ran {}: {}", operator, command, answer);
+        if let Some(file) = self.file.as_mut() {
+            let line = format!("{},{},{},{}\n", timestamp_now(), operator, answer, command);
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                error!("Could not write audit log: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+
+use serde_derive::Deserialize;
+
+use super::points::Points;
+use super::server_error::ServerError;
+
+/// Drink sold by the coffee makers. Buying it earns `points` and paying
+/// it with points costs `price` points.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Product {
+    pub id: String,
+    pub price: u64,
+    pub points: u64,
+}
+
+/// Products of the ring, loaded from the server config. Coffee makers get
+/// it with `CATALOG` when they connect, and servers price the orders that
+/// name a product with it, so a drink is worth the same on every machine.
+/// Without a catalog the points sent by the coffee maker are used.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Catalog {
+    products: Vec<Product>,
+}
+
+impl Catalog {
+    pub fn new(products: Vec<Product>) -> Self {
+        Self { products }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for product in self.products.iter() {
+            if product.id.is_empty() || product.id.contains([',', ':', ';']) {
+                return Err(format!("Invalid product id {:?}", product.id));
+            }
+            if !ids.insert(product.id.as_str()) {
+                return Err(format!("Product {} is listed twice", product.id));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn product(&self, id: &str) -> Option<&Product> {
+        self.products.iter().find(|product| product.id == id)
+    }
+
+    /// Points earned by an ADD of `product`.
+    pub fn earned_points(
+        &self,
+        product: Option<&str>,
+        requested: Points,
+    ) -> Result<Points, ServerError> {
+        self.lookup(product, requested, |p| p.points)
+    }
+
+    /// Points blocked by a REQ of `product`.
+    pub fn price_points(
+        &self,
+        product: Option<&str>,
+        requested: Points,
+    ) -> Result<Points, ServerError> {
+        self.lookup(product, requested, |p| p.price)
+    }
+
+    /// `<id>:<price>:<points>;...`, answered to `CATALOG`.
+    pub fn encode(&self) -> String {
+        self.products
+            .iter()
+            .map(|p| format!("{}:{}:{}", p.id, p.price, p.points))
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    /// FNV-1a of the encoded catalog, stable across processes and builds.
+    pub fn fingerprint(&self) -> u64 {
+        self.encode()
+            .bytes()
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    fn lookup(
+        &self,
+        product: Option<&str>,
+        requested: Points,
+        value: fn(&Product) -> u64,
+    ) -> Result<Points, ServerError> {
+        match product {
+            Some(id) if !self.products.is_empty() => self
+                .product(id)
+                .map(|product| Points::new(value(product)))
+                .ok_or(ServerError::UnknownProduct(id.to_string())),
+            _ => Ok(requested),
+        }
+    }
+}
+
+#[cfg(test)]
+mod catalog_test {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        Catalog::new(vec![
+            Product {
+                id: "espresso".to_string(),
+                price: 30,
+                points: 10,
+            },
+            Product {
+                id: "latte".to_string(),
+                price: 45,
+                points: 15,
+            },
+        ])
+    }
+
+    #[test]
+    fn test01_orders_are_priced_from_the_catalog() {
+        let catalog = catalog();
+
+        assert_eq!(
+            catalog.earned_points(Some("latte"), Points::new(1)),
+            Ok(Points::new(15))
+        );
+        assert_eq!(
+            catalog.price_points(Some("espresso"), Points::new(1)),
+            Ok(Points::new(30))
+        );
+        assert_eq!(
+            catalog.price_points(None, Points::new(7)),
+            Ok(Points::new(7))
+        );
+    }
+
+    #[test]
+    fn test02_unknown_product_is_rejected() {
+        assert_eq!(
+            catalog().earned_points(Some("mocha"), Points::new(1)),
+            Err(ServerError::UnknownProduct("mocha".to_string()))
+        );
+        assert_eq!(
+            Catalog::default().earned_points(Some("mocha"), Points::new(1)),
+            Ok(Points::new(1))
+        );
+    }
+
+    #[test]
+    fn test03_encode_lists_every_product() {
+        assert_eq!(catalog().encode(), "espresso:30:10;latte:45:15");
+        assert_eq!(Catalog::default().encode(), "");
+    }
+
+    #[test]
+    fn test04_invalid_catalog_is_rejected() {
+        let mut products = catalog().products;
+        products[1].id = "espresso".to_string();
+        assert!(Catalog::new(products).validate().is_err());
+
+        let mut products = catalog().products;
+        products[0].id = "flat:white".to_string();
+        assert!(Catalog::new(products).validate().is_err());
+        assert!(catalog().validate().is_ok());
+    }
+}
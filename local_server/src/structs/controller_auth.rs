@@ -0,0 +1,141 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_derive::Deserialize;
+
+use super::server_error::ServerError;
+
+const CHALLENGE_BYTES: usize = 16;
+
+/// Name used in the audit log when no operator is configured.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Person allowed to run controller commands, with the secret shared with
+/// the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Operator {
+    pub name: String,
+    pub secret: String,
+}
+
+impl Operator {
+    /// Answer to a challenge: HMAC-SHA256 of the challenge keyed with the
+    /// secret, in hex.
+    pub fn answer(&self, challenge: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_bytes());
+        to_hex(hmac::sign(&key, challenge.as_bytes()).as_ref())
+    }
+}
+
+/// Challenge-response check of controller sessions. Without operators the
+/// sessions are not authenticated.
+#[derive(Debug, Clone, Default)]
+pub struct ControllerAuth {
+    operators: Vec<Operator>,
+}
+
+impl ControllerAuth {
+    pub fn new(operators: Vec<Operator>) -> Self {
+        Self { operators }
+    }
+
+    pub fn is_required(&self) -> bool {
+        !self.operators.is_empty()
+    }
+
+    /// Random challenge, never reused.
+    pub fn challenge() -> Result<String, String> {
+        let mut bytes = [0; CHALLENGE_BYTES];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| "Could not generate challenge".to_string())?;
+        Ok(to_hex(&bytes))
+    }
+
+    /// Checks the answer of an operator in constant time.
+    pub fn verify(&self, name: &str, challenge: &str, answer: &str) -> Result<(), ServerError> {
+        let unauthorized = || ServerError::Unauthorized(name.to_string());
+        let operator = self
+            .operators
+            .iter()
+            .find(|operator| operator.name == name)
+            .ok_or_else(unauthorized)?;
+        let tag = from_hex(answer).ok_or_else(unauthorized)?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, operator.secret.as_bytes());
+        hmac::verify(&key, challenge.as_bytes(), &tag).map_err(|_| unauthorized())
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod controller_auth_test {
+    use super::*;
+
+    fn alice() -> Operator {
+        Operator {
+            name: "alice".to_string(),
+            secret: "s3cret".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_operator_with_the_secret_is_accepted() {
+        let auth = ControllerAuth::new(vec![alice()]);
+        let challenge = ControllerAuth::challenge().unwrap();
+
+        assert!(auth.is_required());
+        assert_eq!(
+            auth.verify("alice", &challenge, &alice().answer(&challenge)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_wrong_secret_or_operator_is_rejected() {
+        let auth = ControllerAuth::new(vec![alice()]);
+        let challenge = ControllerAuth::challenge().unwrap();
+        let mallory = Operator {
+            name: "alice".to_string(),
+            secret: "guess".to_string(),
+        };
+
+        assert_eq!(
+            auth.verify("alice", &challenge, &mallory.answer(&challenge)),
+            Err(ServerError::Unauthorized("alice".to_string()))
+        );
+        assert!(auth
+            .verify("bob", &challenge, &alice().answer(&challenge))
+            .is_err());
+        assert!(auth.verify("alice", &challenge, "not hex").is_err());
+    }
+
+    #[test]
+    fn test_answers_do_not_work_for_other_challenges() {
+        let auth = ControllerAuth::new(vec![alice()]);
+        let answer = alice().answer(&ControllerAuth::challenge().unwrap());
+
+        assert!(auth
+            .verify("alice", &ControllerAuth::challenge().unwrap(), &answer)
+            .is_err());
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0f0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
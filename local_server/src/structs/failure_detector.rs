@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde_derive::Deserialize;
+
+const DEFAULT_THRESHOLD: f64 = 8.0;
+const DEFAULT_WINDOW: usize = 100;
+const DEFAULT_MIN_STD_DEVIATION_MILLIS: u64 = 500;
+/// Intervals needed before the learned distribution is trusted.
+const MIN_SAMPLES: usize = 3;
+const MAX_SEARCH_STEPS: u32 = 64;
+
+/// Settings of the phi accrual failure detector, in the
+/// `timing.failure_detector` section of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FailureDetectorSettings {
+    /// Suspicion level at which the neighbor is considered dead: phi 8
+    /// means the silence had a 1e-8 chance given the learned intervals.
+    pub threshold: f64,
+    /// Latest intervals between arrivals that are learned.
+    pub window: usize,
+    /// Lower bound of the deviation, so a very regular ring does not make
+    /// a small delay look like a failure.
+    pub min_std_deviation_millis: u64,
+}
+
+impl Default for FailureDetectorSettings {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            window: DEFAULT_WINDOW,
+            min_std_deviation_millis: DEFAULT_MIN_STD_DEVIATION_MILLIS,
+        }
+    }
+}
+
+impl FailureDetectorSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold.is_nan() || self.threshold <= 0.0 || self.window < MIN_SAMPLES {
+            return Err(format!(
+                "The failure detector needs a positive threshold and a window of at least {}",
+                MIN_SAMPLES
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Phi accrual failure detector of the left neighbor. It learns the
+/// intervals between the arrivals of TOKEN and turns a
+/// silence into a suspicion level, so a slow ring waits longer and a fast
+/// one detects failures sooner. Until it learned enough intervals the
+/// neighbor is suspected after the fixed token timeout.
+#[derive(Debug, Clone)]
+pub struct FailureDetector {
+    settings: FailureDetectorSettings,
+    bootstrap: Duration,
+    intervals: VecDeque<f64>,
+    last_arrival: Instant,
+}
+
+impl FailureDetector {
+    pub fn new(settings: FailureDetectorSettings, bootstrap: Duration, now: Instant) -> Self {
+        Self {
+            settings,
+            bootstrap,
+            intervals: VecDeque::new(),
+            last_arrival: now,
+        }
+    }
+
+    /// Records an arrival and learns the interval since the previous one.
+    pub fn heartbeat(&mut self, now: Instant) {
+        let interval = now.saturating_duration_since(self.last_arrival);
+        self.intervals.push_back(interval.as_secs_f64() * 1000.0);
+        if self.intervals.len() > self.settings.window {
+            self.intervals.pop_front();
+        }
+        self.last_arrival = now;
+    }
+
+    /// Restarts the silence without learning from it, after an election or
+    /// while this server holds the token.
+    pub fn reset(&mut self, now: Instant) {
+        self.last_arrival = now;
+    }
+
+    /// Learns from a line of the left neighbor. Only TOKEN marks a lap, the
+    /// SYNC burst sent before it would teach intervals of almost zero, and
+    /// ELECTION shows the neighbor is alive without being a lap.
+    pub fn observe(&mut self, operation: &str, now: Instant) {
+        match operation {
+            "TOKEN" => self.heartbeat(now),
+            "ELECTION" => self.reset(now),
+            _ => {}
+        }
+    }
+
+    /// Suspicion level of the current silence.
+    pub fn phi(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_arrival);
+        match self.distribution() {
+            Some((mean, deviation)) => phi(elapsed.as_secs_f64() * 1000.0, mean, deviation),
+            None if elapsed >= self.bootstrap => f64::INFINITY,
+            None => 0.0,
+        }
+    }
+
+    pub fn is_suspected(&self, now: Instant) -> bool {
+        self.phi(now) >= self.settings.threshold
+    }
+
+    /// Time left until the silence reaches the threshold.
+    pub fn time_to_suspicion(&self, now: Instant) -> Duration {
+        self.suspicion_silence()
+            .saturating_sub(now.saturating_duration_since(self.last_arrival))
+    }
+
+    /// Silence after the last arrival at which phi reaches the threshold.
+    fn suspicion_silence(&self) -> Duration {
+        let (mean, deviation) = match self.distribution() {
+            Some(distribution) => distribution,
+            None => return self.bootstrap,
+        };
+        let threshold = self.settings.threshold;
+        let mut low = 0.0;
+        let mut high = mean.max(1.0);
+        let mut steps = 0;
+        while phi(high, mean, deviation) < threshold && steps < MAX_SEARCH_STEPS {
+            low = high;
+            high *= 2.0;
+            steps += 1;
+        }
+        for _ in 0..MAX_SEARCH_STEPS {
+            let middle = (low + high) / 2.0;
+            if phi(middle, mean, deviation) < threshold {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        // Rounded up, so the silence is suspected once it elapsed.
+        Duration::from_micros((high * 1000.0).ceil() as u64)
+    }
+
+    /// Mean and deviation of the learned intervals, in milliseconds.
+    fn distribution(&self) -> Option<(f64, f64)> {
+        if self.intervals.len() < MIN_SAMPLES {
+            return None;
+        }
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|interval| (interval - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let deviation = variance
+            .sqrt()
+            .max(self.settings.min_std_deviation_millis as f64);
+        Some((mean, deviation))
+    }
+}
+
+/// -log10 of the probability of a silence at least this long, with the
+/// logistic approximation of the normal distribution.
+fn phi(elapsed: f64, mean: f64, deviation: f64) -> f64 {
+    let y = (elapsed - mean) / deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod failure_detector_test {
+    use super::*;
+
+    fn settings() -> FailureDetectorSettings {
+        FailureDetectorSettings {
+            min_std_deviation_millis: 100,
+            ..FailureDetectorSettings::default()
+        }
+    }
+
+    /// Detector that saw arrivals every `interval` milliseconds.
+    fn detector_with_intervals(interval: u64, start: Instant) -> (FailureDetector, Instant) {
+        let mut detector = FailureDetector::new(settings(), Duration::from_secs(20), start);
+        let mut now = start;
+        for _ in 0..10 {
+            now += Duration::from_millis(interval);
+            detector.heartbeat(now);
+        }
+        (detector, now)
+    }
+
+    #[test]
+    fn test_silence_longer_than_usual_is_suspected() {
+        let (detector, last) = detector_with_intervals(1000, Instant::now());
+
+        assert!(detector.phi(last + Duration::from_millis(1000)) < 1.0);
+        assert!(!detector.is_suspected(last + Duration::from_millis(1200)));
+        assert!(detector.is_suspected(last + Duration::from_millis(5000)));
+    }
+
+    #[test]
+    fn test_slow_rings_wait_longer_and_fast_ones_detect_sooner() {
+        let start = Instant::now();
+        let (fast, fast_last) = detector_with_intervals(200, start);
+        let (slow, slow_last) = detector_with_intervals(5000, start);
+
+        let fast_suspicion = fast.time_to_suspicion(fast_last);
+        let slow_suspicion = slow.time_to_suspicion(slow_last);
+
+        assert!(fast_suspicion < Duration::from_secs(2));
+        assert!(slow_suspicion > Duration::from_secs(5));
+        assert!(slow_suspicion < Duration::from_secs(20));
+        assert!(fast.is_suspected(fast_last + fast_suspicion));
+        assert!(!slow.is_suspected(slow_last + Duration::from_millis(5500)));
+    }
+
+    #[test]
+    fn test_without_enough_intervals_the_bootstrap_timeout_is_used() {
+        let start = Instant::now();
+        let mut detector = FailureDetector::new(settings(), Duration::from_secs(20), start);
+        detector.heartbeat(start + Duration::from_millis(100));
+
+        let last = start + Duration::from_millis(100);
+        assert_eq!(detector.time_to_suspicion(last), Duration::from_secs(20));
+        assert!(!detector.is_suspected(last + Duration::from_secs(19)));
+        assert!(detector.is_suspected(last + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn test_reset_restarts_the_silence_without_learning_it() {
+        let (mut detector, last) = detector_with_intervals(1000, Instant::now());
+        let election = last + Duration::from_secs(60);
+
+        detector.reset(election);
+
+        assert!(!detector.is_suspected(election + Duration::from_millis(1000)));
+        assert!(detector.time_to_suspicion(election) < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_sync_burst_before_the_token_is_not_learned() {
+        let mut detector = FailureDetector::new(
+            FailureDetectorSettings::default(),
+            Duration::from_secs(20),
+            Instant::now(),
+        );
+        let mut lap = Instant::now();
+        for _ in 0..10 {
+            lap += Duration::from_millis(3000);
+            for account in 0..50 {
+                detector.observe("SYNC", lap + Duration::from_millis(account));
+            }
+            detector.observe("TOKEN", lap + Duration::from_millis(50));
+        }
+        let last = lap + Duration::from_millis(50);
+
+        assert!(!detector.is_suspected(last + Duration::from_millis(3000)));
+        assert!(!detector.is_suspected(last + Duration::from_millis(3500)));
+        assert!(detector.time_to_suspicion(last) > Duration::from_millis(3500));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        assert_eq!(FailureDetectorSettings::default().validate(), Ok(()));
+        assert!(FailureDetectorSettings {
+            threshold: 0.0,
+            ..settings()
+        }
+        .validate()
+        .is_err());
+        assert!(FailureDetectorSettings {
+            window: 1,
+            ..settings()
+        }
+        .validate()
+        .is_err());
+    }
+}
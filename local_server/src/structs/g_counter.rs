@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+
+use super::points::{Points, PointsError};
+
+/// Grow-only counter with one entry per server and day the points were
+/// earned. Each server only increments its own entries and replicas
+/// converge by keeping the maximum of every entry, so merges can arrive in
+/// any order and more than once. Entries are ordered by day, so the points
+/// of a day form a lot that can be consumed and expired oldest-first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCounter {
+    counts: BTreeMap<(u32, u8), Points>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(
+        &mut self,
+        server_id: u8,
+        day: u32,
+        points: Points,
+    ) -> Result<(), PointsError> {
+        let count = self.counts.entry((day, server_id)).or_default();
+        *count = count.checked_add(points)?;
+        Ok(())
+    }
+
+    pub fn value(&self) -> Result<Points, PointsError> {
+        self.counts
+            .values()
+            .try_fold(Points::ZERO, |total, count| total.checked_add(*count))
+    }
+
+    /// Points earned on `day` or before.
+    pub fn value_until(&self, day: u32) -> Result<Points, PointsError> {
+        self.counts
+            .range(..=(day, u8::MAX))
+            .try_fold(Points::ZERO, |total, (_, count)| total.checked_add(*count))
+    }
+
+    /// Points earned per day, oldest first.
+    pub fn lots(&self) -> Result<BTreeMap<u32, Points>, PointsError> {
+        let mut lots: BTreeMap<u32, Points> = BTreeMap::new();
+        for ((day, _), points) in self.counts.iter() {
+            let lot = lots.entry(*day).or_default();
+            *lot = lot.checked_add(*points)?;
+        }
+        Ok(lots)
+    }
+
+    /// Merges another replica into this one. Returns true if any entry
+    /// changed.
+    pub fn merge(&mut self, other: &GCounter) -> bool {
+        let mut changed = false;
+        for (key, points) in other.counts.iter() {
+            let count = self.counts.entry(*key).or_default();
+            if *points > *count {
+                *count = *points;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Encodes the counter as `<server>@<day>:<points>;...` so it fits in a
+    /// single protocol field.
+    pub fn encode(&self) -> String {
+        self.counts
+            .iter()
+            .map(|((day, server_id), points)| format!("{}@{}:{}", server_id, day, points))
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    pub fn decode(encoded: &str) -> Result<GCounter, String> {
+        let mut counter = GCounter::new();
+        for entry in encoded.split(';').filter(|e| !e.trim().is_empty()) {
+            let (key, points) = entry
+                .split_once(':')
+                .ok_or(format!("Invalid counter entry {}", entry))?;
+            let (server_id, day) = key
+                .split_once('@')
+                .ok_or(format!("Invalid counter entry {}", entry))?;
+            let server_id = server_id.trim().parse::<u8>().map_err(|e| e.to_string())?;
+            let day = day.parse::<u32>().map_err(|e| e.to_string())?;
+            let points = points.parse::<Points>().map_err(|e| e.to_string())?;
+            counter.counts.insert((day, server_id), points);
+        }
+        Ok(counter)
+    }
+}
+
+#[cfg(test)]
+mod g_counter_test {
+    use super::*;
+
+    #[test]
+    fn test01_value_adds_every_server() {
+        let mut counter = GCounter::new();
+        counter.increment(1, 0, Points::new(10)).unwrap();
+        counter.increment(2, 0, Points::new(5)).unwrap();
+        counter.increment(1, 0, Points::new(1)).unwrap();
+
+        assert_eq!(counter.value(), Ok(Points::new(16)));
+    }
+
+    #[test]
+    fn test02_merge_keeps_the_maximum_of_each_server() {
+        let mut a = GCounter::new();
+        a.increment(1, 0, Points::new(10)).unwrap();
+        let mut b = GCounter::new();
+        b.increment(1, 0, Points::new(4)).unwrap();
+        b.increment(2, 0, Points::new(7)).unwrap();
+
+        assert!(a.merge(&b));
+        assert_eq!(a.value(), Ok(Points::new(17)));
+    }
+
+    #[test]
+    fn test03_merge_is_idempotent_and_commutative() {
+        let mut a = GCounter::new();
+        a.increment(1, 0, Points::new(3)).unwrap();
+        let mut b = GCounter::new();
+        b.increment(2, 0, Points::new(8)).unwrap();
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+
+        assert_eq!(ab, ba);
+        assert!(!ab.merge(&b));
+    }
+
+    #[test]
+    fn test04_encode_and_decode() {
+        let mut counter = GCounter::new();
+        counter.increment(1, 20000, Points::new(10)).unwrap();
+        counter.increment(3, 19999, Points::new(2)).unwrap();
+
+        let encoded = counter.encode();
+
+        assert_eq!(encoded, "3@19999:2;1@20000:10");
+        assert_eq!(GCounter::decode(&encoded), Ok(counter));
+        assert_eq!(GCounter::decode(""), Ok(GCounter::new()));
+    }
+
+    #[test]
+    fn test05_decode_malformed_counter_fails() {
+        assert!(GCounter::decode("1@0:10;3").is_err());
+        assert!(GCounter::decode("a@0:10").is_err());
+        assert!(GCounter::decode("1:10").is_err());
+    }
+
+    #[test]
+    fn test06_lots_group_servers_by_day() {
+        let mut counter = GCounter::new();
+        counter.increment(2, 11, Points::new(4)).unwrap();
+        counter.increment(1, 10, Points::new(3)).unwrap();
+        counter.increment(1, 11, Points::new(1)).unwrap();
+
+        let lots: Vec<(u32, Points)> = counter.lots().unwrap().into_iter().collect();
+
+        assert_eq!(lots, vec![(10, Points::new(3)), (11, Points::new(5))]);
+        assert_eq!(counter.value_until(10), Ok(Points::new(3)));
+        assert_eq!(counter.value_until(11), Ok(Points::new(8)));
+    }
+}
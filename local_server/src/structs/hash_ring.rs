@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use serde_derive::Deserialize;
+
+const DEFAULT_VIRTUAL_NODES: u32 = 16;
+
+/// Enables account sharding: each account lives on a primary server plus
+/// a replica chosen by consistent hashing instead of on every server.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ShardingConfig {
+    pub virtual_nodes: u32,
+}
+
+impl Default for ShardingConfig {
+    fn default() -> Self {
+        Self {
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+        }
+    }
+}
+
+/// Consistent hash ring of the servers `1..=servers`. Every server is
+/// placed `virtual_nodes` times so accounts spread evenly and only the
+/// accounts of a server move when the ring changes.
+#[derive(Debug)]
+pub struct HashRing {
+    nodes: BTreeMap<u64, u8>,
+}
+
+impl HashRing {
+    pub fn new(servers: u8, virtual_nodes: u32) -> Self {
+        let mut nodes = BTreeMap::new();
+        for server_id in 1..=servers {
+            for node in 0..virtual_nodes.max(1) {
+                nodes.insert(
+                    hash(format!("{}-{}", server_id, node).as_bytes()),
+                    server_id,
+                );
+            }
+        }
+        Self { nodes }
+    }
+
+    /// Primary followed by the replica of the account. The replica is the
+    /// next distinct server clockwise, absent with a single server.
+    pub fn owners(&self, customer_id: u32) -> Vec<u8> {
+        let start = hash(&customer_id.to_be_bytes());
+        let mut owners = Vec::with_capacity(2);
+        for server_id in self
+            .nodes
+            .range(start..)
+            .chain(self.nodes.range(..start))
+            .map(|(_, server_id)| *server_id)
+        {
+            if !owners.contains(&server_id) {
+                owners.push(server_id);
+                if owners.len() == 2 {
+                    break;
+                }
+            }
+        }
+        owners
+    }
+
+    pub fn is_owner(&self, server_id: u8, customer_id: u32) -> bool {
+        self.owners(customer_id).contains(&server_id)
+    }
+}
+
+/// FNV-1a followed by the murmur3 finalizer to spread close keys, stable
+/// across processes and builds so every server places the accounts in the
+/// same way.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod hash_ring_test {
+    use super::*;
+
+    #[test]
+    fn test01_every_account_has_a_primary_and_a_distinct_replica() {
+        let ring = HashRing::new(3, 16);
+
+        for customer_id in 0..100 {
+            let owners = ring.owners(customer_id);
+            assert_eq!(owners.len(), 2);
+            assert_ne!(owners[0], owners[1]);
+        }
+    }
+
+    #[test]
+    fn test02_owners_are_deterministic() {
+        let first = HashRing::new(3, 16);
+        let second = HashRing::new(3, 16);
+
+        for customer_id in 0..100 {
+            assert_eq!(first.owners(customer_id), second.owners(customer_id));
+        }
+    }
+
+    #[test]
+    fn test03_accounts_are_spread_across_servers() {
+        let ring = HashRing::new(3, 16);
+        let mut primaries = [0; 3];
+
+        for customer_id in 0..300 {
+            primaries[ring.owners(customer_id)[0] as usize - 1] += 1;
+        }
+
+        assert!(primaries.iter().all(|count| *count > 30));
+    }
+
+    #[test]
+    fn test04_single_server_owns_everything_without_replica() {
+        let ring = HashRing::new(1, 16);
+
+        assert_eq!(ring.owners(7), vec![1]);
+        assert!(ring.is_owner(1, 7));
+    }
+}
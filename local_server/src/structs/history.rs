@@ -0,0 +1,214 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+
+pub const SUCCESS: &str = "OK";
+pub const FAILURE: &str = "ERR";
+
+/// One recorded operation. Serialized as a single line:
+/// `<timestamp>,<origin>,<operation>,<customer_id>,<points>,<outcome>,<balance>[,<coffee maker>]`
+/// where `balance` is `-` when the origin does not know the account state
+/// and the coffee maker, if any, is the machine that sent the operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u128,
+    pub origin: String,
+    pub operation: String,
+    pub customer_id: u32,
+    pub points: i64,
+    pub outcome: String,
+    pub balance: Option<u64>,
+    pub coffee_maker: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn parse(line: &str) -> Result<HistoryEntry, String> {
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() != 7 && parts.len() != 8 {
+            return Err(format!("Invalid history line: {}", line));
+        }
+        let balance = match parts[6] {
+            "-" => None,
+            b => Some(b.parse::<u64>().map_err(|e| e.to_string())?),
+        };
+        Ok(Self {
+            timestamp: parts[0].parse::<u128>().map_err(|e| e.to_string())?,
+            origin: parts[1].to_string(),
+            operation: parts[2].to_string(),
+            customer_id: parts[3].parse::<u32>().map_err(|e| e.to_string())?,
+            points: parts[4].parse::<i64>().map_err(|e| e.to_string())?,
+            outcome: parts[5].to_string(),
+            balance,
+            coffee_maker: parts.get(7).map(|id| id.to_string()),
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.outcome == SUCCESS
+    }
+
+    pub fn is_from_server(&self) -> bool {
+        self.origin.starts_with("server")
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let balance = match self.balance {
+            Some(b) => b.to_string(),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{},{},{},{},{},{},{}",
+            self.timestamp,
+            self.origin,
+            self.operation,
+            self.customer_id,
+            self.points,
+            self.outcome,
+            balance
+        )?;
+        match &self.coffee_maker {
+            Some(id) => write!(f, ",{}", id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Append-only operation log. A history without file is disabled and
+/// ignores every record.
+#[derive(Debug)]
+pub struct OperationHistory {
+    origin: String,
+    file: Option<File>,
+}
+
+impl OperationHistory {
+    pub fn new(origin: String, path: &str) -> Result<OperationHistory, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            origin,
+            file: Some(file),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            origin: String::new(),
+            file: None,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        operation: &str,
+        customer_id: u32,
+        points: i64,
+        success: bool,
+        balance: Option<u64>,
+        coffee_maker: Option<&str>,
+    ) {
+        if let Some(file) = self.file.as_mut() {
+            let entry = HistoryEntry {
+                timestamp: timestamp_now(),
+                origin: self.origin.clone(),
+                operation: operation.to_string(),
+                customer_id,
+                points,
+                outcome: if success { SUCCESS } else { FAILURE }.to_string(),
+                balance,
+                coffee_maker: coffee_maker.map(String::from),
+            };
+            if writeln!(file, "{}", entry).is_err() {
+                error!("Could not write history entry {}", entry);
+            }
+        }
+    }
+}
+
+pub(crate) fn timestamp_now() -> u128 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+    use super::*;
+
+    #[test]
+    fn test01_parse_server_entry() {
+        let entry = HistoryEntry::parse("10,server-1,ADD,5,20,OK,20").unwrap();
+
+        assert_eq!(entry.timestamp, 10);
+        assert_eq!(entry.origin, "server-1");
+        assert_eq!(entry.operation, "ADD");
+        assert_eq!(entry.customer_id, 5);
+        assert_eq!(entry.points, 20);
+        assert!(entry.is_success());
+        assert!(entry.is_from_server());
+        assert_eq!(entry.balance, Some(20));
+    }
+
+    #[test]
+    fn test02_parse_entry_without_balance() {
+        let entry = HistoryEntry::parse("10,coffee-7,REQ,5,20,ERR,-").unwrap();
+
+        assert!(!entry.is_success());
+        assert!(!entry.is_from_server());
+        assert_eq!(entry.balance, None);
+    }
+
+    #[test]
+    fn test03_parse_negative_adjustment() {
+        let entry = HistoryEntry::parse("10,server-1,ADJ,5,-20,OK,4294967300").unwrap();
+
+        assert_eq!(entry.points, -20);
+        assert_eq!(entry.balance, Some(4294967300));
+    }
+
+    #[test]
+    fn test04_parse_malformed_entry_fails() {
+        assert!(HistoryEntry::parse("10,server-1,ADD").is_err());
+        assert!(HistoryEntry::parse("10,server-1,ADD,abc,20,OK,-").is_err());
+    }
+
+    #[test]
+    fn test05_display_is_parseable() {
+        let entry = HistoryEntry {
+            timestamp: 3,
+            origin: "server-2".to_string(),
+            operation: "SUBS".to_string(),
+            customer_id: 1,
+            points: 4,
+            outcome: SUCCESS.to_string(),
+            balance: None,
+            coffee_maker: None,
+        };
+
+        assert_eq!(HistoryEntry::parse(&entry.to_string()).unwrap(), entry);
+    }
+
+    #[test]
+    fn test06_coffee_maker_is_an_optional_last_field() {
+        let entry = HistoryEntry::parse("10,server-1,REQ,5,20,OK,0,coffee-7").unwrap();
+
+        assert_eq!(entry.coffee_maker, Some("coffee-7".to_string()));
+        assert_eq!(entry.to_string(), "10,server-1,REQ,5,20,OK,0,coffee-7");
+        assert_eq!(
+            HistoryEntry::parse("10,server-1,REQ,5,20,OK,0")
+                .unwrap()
+                .coffee_maker,
+            None
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of entries kept per account when the configuration says nothing.
+pub const DEFAULT_LEDGER_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LedgerKind {
+    Earned,
+    Redeemed,
+    Unblocked,
+    Adjusted,
+    Expired,
+}
+
+impl LedgerKind {
+    pub fn code(&self) -> &str {
+        match self {
+            LedgerKind::Earned => "EARN",
+            LedgerKind::Redeemed => "REDEEM",
+            LedgerKind::Unblocked => "UNBLOCK",
+            LedgerKind::Adjusted => "ADJUST",
+            LedgerKind::Expired => "EXPIRE",
+        }
+    }
+}
+
+impl FromStr for LedgerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "EARN" => Ok(LedgerKind::Earned),
+            "REDEEM" => Ok(LedgerKind::Redeemed),
+            "UNBLOCK" => Ok(LedgerKind::Unblocked),
+            "ADJUST" => Ok(LedgerKind::Adjusted),
+            "EXPIRE" => Ok(LedgerKind::Expired),
+            _ => Err(format!("Unknown ledger entry kind {}", s)),
+        }
+    }
+}
+
+/// Movement of points of an account. Entries are identified and ordered by
+/// when and where they were made: `seq` tells apart the entries of a server
+/// within the same millisecond.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LedgerEntry {
+    pub timestamp: u64,
+    pub server_id: u8,
+    pub seq: u64,
+    pub kind: LedgerKind,
+    pub points: i64,
+    pub coffee_maker: Option<String>,
+}
+
+impl LedgerEntry {
+    /// Coffee maker ids travel inside a protocol field, so only ids made of
+    /// letters, digits, `-`, `_` and `.` are accepted.
+    pub fn is_valid_coffee_maker(id: &str) -> bool {
+        !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }
+
+    /// `<timestamp>:<server>:<seq>:<kind>:<points>:<coffee maker or ->`
+    pub fn encode(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.timestamp,
+            self.server_id,
+            self.seq,
+            self.kind.code(),
+            self.points,
+            self.coffee_maker.as_deref().unwrap_or("-")
+        )
+    }
+
+    pub fn decode(encoded: &str) -> Result<LedgerEntry, String> {
+        let fields: Vec<&str> = encoded.trim().split(':').collect();
+        if fields.len() != 6 {
+            return Err(format!("Invalid ledger entry {}", encoded));
+        }
+        Ok(Self {
+            timestamp: fields[0].parse::<u64>().map_err(|e| e.to_string())?,
+            server_id: fields[1].parse::<u8>().map_err(|e| e.to_string())?,
+            seq: fields[2].parse::<u64>().map_err(|e| e.to_string())?,
+            kind: fields[3].parse()?,
+            points: fields[4].parse::<i64>().map_err(|e| e.to_string())?,
+            coffee_maker: match fields[5] {
+                "-" => None,
+                id => Some(id.to_string()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+/// Latest movements of an account, bounded to a number of entries. Every
+/// server adds the entries of the operations it serves and replicas merge
+/// by keeping the newest entries of the union, so they converge whatever
+/// the order merges arrive in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ledger {
+    entries: BTreeSet<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, entry: LedgerEntry, capacity: usize) {
+        self.entries.insert(entry);
+        self.truncate(capacity);
+    }
+
+    /// Merges the ledger of another replica. Returns true if an entry was
+    /// added.
+    pub fn merge(&mut self, other: &Ledger, capacity: usize) -> bool {
+        let before = self.entries.clone();
+        self.entries.extend(other.entries.iter().cloned());
+        self.truncate(capacity);
+        self.entries != before
+    }
+
+    /// The last `n` entries, newest first.
+    pub fn last(&self, n: usize) -> Vec<LedgerEntry> {
+        self.entries.iter().rev().take(n).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encodes the entries oldest first, separated by `;`.
+    pub fn encode(&self) -> String {
+        Self::encode_entries(self.entries.iter())
+    }
+
+    pub fn encode_entries<'a>(entries: impl Iterator<Item = &'a LedgerEntry>) -> String {
+        entries
+            .map(LedgerEntry::encode)
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    pub fn decode(encoded: &str) -> Result<Ledger, String> {
+        let mut ledger = Ledger::new();
+        for entry in encoded.split(';').filter(|e| !e.trim().is_empty()) {
+            ledger.entries.insert(LedgerEntry::decode(entry)?);
+        }
+        Ok(ledger)
+    }
+
+    fn truncate(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            self.entries.pop_first();
+        }
+    }
+}
+
+#[cfg(test)]
+mod ledger_test {
+    use super::*;
+
+    fn entry(timestamp: u64, server_id: u8, points: i64) -> LedgerEntry {
+        LedgerEntry {
+            timestamp,
+            server_id,
+            seq: 0,
+            kind: LedgerKind::Earned,
+            points,
+            coffee_maker: Some("coffee-1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_oldest_entries_are_dropped() {
+        let mut ledger = Ledger::new();
+        for timestamp in 1..=4 {
+            ledger.record(entry(timestamp, 1, 10), 3);
+        }
+
+        let last = ledger.last(10);
+
+        assert_eq!(ledger.len(), 3);
+        assert_eq!(
+            last.iter().map(|e| e.timestamp).collect::<Vec<u64>>(),
+            vec![4, 3, 2]
+        );
+    }
+
+    #[test]
+    fn test_replicas_converge_in_any_merge_order() {
+        let mut a = Ledger::new();
+        a.record(entry(1, 1, 10), 2);
+        a.record(entry(3, 1, 10), 2);
+        let mut b = Ledger::new();
+        b.record(entry(2, 2, 5), 2);
+        b.record(entry(4, 2, 5), 2);
+
+        let mut ab = a.clone();
+        assert!(ab.merge(&b, 2));
+        let mut ba = b.clone();
+        assert!(ba.merge(&a, 2));
+
+        assert_eq!(ab, ba);
+        assert!(!ab.merge(&a, 2));
+        assert_eq!(ab.last(2)[1].timestamp, 3);
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let mut ledger = Ledger::new();
+        ledger.record(entry(1, 1, 10), 5);
+        ledger.record(
+            LedgerEntry {
+                timestamp: 2,
+                server_id: 3,
+                seq: 7,
+                kind: LedgerKind::Adjusted,
+                points: -4,
+                coffee_maker: None,
+            },
+            5,
+        );
+
+        let encoded = ledger.encode();
+
+        assert_eq!(encoded, "1:1:0:EARN:10:coffee-1;2:3:7:ADJUST:-4:-");
+        assert_eq!(Ledger::decode(&encoded), Ok(ledger));
+        assert_eq!(Ledger::decode(""), Ok(Ledger::new()));
+        assert!(Ledger::decode("1:1:0:GIFT:10:-").is_err());
+    }
+
+    #[test]
+    fn test_coffee_maker_ids_cannot_break_the_encoding() {
+        assert!(LedgerEntry::is_valid_coffee_maker("coffee-12_a.b"));
+        assert!(!LedgerEntry::is_valid_coffee_maker("coffee:1"));
+        assert!(!LedgerEntry::is_valid_coffee_maker("a;b"));
+        assert!(!LedgerEntry::is_valid_coffee_maker(""));
+    }
+}
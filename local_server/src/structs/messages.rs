@@ -1,45 +1,170 @@
 use super::account::Account;
+use super::account_status::{AccountState, AccountStatus};
+use super::g_counter::GCounter;
+use super::ledger::{Ledger, LedgerEntry};
+use super::points::Points;
+use super::registration::Registration;
+use super::server_error::ServerError;
+use super::shard_locks::ShardFilter;
 use actix::Message;
 use tokio::net::TcpStream;
 
+/// Coffee order behind an ADD or REQ, used to apply promotions and to
+/// tell in the ledger which coffee maker served it. Points that do not come
+/// from a coffee order carry none.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoffeeOrder {
+    pub product: Option<String>,
+    pub coffee_maker: Option<String>,
+}
+
 #[derive(Message, Debug)]
-#[rtype(result = "Result<(),()>")]
+#[rtype(result = "Result<(),ServerError>")]
 pub struct AddPoints {
     pub customer_id: u32,
-    pub points: u32,
+    pub points: Points,
+    pub order: Option<CoffeeOrder>,
 }
 
+/// Points moved into an account by a transfer, added as they come.
 #[derive(Message, Debug)]
-#[rtype(result = "Result<u32,()>")]
+#[rtype(result = "Result<(),ServerError>")]
+pub struct CreditPoints {
+    pub customer_id: u32,
+    pub points: Points,
+}
+
+/// Vote of the credit leg of a transfer: the account must exist and be
+/// open, transfers never open accounts.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(),ServerError>")]
+pub struct CheckCredit {
+    pub customer_id: u32,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Points,ServerError>")]
 pub struct BlockPoints {
     pub customer_id: u32,
-    pub points: u32,
+    pub points: Points,
+    pub order: Option<CoffeeOrder>,
 }
 
 #[derive(Message, Debug)]
-#[rtype(result = "Result<u32,()>")]
+#[rtype(result = "Result<Points,ServerError>")]
 pub struct SubtractPoints {
     pub customer_id: u32,
-    pub points: u32,
+    pub points: Points,
+    pub coffee_maker: Option<String>,
 }
 
 #[derive(Message, Debug)]
-#[rtype(result = "Result<u32,()>")]
+#[rtype(result = "Result<Points,ServerError>")]
 pub struct UnblockPoints {
     pub customer_id: u32,
-    pub points: u32,
+    pub points: Points,
+    pub coffee_maker: Option<String>,
 }
 
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(),ServerError>")]
+pub struct AdjustPoints {
+    pub customer_id: u32,
+    pub points: i64,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Points")]
+pub struct GlobalBlockedPoints {}
+
+/// Accounts flagged for review by the anomaly rules, with the rule they
+/// broke.
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<(u32, String)>")]
+pub struct FlaggedAccounts {}
+
+/// Handshake of a coffee maker session, accepted if the coffee maker is
+/// allowed to connect.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), ServerError>")]
+pub struct RegisterCoffeeMaker {
+    pub registration: Option<Registration>,
+}
+
+/// Stops taking new reservations and coffee makers, before the server
+/// leaves the ring.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct StopAccepting {}
+
+/// Encoded product catalog, answered to coffee makers on `CATALOG`.
+#[derive(Message, Debug)]
+#[rtype(result = "String")]
+pub struct GetCatalog {}
+
 #[derive(Message, Debug)]
 #[rtype(result = "String")]
 pub struct SyncAccount {
     pub customer_id: u32,
-    pub points: u32,
+    pub redeemed: Points,
+    pub expired: Points,
+    pub orders: u64,
+    pub earned: GCounter,
+    pub status: AccountStatus,
+    pub ledger: Ledger,
+}
+
+/// Earned points, status and ledger gossiped by another server.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct MergeEarned {
+    pub customer_id: u32,
+    pub earned: GCounter,
+    pub status: AccountStatus,
+    pub ledger: Ledger,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "Vec<Account>")]
-pub struct SyncNextServer {}
+pub struct PendingGossip {}
+
+/// Last `entries` movements of an account, newest first.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<LedgerEntry>,ServerError>")]
+pub struct GetLedger {
+    pub customer_id: u32,
+    pub entries: usize,
+}
+
+/// OPEN, FREEZE or CLOSE an account, sent by the controller to any server.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(),ServerError>")]
+pub struct ChangeStatus {
+    pub customer_id: u32,
+    pub state: AccountState,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<Account>")]
+pub struct PendingReplication {}
+
+/// Registers the buffered adjustments and returns the accounts to sync to
+/// the right neighbor. With shard locks only the accounts of `shards`
+/// register them; the others keep them until their shard is held.
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<Account>")]
+pub struct SyncNextServer {
+    pub shards: Option<ShardFilter>,
+}
+
+/// Expires the lots earned more than the configured days before `today`.
+/// With shard locks only the accounts of `shards` are swept.
+#[derive(Message, Debug)]
+#[rtype(result = "Points")]
+pub struct ExpirePoints {
+    pub today: u32,
+    pub shards: Option<ShardFilter>,
+}
 
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(),String>")]
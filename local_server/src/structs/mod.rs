@@ -1,3 +1,21 @@
 pub mod account;
+pub mod account_status;
+pub mod audit_log;
+pub mod catalog;
+pub mod controller_auth;
+pub mod failure_detector;
+pub mod g_counter;
+pub mod hash_ring;
+pub mod history;
+pub mod ledger;
 pub mod messages;
+pub mod points;
+pub mod promotions;
+pub mod rate_limits;
+pub mod registration;
+pub mod server_error;
+pub mod shard_locks;
+pub mod tiers;
+pub mod timing;
 pub mod token;
+pub mod transfer;
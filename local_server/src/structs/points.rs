@@ -0,0 +1,134 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointsError {
+    Overflow,
+    Underflow,
+    Invalid(String),
+}
+
+impl fmt::Display for PointsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointsError::Overflow => write!(f, "points overflow"),
+            PointsError::Underflow => write!(f, "points underflow"),
+            PointsError::Invalid(value) => write!(f, "invalid points amount {:?}", value),
+        }
+    }
+}
+
+/// Amount of coffee points. Every arithmetic operation is checked and
+/// returns a `PointsError` instead of wrapping or panicking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Points(u64);
+
+impl Points {
+    pub const ZERO: Points = Points(0);
+
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Points) -> Result<Points, PointsError> {
+        self.0
+            .checked_add(other.0)
+            .map(Points)
+            .ok_or(PointsError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Points) -> Result<Points, PointsError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Points)
+            .ok_or(PointsError::Underflow)
+    }
+
+    /// Applies a signed adjustment, failing if the result leaves the
+    /// representable range.
+    pub fn adjust(self, adjustment: i64) -> Result<Points, PointsError> {
+        if adjustment >= 0 {
+            self.checked_add(Points(adjustment.unsigned_abs()))
+        } else {
+            self.checked_sub(Points(adjustment.unsigned_abs()))
+        }
+    }
+}
+
+impl From<u64> for Points {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl FromStr for Points {
+    type Err = PointsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .parse::<u64>()
+            .map(Points)
+            .map_err(|_| PointsError::Invalid(s.to_string()))
+    }
+}
+
+impl fmt::Display for Points {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod points_test {
+    use super::*;
+
+    #[test]
+    fn test01_add_beyond_u32_success() {
+        let points = Points::new(u32::MAX as u64);
+
+        let result = points.checked_add(Points::new(1));
+
+        assert_eq!(result, Ok(Points::new(u32::MAX as u64 + 1)));
+    }
+
+    #[test]
+    fn test02_add_overflow_fails() {
+        let result = Points::new(u64::MAX).checked_add(Points::new(1));
+
+        assert_eq!(result, Err(PointsError::Overflow));
+    }
+
+    #[test]
+    fn test03_sub_underflow_fails() {
+        let result = Points::new(5).checked_sub(Points::new(6));
+
+        assert_eq!(result, Err(PointsError::Underflow));
+    }
+
+    #[test]
+    fn test04_negative_adjustment_success() {
+        assert_eq!(Points::new(10).adjust(-4), Ok(Points::new(6)));
+        assert_eq!(Points::new(10).adjust(4), Ok(Points::new(14)));
+    }
+
+    #[test]
+    fn test05_negative_adjustment_below_zero_fails() {
+        assert_eq!(Points::new(3).adjust(-4), Err(PointsError::Underflow));
+        assert_eq!(Points::ZERO.adjust(i64::MIN), Err(PointsError::Underflow));
+    }
+
+    #[test]
+    fn test06_parse_points() {
+        assert_eq!("42".parse::<Points>(), Ok(Points::new(42)));
+        assert!("-1".parse::<Points>().is_err());
+        assert!("abc".parse::<Points>().is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::Deserialize;
+
+use super::points::{Points, PointsError};
+
+const BASE_MULTIPLIER_PERCENT: u64 = 100;
+const HOURS_PER_DAY: u8 = 24;
+
+/// Current hour of the day in UTC, so every server evaluates time windows
+/// the same way regardless of its timezone.
+pub fn current_hour() -> u8 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    ((seconds / 3600) % HOURS_PER_DAY as u64) as u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PromotedOperation {
+    Add,
+    Req,
+}
+
+/// Hours `[from, to)` in UTC. A window with `from` after `to` wraps
+/// around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct HourWindow {
+    pub from: u8,
+    pub to: u8,
+}
+
+impl HourWindow {
+    fn contains(&self, hour: u8) -> bool {
+        if self.from <= self.to {
+            self.from <= hour && hour < self.to
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+/// Rewrites the points of the orders it matches by `multiplier_percent` /
+/// 100. Every condition present must hold: the time window, the product
+/// and, for REQs, being the `n`th order paid with points of the account.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Promotion {
+    pub name: String,
+    pub operation: PromotedOperation,
+    #[serde(default)]
+    pub hours: Option<HourWindow>,
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(default)]
+    pub every_nth_order: Option<u64>,
+    pub multiplier_percent: u64,
+}
+
+/// Order being evaluated. `order_number` counts the orders paid with
+/// points of the account, this one included.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderContext<'a> {
+    pub operation: PromotedOperation,
+    pub product: Option<&'a str>,
+    pub hour: u8,
+    pub order_number: u64,
+}
+
+impl Promotion {
+    fn matches(&self, order: &OrderContext) -> bool {
+        self.operation == order.operation
+            && self.hours.map(|h| h.contains(order.hour)).unwrap_or(true)
+            && self
+                .product
+                .as_deref()
+                .map(|p| order.product == Some(p))
+                .unwrap_or(true)
+            && self
+                .every_nth_order
+                .map(|n| order.order_number.is_multiple_of(n))
+                .unwrap_or(true)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.name.contains(',') {
+            return Err(format!("Invalid promotion name {:?}", self.name));
+        }
+        if let Some(hours) = self.hours {
+            if hours.from >= HOURS_PER_DAY || hours.to > HOURS_PER_DAY {
+                return Err(format!("Invalid hours in promotion {}", self.name));
+            }
+        }
+        match (self.operation, self.every_nth_order) {
+            (_, Some(0)) => Err(format!("Promotion {} repeats every 0 orders", self.name)),
+            (PromotedOperation::Add, Some(_)) => Err(format!(
+                "Promotion {} counts orders, only REQ orders are counted",
+                self.name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Promotions of the ring, applied in order by the server that serves the
+/// order. Every server must load the same rules; `fingerprint` is sent in
+/// the server handshake so mismatches are reported.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Promotions {
+    rules: Vec<Promotion>,
+}
+
+impl Promotions {
+    pub fn new(rules: Vec<Promotion>) -> Self {
+        Self { rules }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.rules.iter().try_for_each(|rule| rule.validate())
+    }
+
+    /// Points of the order once every matching promotion is applied,
+    /// rounded down, and the names of the promotions applied.
+    pub fn apply(
+        &self,
+        order: &OrderContext,
+        points: Points,
+    ) -> Result<(Points, Vec<&str>), PointsError> {
+        let mut applied = vec![];
+        let mut rewritten = points;
+        for rule in self.rules.iter().filter(|rule| rule.matches(order)) {
+            let multiplied = (rewritten.value() as u128) * (rule.multiplier_percent as u128)
+                / (BASE_MULTIPLIER_PERCENT as u128);
+            rewritten = u64::try_from(multiplied)
+                .map(Points::new)
+                .map_err(|_| PointsError::Overflow)?;
+            applied.push(rule.name.as_str());
+        }
+        Ok((rewritten, applied))
+    }
+
+    /// FNV-1a of the rules, stable across processes and builds.
+    pub fn fingerprint(&self) -> u64 {
+        format!("{:?}", self.rules)
+            .bytes()
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+}
+
+#[cfg(test)]
+mod promotions_test {
+    use super::*;
+
+    fn promotions() -> Promotions {
+        Promotions::new(vec![
+            Promotion {
+                name: "morning_double".to_string(),
+                operation: PromotedOperation::Add,
+                hours: Some(HourWindow { from: 7, to: 9 }),
+                product: None,
+                every_nth_order: None,
+                multiplier_percent: 200,
+            },
+            Promotion {
+                name: "tenth_free".to_string(),
+                operation: PromotedOperation::Req,
+                hours: None,
+                product: Some("espresso".to_string()),
+                every_nth_order: Some(10),
+                multiplier_percent: 0,
+            },
+        ])
+    }
+
+    fn order(operation: PromotedOperation, hour: u8, order_number: u64) -> OrderContext<'static> {
+        OrderContext {
+            operation,
+            product: Some("espresso"),
+            hour,
+            order_number,
+        }
+    }
+
+    #[test]
+    fn test01_time_window_promotion_rewrites_add() {
+        let promotions = promotions();
+
+        let (points, applied) = promotions
+            .apply(&order(PromotedOperation::Add, 8, 1), Points::new(5))
+            .unwrap();
+        let (late, none) = promotions
+            .apply(&order(PromotedOperation::Add, 9, 1), Points::new(5))
+            .unwrap();
+
+        assert_eq!(points, Points::new(10));
+        assert_eq!(applied, vec!["morning_double"]);
+        assert_eq!(late, Points::new(5));
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test02_every_nth_order_of_the_product_is_free() {
+        let promotions = promotions();
+        let mut other_product = order(PromotedOperation::Req, 12, 10);
+        other_product.product = Some("latte");
+
+        let (ninth, _) = promotions
+            .apply(&order(PromotedOperation::Req, 12, 9), Points::new(30))
+            .unwrap();
+        let (tenth, applied) = promotions
+            .apply(&order(PromotedOperation::Req, 12, 10), Points::new(30))
+            .unwrap();
+        let (latte, _) = promotions.apply(&other_product, Points::new(30)).unwrap();
+
+        assert_eq!(ninth, Points::new(30));
+        assert_eq!(tenth, Points::ZERO);
+        assert_eq!(applied, vec!["tenth_free"]);
+        assert_eq!(latte, Points::new(30));
+    }
+
+    #[test]
+    fn test03_window_wraps_around_midnight() {
+        let window = HourWindow { from: 22, to: 2 };
+
+        assert!(window.contains(23));
+        assert!(window.contains(1));
+        assert!(!window.contains(2));
+        assert!(!window.contains(12));
+    }
+
+    #[test]
+    fn test04_invalid_rules_are_rejected() {
+        assert!(promotions().validate().is_ok());
+
+        let mut rules = promotions().rules;
+        rules[0].every_nth_order = Some(3);
+        assert!(Promotions::new(rules).validate().is_err());
+
+        let mut rules = promotions().rules;
+        rules[1].name = "a,b".to_string();
+        assert!(Promotions::new(rules).validate().is_err());
+    }
+
+    #[test]
+    fn test05_fingerprint_changes_with_the_rules() {
+        let mut rules = promotions().rules;
+        rules[0].multiplier_percent = 300;
+
+        assert_eq!(promotions().fingerprint(), promotions().fingerprint());
+        assert_ne!(
+            promotions().fingerprint(),
+            Promotions::new(rules).fingerprint()
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde_derive::Deserialize;
+
+use super::points::Points;
+use super::server_error::ServerError;
+
+const MINUTE_MILLIS: u64 = 60 * 1000;
+const HOUR_MILLIS: u64 = 60 * MINUTE_MILLIS;
+
+pub const REDEMPTIONS_PER_MINUTE: &str = "max_redemptions_per_minute";
+pub const POINTS_EARNED_PER_HOUR: &str = "max_points_earned_per_hour";
+
+/// Anomaly rules of the configuration. Absent rules are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub max_redemptions_per_minute: Option<usize>,
+    pub max_points_earned_per_hour: Option<u64>,
+}
+
+/// Sliding windows of the redemptions and purchases of every account served
+/// by this server. Operations rejected by a rule are not counted.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    redemptions: HashMap<u32, VecDeque<u64>>,
+    earnings: HashMap<u32, VecDeque<(u64, Points)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Counts a REQ made at `now` (milliseconds) unless the account already
+    /// made the maximum in the last minute.
+    pub fn check_redemption(&mut self, customer_id: u32, now: u64) -> Result<(), ServerError> {
+        let Some(max) = self.limits.max_redemptions_per_minute else {
+            return Ok(());
+        };
+        let window = self.redemptions.entry(customer_id).or_default();
+        while window.front().is_some_and(|t| t + MINUTE_MILLIS <= now) {
+            window.pop_front();
+        }
+        if window.len() >= max {
+            return Err(ServerError::RateLimited {
+                customer_id,
+                rule: REDEMPTIONS_PER_MINUTE.to_string(),
+            });
+        }
+        window.push_back(now);
+        Ok(())
+    }
+
+    /// Counts points earned by a purchase at `now` (milliseconds) unless
+    /// they take the account over the maximum of the last hour.
+    pub fn check_earning(
+        &mut self,
+        customer_id: u32,
+        points: Points,
+        now: u64,
+    ) -> Result<(), ServerError> {
+        let Some(max) = self.limits.max_points_earned_per_hour else {
+            return Ok(());
+        };
+        let window = self.earnings.entry(customer_id).or_default();
+        while window.front().is_some_and(|(t, _)| t + HOUR_MILLIS <= now) {
+            window.pop_front();
+        }
+        let earned = window.iter().fold(points.value(), |total, (_, p)| {
+            total.saturating_add(p.value())
+        });
+        if earned > max {
+            return Err(ServerError::RateLimited {
+                customer_id,
+                rule: POINTS_EARNED_PER_HOUR.to_string(),
+            });
+        }
+        window.push_back((now, points));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rate_limits_test {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            max_redemptions_per_minute: Some(2),
+            max_points_earned_per_hour: Some(100),
+        })
+    }
+
+    #[test]
+    fn test_redemptions_over_the_limit_are_rejected_until_the_minute_passes() {
+        let mut limiter = limiter();
+        assert!(limiter.check_redemption(1, 0).is_ok());
+        assert!(limiter.check_redemption(1, 10_000).is_ok());
+        assert_eq!(
+            limiter.check_redemption(1, 20_000),
+            Err(ServerError::RateLimited {
+                customer_id: 1,
+                rule: REDEMPTIONS_PER_MINUTE.to_string(),
+            })
+        );
+        assert!(limiter.check_redemption(2, 20_000).is_ok());
+        assert!(limiter.check_redemption(1, 60_000).is_ok());
+    }
+
+    #[test]
+    fn test_points_earned_over_the_limit_are_rejected() {
+        let mut limiter = limiter();
+        assert!(limiter.check_earning(1, Points::new(60), 0).is_ok());
+        assert!(limiter.check_earning(1, Points::new(50), 1_000).is_err());
+        assert!(limiter.check_earning(1, Points::new(40), 2_000).is_ok());
+        assert!(limiter
+            .check_earning(1, Points::new(60), HOUR_MILLIS)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_without_limits_everything_is_allowed() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..100 {
+            assert!(limiter.check_redemption(1, 0).is_ok());
+            assert!(limiter.check_earning(1, Points::new(u64::MAX), 0).is_ok());
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use log::warn;
+use serde_derive::Deserialize;
+
+use super::ledger::LedgerEntry;
+use super::server_error::ServerError;
+
+/// Identity a coffee maker gives in its handshake:
+/// `CH,<machine id>[,<location>,<firmware>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub machine_id: String,
+    pub location: Option<String>,
+    pub firmware: Option<String>,
+}
+
+impl Registration {
+    /// Registration of the handshake, None for a bare `CH` or a handshake
+    /// with fields that cannot travel in the protocol.
+    pub fn parse(parts: &[&str]) -> Option<Registration> {
+        let field = |i: usize| parts.get(i).filter(|f| !f.is_empty());
+        let fields = [field(1), field(2), field(3)];
+        if let Some(invalid) = fields
+            .iter()
+            .flatten()
+            .find(|f| !LedgerEntry::is_valid_coffee_maker(f))
+        {
+            warn!("Ignoring registration with invalid field {:?}", invalid);
+            return None;
+        }
+        Some(Registration {
+            machine_id: fields[0]?.to_string(),
+            location: fields[1].map(|f| f.to_string()),
+            firmware: fields[2].map(|f| f.to_string()),
+        })
+    }
+
+    /// Handshake with the same identity, used by servers that forward the
+    /// orders of the coffee maker.
+    pub fn handshake(&self) -> String {
+        format!(
+            "CH,{},{},{}\n",
+            self.machine_id,
+            self.location.as_deref().unwrap_or(""),
+            self.firmware.as_deref().unwrap_or("")
+        )
+    }
+}
+
+/// Coffee maker allowed to connect. Without location or firmware list any
+/// is accepted.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AllowedCoffeeMaker {
+    pub id: String,
+    pub location: Option<String>,
+    #[serde(default)]
+    pub firmware: Vec<String>,
+}
+
+impl AllowedCoffeeMaker {
+    fn accepts(&self, registration: &Registration) -> bool {
+        self.id == registration.machine_id
+            && self
+                .location
+                .as_ref()
+                .is_none_or(|location| registration.location.as_ref() == Some(location))
+            && (self.firmware.is_empty()
+                || registration
+                    .firmware
+                    .as_ref()
+                    .is_some_and(|firmware| self.firmware.contains(firmware)))
+    }
+}
+
+/// Checks registrations against the configured coffee makers. An empty
+/// allowlist accepts every coffee maker, registered or not.
+pub fn check_registration(
+    allowlist: &[AllowedCoffeeMaker],
+    registration: Option<&Registration>,
+) -> Result<(), ServerError> {
+    if allowlist.is_empty() {
+        return Ok(());
+    }
+    match registration {
+        Some(registration) if allowlist.iter().any(|m| m.accepts(registration)) => Ok(()),
+        Some(registration) => Err(ServerError::Unregistered(registration.machine_id.clone())),
+        None => Err(ServerError::Unregistered("-".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod registration_test {
+    use super::*;
+
+    fn registration(location: &str, firmware: &str) -> Registration {
+        Registration::parse(&["CH", "coffee-1", location, firmware]).unwrap()
+    }
+
+    fn allowlist() -> Vec<AllowedCoffeeMaker> {
+        vec![AllowedCoffeeMaker {
+            id: "coffee-1".to_string(),
+            location: Some("palermo".to_string()),
+            firmware: vec!["1.2.0".to_string()],
+        }]
+    }
+
+    #[test]
+    fn test_handshake_fields_are_parsed() {
+        let registration = registration("palermo", "1.2.0");
+
+        assert_eq!(registration.machine_id, "coffee-1");
+        assert_eq!(registration.location, Some("palermo".to_string()));
+        assert_eq!(registration.firmware, Some("1.2.0".to_string()));
+        assert_eq!(registration.handshake(), "CH,coffee-1,palermo,1.2.0\n");
+    }
+
+    #[test]
+    fn test_old_handshakes_are_still_accepted() {
+        assert_eq!(Registration::parse(&["CH"]), None);
+        let registration = Registration::parse(&["CH", "coffee-1"]).unwrap();
+        assert_eq!(registration.location, None);
+        assert_eq!(registration.handshake(), "CH,coffee-1,,\n");
+        assert_eq!(
+            Registration::parse(&["CH", "coffee-1", "", ""]),
+            Some(registration)
+        );
+    }
+
+    #[test]
+    fn test_fields_that_break_the_protocol_are_ignored() {
+        assert_eq!(Registration::parse(&["CH", "coffee:1"]), None);
+        assert_eq!(Registration::parse(&["CH", "coffee-1", "a;b", "1"]), None);
+    }
+
+    #[test]
+    fn test_only_listed_coffee_makers_are_accepted() {
+        let allowlist = allowlist();
+
+        assert!(check_registration(&allowlist, Some(&registration("palermo", "1.2.0"))).is_ok());
+        assert!(check_registration(&allowlist, Some(&registration("belgrano", "1.2.0"))).is_err());
+        assert!(check_registration(&allowlist, Some(&registration("palermo", "0.9.0"))).is_err());
+        assert_eq!(
+            check_registration(&allowlist, None),
+            Err(ServerError::Unregistered("-".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_without_allowlist_every_coffee_maker_is_accepted() {
+        assert!(check_registration(&[], None).is_ok());
+        assert!(check_registration(&[], Some(&registration("", ""))).is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use super::points::{Points, PointsError};
+
+/// Reasons an operation can be rejected. The `code` travels through the
+/// protocol so clients know why a REQ/SUBS/UNBL/ADD/ADJ failed, e.g.
+/// `NOT OK,INSUFFICIENT_POINTS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    AccountNotFound(u32),
+    AccountFrozen(u32),
+    AccountClosed(u32),
+    InsufficientPoints {
+        customer_id: u32,
+        requested: Points,
+        available: Points,
+    },
+    NotBlocked {
+        customer_id: u32,
+        requested: Points,
+        blocked: Points,
+    },
+    NoReservation,
+    NotTokenHolder,
+    InvalidPoints(PointsError),
+    Unavailable,
+    UnknownProduct(String),
+    /// The operation breaks an anomaly rule, the account is flagged for
+    /// review.
+    RateLimited {
+        customer_id: u32,
+        rule: String,
+    },
+    /// The coffee maker is not in the allowlist of the server.
+    Unregistered(String),
+    /// A controller session failed the challenge of the operator.
+    Unauthorized(String),
+    /// The server is leaving the ring and takes no new reservations or
+    /// coffee makers.
+    ShuttingDown,
+    /// Rejection code answered by another server taking part in the
+    /// operation.
+    RemoteRejection(String),
+}
+
+impl ServerError {
+    pub fn code(&self) -> &str {
+        match self {
+            ServerError::AccountNotFound(_) => "ACCOUNT_NOT_FOUND",
+            ServerError::AccountFrozen(_) => "ACCOUNT_FROZEN",
+            ServerError::AccountClosed(_) => "ACCOUNT_CLOSED",
+            ServerError::InsufficientPoints { .. } => "INSUFFICIENT_POINTS",
+            ServerError::NotBlocked { .. } => "NOT_BLOCKED",
+            ServerError::NoReservation => "NO_RESERVATION",
+            ServerError::NotTokenHolder => "NOT_TOKEN_HOLDER",
+            ServerError::InvalidPoints(_) => "INVALID_POINTS",
+            ServerError::Unavailable => "UNAVAILABLE",
+            ServerError::UnknownProduct(_) => "UNKNOWN_PRODUCT",
+            ServerError::RateLimited { .. } => "RATE_LIMITED",
+            ServerError::Unregistered(_) => "UNREGISTERED",
+            ServerError::Unauthorized(_) => "UNAUTHORIZED",
+            ServerError::ShuttingDown => "SHUTTING_DOWN",
+            ServerError::RemoteRejection(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::AccountNotFound(customer_id) => {
+                write!(f, "account {} does not exist", customer_id)
+            }
+            ServerError::AccountFrozen(customer_id) => {
+                write!(f, "account {} is frozen", customer_id)
+            }
+            ServerError::AccountClosed(customer_id) => {
+                write!(f, "account {} is closed", customer_id)
+            }
+            ServerError::InsufficientPoints {
+                customer_id,
+                requested,
+                available,
+            } => write!(
+                f,
+                "account {} has {} points available, {} requested",
+                customer_id, available, requested
+            ),
+            ServerError::NotBlocked {
+                customer_id,
+                requested,
+                blocked,
+            } => write!(
+                f,
+                "account {} has {} points blocked, {} requested",
+                customer_id, blocked, requested
+            ),
+            ServerError::NoReservation => write!(f, "no previous successful REQ"),
+            ServerError::NotTokenHolder => write!(f, "server does not hold the token"),
+            ServerError::InvalidPoints(e) => write!(f, "{}", e),
+            ServerError::Unavailable => write!(f, "server actor unavailable"),
+            ServerError::UnknownProduct(id) => write!(f, "product {} is not in the catalog", id),
+            ServerError::RateLimited { customer_id, rule } => {
+                write!(f, "account {} exceeded {}", customer_id, rule)
+            }
+            ServerError::Unregistered(machine_id) => {
+                write!(f, "coffee maker {} is not registered", machine_id)
+            }
+            ServerError::Unauthorized(operator) => {
+                write!(f, "operator {} could not authenticate", operator)
+            }
+            ServerError::ShuttingDown => write!(f, "server is shutting down"),
+            ServerError::RemoteRejection(code) => write!(f, "rejected by other server: {}", code),
+        }
+    }
+}
+
+impl From<PointsError> for ServerError {
+    fn from(error: PointsError) -> Self {
+        ServerError::InvalidPoints(error)
+    }
+}
+
+#[cfg(test)]
+mod server_error_test {
+    use super::*;
+
+    #[test]
+    fn test01_points_errors_are_invalid_points() {
+        let error: ServerError = PointsError::Overflow.into();
+
+        assert_eq!(error, ServerError::InvalidPoints(PointsError::Overflow));
+        assert_eq!(error.code(), "INVALID_POINTS");
+    }
+
+    #[test]
+    fn test02_display_explains_the_rejection() {
+        let error = ServerError::InsufficientPoints {
+            customer_id: 3,
+            requested: Points::new(10),
+            available: Points::new(4),
+        };
+
+        assert_eq!(error.code(), "INSUFFICIENT_POINTS");
+        assert_eq!(
+            error.to_string(),
+            "account 3 has 4 points available, 10 requested"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use log::{error, info};
+use serde_derive::Deserialize;
+
+/// How redemptions are serialized across the ring.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ConcurrencyMode {
+    /// A single token: only its holder may block or consume points.
+    #[default]
+    GlobalToken,
+    /// Accounts are split in `shards` by `customer_id % shards` and the
+    /// token carries which server owns each shard, so servers touching
+    /// different shards redeem in parallel.
+    ShardLocks { shards: u32 },
+}
+
+/// Snapshot of the shards owned by a server, used to restrict work that
+/// only the owner of an account may do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardFilter {
+    shards: u32,
+    owned: BTreeSet<u32>,
+}
+
+impl ShardFilter {
+    pub fn contains(&self, customer_id: u32) -> bool {
+        self.owned.contains(&(customer_id % self.shards))
+    }
+}
+
+/// Shards owned by this server and the REQs waiting for or holding them.
+/// The token carries the ring wide table as `<shard>:<owner>;...`; a shard
+/// absent from the table is free.
+#[derive(Debug)]
+pub struct ShardLocks {
+    server_id: u8,
+    shards: u32,
+    owned: BTreeSet<u32>,
+    waiting: HashMap<u32, u32>,
+    in_flight: HashMap<u32, u32>,
+}
+
+impl ShardLocks {
+    pub fn new(server_id: u8, shards: u32) -> Self {
+        Self {
+            server_id,
+            shards: shards.max(1),
+            owned: BTreeSet::new(),
+            waiting: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn shard_of(&self, customer_id: u32) -> u32 {
+        customer_id % self.shards
+    }
+
+    pub fn owns(&self, customer_id: u32) -> bool {
+        self.owned.contains(&self.shard_of(customer_id))
+    }
+
+    pub fn filter(&self) -> ShardFilter {
+        ShardFilter {
+            shards: self.shards,
+            owned: self.owned.clone(),
+        }
+    }
+
+    pub fn wait_for(&mut self, customer_id: u32) {
+        let shard = self.shard_of(customer_id);
+        *self.waiting.entry(shard).or_insert(0) += 1;
+    }
+
+    pub fn stop_waiting(&mut self, customer_id: u32) {
+        let shard = self.shard_of(customer_id);
+        decrement(&mut self.waiting, shard);
+    }
+
+    pub fn begin_reservation(&mut self, customer_id: u32) {
+        let shard = self.shard_of(customer_id);
+        *self.in_flight.entry(shard).or_insert(0) += 1;
+    }
+
+    pub fn end_reservation(&mut self, customer_id: u32) {
+        let shard = self.shard_of(customer_id);
+        decrement(&mut self.in_flight, shard);
+    }
+
+    /// Applies a token visit to the table it carries: idle shards are
+    /// released, shards with waiting REQs are taken if free and shards this
+    /// server still uses are reasserted. Returns the table to forward.
+    pub fn update_table(&mut self, table: &str) -> Result<String, String> {
+        let mut table = decode_table(table)?;
+        table.retain(|shard, owner| *owner != self.server_id || self.owned.contains(shard));
+
+        for shard in self.owned.clone() {
+            if self.is_idle(shard) {
+                info!("Releasing shard {}", shard);
+                self.owned.remove(&shard);
+                table.remove(&shard);
+                continue;
+            }
+            let owner = *table.entry(shard).or_insert(self.server_id);
+            if owner != self.server_id {
+                error!("Shard {} is also owned by server {}", shard, owner);
+            }
+        }
+
+        for (shard, waiting) in self.waiting.iter() {
+            if *waiting > 0 && !table.contains_key(shard) {
+                info!("Acquiring shard {}", shard);
+                table.insert(*shard, self.server_id);
+                self.owned.insert(*shard);
+            }
+        }
+
+        Ok(encode_table(&table))
+    }
+
+    fn is_idle(&self, shard: u32) -> bool {
+        self.waiting.get(&shard).copied().unwrap_or(0) == 0
+            && self.in_flight.get(&shard).copied().unwrap_or(0) == 0
+    }
+}
+
+fn decrement(counts: &mut HashMap<u32, u32>, shard: u32) {
+    if let Some(count) = counts.get_mut(&shard) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(&shard);
+        }
+    }
+}
+
+fn encode_table(table: &BTreeMap<u32, u8>) -> String {
+    table
+        .iter()
+        .map(|(shard, owner)| format!("{}:{}", shard, owner))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+fn decode_table(table: &str) -> Result<BTreeMap<u32, u8>, String> {
+    let mut owners = BTreeMap::new();
+    for entry in table.split(';').filter(|e| !e.trim().is_empty()) {
+        let (shard, owner) = entry
+            .split_once(':')
+            .ok_or(format!("Invalid shard lock entry {}", entry))?;
+        owners.insert(
+            shard.trim().parse::<u32>().map_err(|e| e.to_string())?,
+            owner.trim().parse::<u8>().map_err(|e| e.to_string())?,
+        );
+    }
+    Ok(owners)
+}
+
+#[cfg(test)]
+mod shard_locks_test {
+    use super::*;
+
+    #[test]
+    fn test01_waiting_shard_is_acquired_when_free() {
+        let mut locks = ShardLocks::new(1, 4);
+        locks.wait_for(5);
+
+        let table = locks.update_table("").unwrap();
+
+        assert_eq!(table, "1:1");
+        assert!(locks.owns(5));
+        assert!(!locks.owns(6));
+    }
+
+    #[test]
+    fn test02_shard_owned_by_other_server_is_not_acquired() {
+        let mut locks = ShardLocks::new(1, 4);
+        locks.wait_for(5);
+
+        let table = locks.update_table("1:2").unwrap();
+
+        assert_eq!(table, "1:2");
+        assert!(!locks.owns(5));
+    }
+
+    #[test]
+    fn test03_disjoint_shards_are_owned_in_parallel() {
+        let mut first = ShardLocks::new(1, 4);
+        let mut second = ShardLocks::new(2, 4);
+        first.wait_for(5);
+        second.wait_for(6);
+
+        let table = first.update_table("").unwrap();
+        let table = second.update_table(&table).unwrap();
+
+        assert_eq!(table, "1:1;2:2");
+        assert!(first.owns(5));
+        assert!(second.owns(6));
+    }
+
+    #[test]
+    fn test04_shard_in_use_is_kept_and_idle_shard_is_released() {
+        let mut locks = ShardLocks::new(1, 4);
+        locks.wait_for(5);
+        locks.update_table("").unwrap();
+        locks.stop_waiting(5);
+        locks.begin_reservation(5);
+
+        assert_eq!(locks.update_table("1:1").unwrap(), "1:1");
+
+        locks.end_reservation(5);
+
+        assert_eq!(locks.update_table("1:1").unwrap(), "");
+        assert!(!locks.owns(5));
+    }
+
+    #[test]
+    fn test05_stale_entries_of_this_server_are_dropped() {
+        let mut locks = ShardLocks::new(1, 4);
+
+        assert_eq!(locks.update_table("0:1;3:2").unwrap(), "3:2");
+    }
+
+    #[test]
+    fn test06_filter_contains_accounts_of_owned_shards() {
+        let mut locks = ShardLocks::new(1, 4);
+        locks.wait_for(5);
+        locks.update_table("").unwrap();
+
+        let filter = locks.filter();
+
+        assert!(filter.contains(9));
+        assert!(!filter.contains(6));
+    }
+
+    #[test]
+    fn test07_malformed_table_fails() {
+        let mut locks = ShardLocks::new(1, 4);
+
+        assert!(locks.update_table("1").is_err());
+        assert!(locks.update_table("a:1").is_err());
+    }
+}
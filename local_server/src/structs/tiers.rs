@@ -0,0 +1,107 @@
+use serde_derive::Deserialize;
+
+use super::points::{Points, PointsError};
+
+const BASE_MULTIPLIER_PERCENT: u64 = 100;
+
+/// Loyalty tier reached once a customer earned `min_lifetime_points`. The
+/// points of every purchase are multiplied by `multiplier_percent` / 100.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Tier {
+    pub name: String,
+    pub min_lifetime_points: u64,
+    pub multiplier_percent: u64,
+}
+
+/// Tiers configured for the ring. Every server must load the same tiers,
+/// and the tier of an account only depends on its earned points, which are
+/// replicated, so every server computes the same tier. Without tiers the
+/// points of an order are added as they come.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct TierPolicy {
+    tiers: Vec<Tier>,
+}
+
+impl TierPolicy {
+    pub fn new(tiers: Vec<Tier>) -> Self {
+        Self { tiers }
+    }
+
+    /// Highest tier reached with `lifetime_points`, if any.
+    pub fn tier_for(&self, lifetime_points: Points) -> Option<&Tier> {
+        self.tiers
+            .iter()
+            .filter(|tier| tier.min_lifetime_points <= lifetime_points.value())
+            .max_by_key(|tier| tier.min_lifetime_points)
+    }
+
+    /// Points earned by a purchase of `points` for a customer with
+    /// `lifetime_points`, rounded down.
+    pub fn apply(&self, lifetime_points: Points, points: Points) -> Result<Points, PointsError> {
+        let percent = self
+            .tier_for(lifetime_points)
+            .map(|tier| tier.multiplier_percent)
+            .unwrap_or(BASE_MULTIPLIER_PERCENT);
+        let multiplied =
+            (points.value() as u128) * (percent as u128) / (BASE_MULTIPLIER_PERCENT as u128);
+        u64::try_from(multiplied)
+            .map(Points::new)
+            .map_err(|_| PointsError::Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tiers_test {
+    use super::*;
+
+    fn policy() -> TierPolicy {
+        TierPolicy::new(vec![
+            Tier {
+                name: "gold".to_string(),
+                min_lifetime_points: 500,
+                multiplier_percent: 200,
+            },
+            Tier {
+                name: "silver".to_string(),
+                min_lifetime_points: 100,
+                multiplier_percent: 150,
+            },
+        ])
+    }
+
+    #[test]
+    fn test01_highest_reached_tier_is_chosen() {
+        let policy = policy();
+
+        assert_eq!(policy.tier_for(Points::new(99)), None);
+        assert_eq!(policy.tier_for(Points::new(100)).unwrap().name, "silver");
+        assert_eq!(policy.tier_for(Points::new(800)).unwrap().name, "gold");
+    }
+
+    #[test]
+    fn test02_multiplier_is_applied_and_rounded_down() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.apply(Points::new(0), Points::new(5)),
+            Ok(Points::new(5))
+        );
+        assert_eq!(
+            policy.apply(Points::new(100), Points::new(5)),
+            Ok(Points::new(7))
+        );
+        assert_eq!(
+            policy.apply(Points::new(500), Points::new(5)),
+            Ok(Points::new(10))
+        );
+    }
+
+    #[test]
+    fn test03_multiplied_overflow_fails() {
+        assert_eq!(
+            policy().apply(Points::new(500), Points::new(u64::MAX)),
+            Err(PointsError::Overflow)
+        );
+    }
+}
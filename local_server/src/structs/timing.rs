@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_derive::Deserialize;
+
+use super::failure_detector::FailureDetectorSettings;
+
+const DEFAULT_IDLE_TOKEN_DELAY_MILLIS: u64 = 1000;
+const DEFAULT_TOKEN_TIMEOUT_MILLIS: u64 = 20000;
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_RECONNECT_BASE_DELAY_MILLIS: u64 = 1000;
+const DEFAULT_RECONNECT_MAX_DELAY_MILLIS: u64 = 8000;
+const DEFAULT_RECONNECT_JITTER_PERCENT: u64 = 20;
+
+/// Delays of the ring, set in the `timing` section of the configuration.
+/// They are awaited, so coffee makers keep being served meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Timing {
+    /// Pause before passing on a token no coffee maker is waiting for, so
+    /// an idle ring does not spin.
+    pub idle_token_delay_millis: u64,
+    /// Time without TOKEN from the left neighbor before the token is
+    /// considered lost, until the failure detector learned how often it
+    /// arrives. The time this server holds the token does not count.
+    pub token_timeout_millis: u64,
+    pub failure_detector: FailureDetectorSettings,
+    /// Attempts to connect to a right neighbor before skipping it.
+    pub reconnect_attempts: u32,
+    /// Pause after the first failed attempt, doubled on every retry up to
+    /// `reconnect_max_delay_millis`.
+    pub reconnect_base_delay_millis: u64,
+    pub reconnect_max_delay_millis: u64,
+    /// Up to this percent of every pause is added or removed at random, so
+    /// servers retrying at the same time spread out.
+    pub reconnect_jitter_percent: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            idle_token_delay_millis: DEFAULT_IDLE_TOKEN_DELAY_MILLIS,
+            token_timeout_millis: DEFAULT_TOKEN_TIMEOUT_MILLIS,
+            failure_detector: FailureDetectorSettings::default(),
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_base_delay_millis: DEFAULT_RECONNECT_BASE_DELAY_MILLIS,
+            reconnect_max_delay_millis: DEFAULT_RECONNECT_MAX_DELAY_MILLIS,
+            reconnect_jitter_percent: DEFAULT_RECONNECT_JITTER_PERCENT,
+        }
+    }
+}
+
+impl Timing {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.token_timeout_millis == 0 {
+            return Err("The token timeout must be positive".to_string());
+        }
+        if self.reconnect_attempts == 0 {
+            return Err("At least one reconnect attempt is needed".to_string());
+        }
+        if self.reconnect_base_delay_millis > self.reconnect_max_delay_millis {
+            return Err("The reconnect base delay exceeds the maximum delay".to_string());
+        }
+        if self.reconnect_jitter_percent > 100 {
+            return Err("The reconnect jitter must be at most 100 percent".to_string());
+        }
+        self.failure_detector.validate()
+    }
+
+    pub fn idle_token_delay(&self) -> Duration {
+        Duration::from_millis(self.idle_token_delay_millis)
+    }
+
+    pub fn token_timeout(&self) -> Duration {
+        Duration::from_millis(self.token_timeout_millis)
+    }
+
+    /// Pause after the failed attempt number `attempt`, counted from 0.
+    pub fn reconnect_delay(&self, attempt: u32) -> Duration {
+        let mut sample = [0; 4];
+        let jitter = match SystemRandom::new().fill(&mut sample) {
+            Ok(_) => u32::from_be_bytes(sample) as f64 / u32::MAX as f64 * 2.0 - 1.0,
+            Err(_) => 0.0,
+        };
+        self.backoff(attempt, jitter)
+    }
+
+    /// Exponential backoff with `jitter`, between -1 and 1, scaled to the
+    /// configured percent.
+    fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        let delay = self
+            .reconnect_base_delay_millis
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.reconnect_max_delay_millis) as f64;
+        let spread = delay * self.reconnect_jitter_percent as f64 / 100.0;
+        Duration::from_millis((delay + spread * jitter.clamp(-1.0, 1.0)).max(0.0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod timing_test {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay_doubles_up_to_the_maximum() {
+        let timing = Timing {
+            reconnect_jitter_percent: 0,
+            ..Timing::default()
+        };
+
+        let delays: Vec<u64> = (0..6)
+            .map(|attempt| timing.backoff(attempt, 0.5).as_millis() as u64)
+            .collect();
+
+        assert_eq!(delays, vec![1000, 2000, 4000, 8000, 8000, 8000]);
+        assert_eq!(timing.backoff(u32::MAX, 0.0), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_jitter_spreads_the_delay_by_its_percent() {
+        let timing = Timing::default();
+
+        assert_eq!(timing.backoff(1, -1.0), Duration::from_millis(1600));
+        assert_eq!(timing.backoff(1, 1.0), Duration::from_millis(2400));
+        for _ in 0..20 {
+            let delay = timing.reconnect_delay(0);
+            assert!(delay >= Duration::from_millis(800) && delay <= Duration::from_millis(1200));
+        }
+    }
+
+    #[test]
+    fn test_invalid_timings_are_rejected() {
+        let valid = Timing::default();
+
+        assert_eq!(valid.validate(), Ok(()));
+        assert!(Timing {
+            reconnect_attempts: 0,
+            ..valid
+        }
+        .validate()
+        .is_err());
+        assert!(Timing {
+            reconnect_base_delay_millis: 10000,
+            ..valid
+        }
+        .validate()
+        .is_err());
+        assert!(Timing {
+            reconnect_jitter_percent: 150,
+            ..valid
+        }
+        .validate()
+        .is_err());
+    }
+}
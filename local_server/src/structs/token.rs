@@ -1,13 +1,67 @@
+use std::time::{Duration, Instant};
+
+use serde_derive::Deserialize;
+
+use super::shard_locks::ShardLocks;
+
+/// Limits how much work a server may do on each token visit. Once either
+/// bound is reached no new REQ is served: in flight redemptions finish and
+/// the token moves on, leftover REQs wait for the next lap.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HoldPolicy {
+    pub max_operations: Option<u32>,
+    pub max_hold_millis: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct Token {
     status: bool,
+    policy: HoldPolicy,
+    operations: u32,
+    acquired_at: Option<Instant>,
+    locks: Option<ShardLocks>,
 }
 
 impl Token {
     pub fn new() -> Self {
+        Self::with_policy(HoldPolicy::default())
+    }
+
+    pub fn with_policy(policy: HoldPolicy) -> Self {
         let status: bool = false;
 
-        Self { status }
+        Self {
+            status,
+            policy,
+            operations: 0,
+            acquired_at: None,
+            locks: None,
+        }
+    }
+
+    /// Token that only carries shard ownership: REQs wait for the shard of
+    /// their account instead of the whole token.
+    pub fn with_shards(policy: HoldPolicy, server_id: u8, shards: u32) -> Self {
+        let mut token = Self::with_policy(policy);
+        token.locks = Some(ShardLocks::new(server_id, shards));
+        token
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        self.locks.is_some()
+    }
+
+    pub fn locks_mut(&mut self) -> Option<&mut ShardLocks> {
+        self.locks.as_mut()
+    }
+
+    /// True when this server may block or consume points of the account.
+    pub fn can_settle(&self, customer_id: u32) -> bool {
+        match &self.locks {
+            Some(locks) => locks.owns(customer_id),
+            None => self.status,
+        }
     }
 
     pub fn is_avaliable(&self) -> bool {
@@ -16,10 +70,35 @@ impl Token {
 
     pub fn avaliable(&mut self) {
         self.status = true;
+        self.operations = 0;
+        self.acquired_at = Some(Instant::now());
     }
 
     pub fn not_avaliable(&mut self) {
         self.status = false;
+        self.acquired_at = None;
+    }
+
+    /// True when the token is held and the current visit still has room
+    /// for another REQ.
+    pub fn can_serve(&self) -> bool {
+        self.status && !self.is_exhausted()
+    }
+
+    pub fn register_operation(&mut self) {
+        self.operations += 1;
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        let too_many_operations = match self.policy.max_operations {
+            Some(max) => self.operations >= max,
+            None => false,
+        };
+        let held_too_long = match (self.policy.max_hold_millis, self.acquired_at) {
+            (Some(max), Some(acquired_at)) => acquired_at.elapsed() >= Duration::from_millis(max),
+            _ => false,
+        };
+        too_many_operations || held_too_long
     }
 }
 
@@ -31,7 +110,7 @@ impl Default for Token {
 
 #[cfg(test)]
 mod token_test {
-    use super::Token;
+    use super::{HoldPolicy, Token};
 
     #[test]
     fn test01_token_start_not_avalible() {
@@ -55,4 +134,74 @@ mod token_test {
 
         assert!(!token.is_avaliable());
     }
+
+    #[test]
+    fn test04_token_without_policy_is_never_exhausted() {
+        let mut token = Token::new();
+        token.avaliable();
+        for _ in 0..1000 {
+            token.register_operation();
+        }
+
+        assert!(token.can_serve());
+    }
+
+    #[test]
+    fn test05_token_is_exhausted_after_max_operations() {
+        let mut token = Token::with_policy(HoldPolicy {
+            max_operations: Some(2),
+            max_hold_millis: None,
+        });
+        token.avaliable();
+        token.register_operation();
+        assert!(token.can_serve());
+        token.register_operation();
+
+        assert!(token.is_exhausted());
+        assert!(!token.can_serve());
+    }
+
+    #[test]
+    fn test06_token_is_exhausted_after_max_hold_time() {
+        let mut token = Token::with_policy(HoldPolicy {
+            max_operations: None,
+            max_hold_millis: Some(0),
+        });
+        token.avaliable();
+
+        assert!(!token.can_serve());
+    }
+
+    #[test]
+    fn test07_new_visit_resets_the_operations() {
+        let mut token = Token::with_policy(HoldPolicy {
+            max_operations: Some(1),
+            max_hold_millis: None,
+        });
+        token.avaliable();
+        token.register_operation();
+        token.not_avaliable();
+        token.avaliable();
+
+        assert!(token.can_serve());
+    }
+
+    #[test]
+    fn test08_token_not_avaliable_can_not_serve() {
+        let token = Token::new();
+
+        assert!(!token.can_serve());
+    }
+
+    #[test]
+    fn test09_sharded_token_settles_owned_shards_only() {
+        let mut token = Token::with_shards(HoldPolicy::default(), 1, 4);
+        token.avaliable();
+        let locks = token.locks_mut().unwrap();
+        locks.wait_for(5);
+        locks.update_table("").unwrap();
+
+        assert!(token.can_settle(5));
+        assert!(!token.can_settle(6));
+    }
 }
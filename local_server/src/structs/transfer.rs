@@ -0,0 +1,106 @@
+use std::fmt;
+
+use super::points::Points;
+
+/// Side of a transfer: the debit blocks and then consumes the points of the
+/// source account, the credit adds them to the destination account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegRole {
+    Debit,
+    Credit,
+}
+
+impl fmt::Display for LegRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LegRole::Debit => write!(f, "DEBIT"),
+            LegRole::Credit => write!(f, "CREDIT"),
+        }
+    }
+}
+
+/// Part of a transfer executed by the server that owns the account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferLeg {
+    pub role: LegRole,
+    pub customer_id: u32,
+    pub points: Points,
+}
+
+impl TransferLeg {
+    pub fn debit(customer_id: u32, points: Points) -> Self {
+        Self {
+            role: LegRole::Debit,
+            customer_id,
+            points,
+        }
+    }
+
+    pub fn credit(customer_id: u32, points: Points) -> Self {
+        Self {
+            role: LegRole::Credit,
+            customer_id,
+            points,
+        }
+    }
+
+    /// `PREPARE,<transaction_id>,<DEBIT|CREDIT>,<customer_id>,<points>`
+    pub fn prepare_message(&self, transaction_id: &str) -> String {
+        format!(
+            "PREPARE,{},{},{},{}\n",
+            transaction_id, self.role, self.customer_id, self.points
+        )
+    }
+
+    /// Parses the fields of a PREPARE message split by commas.
+    pub fn parse_prepare(parts: &[&str]) -> Result<(String, TransferLeg), String> {
+        if parts.len() != 5 || parts[0] != "PREPARE" {
+            return Err(format!("Invalid PREPARE message {:?}", parts));
+        }
+        let role = match parts[2] {
+            "DEBIT" => LegRole::Debit,
+            "CREDIT" => LegRole::Credit,
+            other => return Err(format!("Invalid transfer role {}", other)),
+        };
+        let leg = TransferLeg {
+            role,
+            customer_id: parts[3].parse::<u32>().map_err(|e| e.to_string())?,
+            points: parts[4].parse::<Points>().map_err(|e| e.to_string())?,
+        };
+        Ok((parts[1].to_string(), leg))
+    }
+}
+
+#[cfg(test)]
+mod transfer_test {
+    use super::*;
+
+    #[test]
+    fn test01_prepare_message_is_parseable() {
+        let leg = TransferLeg::debit(5, Points::new(20));
+        let message = leg.prepare_message("1-42");
+        let parts: Vec<&str> = message.split(',').map(|s| s.trim()).collect();
+
+        assert_eq!(message, "PREPARE,1-42,DEBIT,5,20\n");
+        assert_eq!(
+            TransferLeg::parse_prepare(&parts),
+            Ok(("1-42".to_string(), leg))
+        );
+    }
+
+    #[test]
+    fn test02_parse_credit_leg() {
+        let (transaction_id, leg) =
+            TransferLeg::parse_prepare(&["PREPARE", "2-7", "CREDIT", "9", "3"]).unwrap();
+
+        assert_eq!(transaction_id, "2-7");
+        assert_eq!(leg, TransferLeg::credit(9, Points::new(3)));
+    }
+
+    #[test]
+    fn test03_parse_malformed_prepare_fails() {
+        assert!(TransferLeg::parse_prepare(&["PREPARE", "2-7", "MOVE", "9", "3"]).is_err());
+        assert!(TransferLeg::parse_prepare(&["PREPARE", "2-7", "DEBIT", "9"]).is_err());
+        assert!(TransferLeg::parse_prepare(&["COMMIT", "2-7", "DEBIT", "9", "3"]).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+
+use crate::structs::catalog::Catalog;
+use crate::structs::controller_auth::Operator;
+use crate::structs::hash_ring::ShardingConfig;
+use crate::structs::ledger::DEFAULT_LEDGER_SIZE;
+use crate::structs::promotions::Promotions;
+use crate::structs::rate_limits::RateLimits;
+use crate::structs::registration::AllowedCoffeeMaker;
+use crate::structs::shard_locks::ConcurrencyMode;
+use crate::structs::tiers::TierPolicy;
+use crate::structs::timing::Timing;
+use crate::structs::token::HoldPolicy;
+use crate::utils::tls::TlsSettings;
+
+const DEFAULT_GOSSIP_INTERVAL_MILLIS: u64 = 1000;
+const DEFAULT_COFFEE_MAKER_IDLE_TIMEOUT_MILLIS: u64 = 30000;
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MILLIS: u64 = 10000;
+const DEFAULT_SERVERS: u8 = 3;
+
+/// Runtime configuration of a local server, read from the optional JSON
+/// file given as second argument. Every field has a default so an empty
+/// file (or no file at all) keeps the original behaviour.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub history_file: Option<String>,
+    pub token_hold: HoldPolicy,
+    pub gossip_interval_millis: Option<u64>,
+    pub concurrency: ConcurrencyMode,
+    pub servers: Option<u8>,
+    pub account_sharding: Option<ShardingConfig>,
+    /// Days after which earned points expire, never if absent.
+    pub points_expiration_days: Option<u32>,
+    pub tiers: TierPolicy,
+    pub promotions: Promotions,
+    pub catalog: Catalog,
+    /// Accounts must be opened with OPEN before earning points. Otherwise
+    /// the first ADD opens them.
+    pub explicit_account_opening: bool,
+    /// Entries of the ledger kept per account.
+    pub ledger_size: Option<usize>,
+    pub rate_limits: RateLimits,
+    /// Mutual TLS between every peer of the ring, plaintext if absent.
+    pub tls: Option<TlsSettings>,
+    /// Operators allowed to open controller sessions. Sessions are not
+    /// authenticated if there are none.
+    pub operators: Vec<Operator>,
+    /// File where every controller command is recorded.
+    pub audit_file: Option<String>,
+    /// Key shared by the servers to sign the messages they send each
+    /// other. Messages are not signed if absent.
+    pub cluster_key: Option<String>,
+    /// Coffee makers allowed to connect. Any can if empty.
+    pub coffee_makers: Vec<AllowedCoffeeMaker>,
+    /// Time a coffee maker session may go without any message, PING
+    /// included, before it is closed.
+    pub coffee_maker_idle_timeout_millis: Option<u64>,
+    /// Time a stopping server waits for the REQs in flight to be settled
+    /// before leaving the ring.
+    pub shutdown_drain_timeout_millis: Option<u64>,
+    pub timing: Timing,
+}
+
+impl ServerConfig {
+    pub fn from_file(path: &str) -> Result<ServerConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let config = serde_json::from_str::<ServerConfig>(&contents).map_err(|e| e.to_string())?;
+        config.promotions.validate()?;
+        config.catalog.validate()?;
+        config.timing.validate()?;
+        Ok(config)
+    }
+
+    /// Number of servers in the ring, numbered from 1.
+    pub fn servers(&self) -> u8 {
+        self.servers.unwrap_or(DEFAULT_SERVERS)
+    }
+
+    /// Fingerprint of the promotions and catalog, which must be the same on
+    /// every server. It is sent in the server handshake.
+    pub fn rules_fingerprint(&self) -> u64 {
+        self.promotions.fingerprint() ^ self.catalog.fingerprint().rotate_left(32)
+    }
+
+    pub fn ledger_size(&self) -> usize {
+        self.ledger_size.unwrap_or(DEFAULT_LEDGER_SIZE)
+    }
+
+    /// How often earned points are gossiped to the right neighbor, or
+    /// changed accounts replicated when sharding is enabled.
+    pub fn gossip_interval(&self) -> Duration {
+        Duration::from_millis(
+            self.gossip_interval_millis
+                .unwrap_or(DEFAULT_GOSSIP_INTERVAL_MILLIS),
+        )
+    }
+
+    pub fn coffee_maker_idle_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.coffee_maker_idle_timeout_millis
+                .unwrap_or(DEFAULT_COFFEE_MAKER_IDLE_TIMEOUT_MILLIS),
+        )
+    }
+
+    pub fn shutdown_drain_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.shutdown_drain_timeout_millis
+                .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MILLIS),
+        )
+    }
+}
+
+/// Configuration of the controller, read from the optional JSON file given
+/// as second argument.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ControllerConfig {
+    /// Credentials answering the challenge of servers that require them.
+    pub operator: Option<Operator>,
+    pub tls: Option<TlsSettings>,
+}
+
+impl ControllerConfig {
+    pub fn from_file(path: &str) -> Result<ControllerConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str::<ControllerConfig>(&contents).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::{ControllerConfig, ServerConfig};
+    use crate::structs::points::Points;
+    use crate::structs::shard_locks::ConcurrencyMode;
+    use crate::structs::token::HoldPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test01_empty_config_uses_defaults() {
+        let config = ServerConfig::from_file("resources/test/empty_config.json").unwrap();
+
+        assert!(config.history_file.is_none());
+        assert_eq!(config.token_hold, HoldPolicy::default());
+        assert_eq!(config.gossip_interval(), Duration::from_millis(1000));
+        assert_eq!(config.concurrency, ConcurrencyMode::GlobalToken);
+        assert_eq!(config.servers(), 3);
+        assert!(config.account_sharding.is_none());
+        assert!(config.points_expiration_days.is_none());
+    }
+
+    #[test]
+    fn test02_history_file_is_read() {
+        let config = ServerConfig::from_file("resources/test/history_config.json").unwrap();
+
+        assert_eq!(config.history_file, Some("history-server.log".to_string()));
+    }
+
+    #[test]
+    fn test03_token_hold_policy_is_read() {
+        let config = ServerConfig::from_file("resources/test/token_hold_config.json").unwrap();
+
+        assert_eq!(config.token_hold.max_operations, Some(5));
+        assert_eq!(config.token_hold.max_hold_millis, Some(2000));
+    }
+
+    #[test]
+    fn test04_gossip_interval_is_read() {
+        let config = ServerConfig::from_file("resources/test/gossip_config.json").unwrap();
+
+        assert_eq!(config.gossip_interval(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test05_shard_locks_mode_is_read() {
+        let config = ServerConfig::from_file("resources/test/shard_locks_config.json").unwrap();
+
+        assert_eq!(
+            config.concurrency,
+            ConcurrencyMode::ShardLocks { shards: 16 }
+        );
+    }
+
+    #[test]
+    fn test06_account_sharding_is_read() {
+        let config = ServerConfig::from_file("resources/test/sharding_config.json").unwrap();
+
+        assert_eq!(config.servers(), 5);
+        assert_eq!(config.account_sharding.map(|s| s.virtual_nodes), Some(32));
+    }
+
+    #[test]
+    fn test07_points_expiration_is_read() {
+        let config = ServerConfig::from_file("resources/test/expiration_config.json").unwrap();
+
+        assert_eq!(config.points_expiration_days, Some(90));
+    }
+
+    #[test]
+    fn test08_tiers_are_read() {
+        let config = ServerConfig::from_file("resources/test/tiers_config.json").unwrap();
+
+        assert_eq!(
+            config.tiers.tier_for(Points::new(99)).unwrap().name,
+            "bronze"
+        );
+        let gold = config.tiers.tier_for(Points::new(1000)).unwrap();
+        assert_eq!(gold.name, "gold");
+        assert_eq!(gold.multiplier_percent, 200);
+    }
+
+    #[test]
+    fn test09_promotions_are_read_and_validated() {
+        let config = ServerConfig::from_file("resources/test/promotions_config.json").unwrap();
+
+        assert_ne!(config.promotions, Default::default());
+        assert!(ServerConfig::from_file("resources/test/invalid_promotions_config.json").is_err());
+    }
+
+    #[test]
+    fn test10_catalog_is_read() {
+        let config = ServerConfig::from_file("resources/test/catalog_config.json").unwrap();
+
+        let latte = config.catalog.product("latte").unwrap();
+        assert_eq!((latte.price, latte.points), (45, 15));
+        assert_ne!(
+            config.rules_fingerprint(),
+            ServerConfig::default().rules_fingerprint()
+        );
+    }
+
+    #[test]
+    fn test11_non_existing_file_fails() {
+        assert!(ServerConfig::from_file("resources/test/non_existing.json").is_err());
+    }
+
+    #[test]
+    fn test12_explicit_account_opening_is_read() {
+        let config = ServerConfig::from_file("resources/test/account_opening_config.json").unwrap();
+
+        assert!(config.explicit_account_opening);
+        assert!(!ServerConfig::default().explicit_account_opening);
+    }
+
+    #[test]
+    fn test13_ledger_size_is_read() {
+        let config = ServerConfig::from_file("resources/test/ledger_config.json").unwrap();
+
+        assert_eq!(config.ledger_size(), 5);
+        assert_eq!(ServerConfig::default().ledger_size(), 20);
+    }
+
+    #[test]
+    fn test14_rate_limits_are_read() {
+        let config = ServerConfig::from_file("resources/test/rate_limits_config.json").unwrap();
+
+        assert_eq!(config.rate_limits.max_redemptions_per_minute, Some(3));
+        assert_eq!(config.rate_limits.max_points_earned_per_hour, Some(500));
+    }
+
+    #[test]
+    fn test15_tls_settings_are_read() {
+        let config = ServerConfig::from_file("resources/test/tls_config.json").unwrap();
+        let tls = config.tls.unwrap();
+
+        assert_eq!(tls.cert_file, "certs/server-1.pem");
+        assert_eq!(tls.key_file, "certs/server-1.key");
+        assert_eq!(tls.ca_file, "certs/ca.pem");
+        assert!(ServerConfig::default().tls.is_none());
+    }
+
+    #[test]
+    fn test16_operators_and_audit_file_are_read() {
+        let config = ServerConfig::from_file("resources/test/operators_config.json").unwrap();
+
+        assert_eq!(config.operators.len(), 1);
+        assert_eq!(config.operators[0].name, "alice");
+        assert_eq!(config.audit_file, Some("audit-server.log".to_string()));
+        assert!(ServerConfig::default().operators.is_empty());
+    }
+
+    #[test]
+    fn test17_controller_config_is_read() {
+        let config = ControllerConfig::from_file("resources/test/controller_config.json").unwrap();
+
+        assert_eq!(config.operator.unwrap().name, "alice");
+        assert_eq!(config.tls.unwrap().cert_file, "certs/controller.pem");
+    }
+
+    #[test]
+    fn test18_cluster_key_is_read() {
+        let config = ServerConfig::from_file("resources/test/cluster_key_config.json").unwrap();
+
+        assert_eq!(config.cluster_key, Some("ring-secret".to_string()));
+        assert!(ServerConfig::default().cluster_key.is_none());
+    }
+
+    #[test]
+    fn test19_coffee_maker_allowlist_is_read() {
+        let config = ServerConfig::from_file("resources/test/coffee_makers_config.json").unwrap();
+
+        assert_eq!(config.coffee_makers.len(), 2);
+        assert_eq!(config.coffee_makers[0].id, "coffee-1");
+        assert_eq!(
+            config.coffee_makers[0].location,
+            Some("palermo".to_string())
+        );
+        assert_eq!(config.coffee_makers[0].firmware, vec!["0.1.0".to_string()]);
+        assert_eq!(config.coffee_makers[1].location, None);
+        assert!(config.coffee_makers[1].firmware.is_empty());
+    }
+
+    #[test]
+    fn test20_coffee_maker_idle_timeout_is_read() {
+        let config = ServerConfig::from_file("resources/test/idle_timeout_config.json").unwrap();
+
+        assert_eq!(
+            config.coffee_maker_idle_timeout(),
+            Duration::from_millis(5000)
+        );
+        assert_eq!(
+            ServerConfig::default().coffee_maker_idle_timeout(),
+            Duration::from_millis(30000)
+        );
+    }
+
+    #[test]
+    fn test21_timing_is_read() {
+        let config = ServerConfig::from_file("resources/test/timing_config.json").unwrap();
+
+        assert_eq!(config.timing.idle_token_delay(), Duration::from_millis(250));
+        assert_eq!(config.timing.token_timeout(), Duration::from_secs(5));
+        assert_eq!(config.timing.reconnect_attempts, 3);
+        assert_eq!(config.timing.reconnect_jitter_percent, 20);
+        assert_eq!(config.timing.failure_detector.threshold, 10.0);
+        assert_eq!(config.timing.failure_detector.window, 100);
+        assert_eq!(
+            ServerConfig::default().timing.idle_token_delay(),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test22_shutdown_drain_timeout_is_read() {
+        let config = ServerConfig::from_file("resources/test/shutdown_config.json").unwrap();
+
+        assert_eq!(config.shutdown_drain_timeout(), Duration::from_millis(2500));
+        assert_eq!(
+            ServerConfig::default().shutdown_drain_timeout(),
+            Duration::from_secs(10)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+
+use crate::structs::hash_ring::HashRing;
+use crate::structs::registration::Registration;
+use crate::utils::tls::{self, Stream};
+
+/// Where an operation on an account was served.
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Local,
+    Remote(String),
+    Unavailable,
+}
+
+/// Proxies the operations of a coffee maker session on accounts owned by
+/// other servers. One coffee maker style session is kept per owner, so the
+/// owner matches a later SUBS or UNBL with the REQ of the same session.
+pub struct Forwarder {
+    id: u8,
+    ring: Arc<HashRing>,
+    sessions: HashMap<u8, (BufReader<ReadHalf<Stream>>, WriteHalf<Stream>)>,
+    /// Identity of the proxied coffee maker, sent in the handshake with
+    /// owners.
+    registration: Option<Registration>,
+}
+
+impl Forwarder {
+    pub fn new(id: u8, ring: Arc<HashRing>, registration: Option<Registration>) -> Self {
+        Self {
+            id,
+            ring,
+            sessions: HashMap::new(),
+            registration,
+        }
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Sends the line to the primary of the account, or to its replica if
+    /// the primary does not answer. `Route::Local` means this server is the
+    /// first owner alive and must serve the operation itself.
+    pub async fn route(&mut self, line: &str, customer_id: u32) -> Route {
+        for owner in self.ring.owners(customer_id) {
+            if owner == self.id {
+                return Route::Local;
+            }
+            match self.send(owner, line).await {
+                Ok(response) => return Route::Remote(response),
+                Err(e) => {
+                    warn!("Server {} did not answer: {}", owner, e);
+                    self.sessions.remove(&owner);
+                }
+            }
+        }
+        Route::Unavailable
+    }
+
+    pub async fn close(&mut self) {
+        for (owner, (_, mut writer)) in self.sessions.drain() {
+            debug!("Closing forwarded session with server {}", owner);
+            let _ = writer.write_all(b"BYE\n").await;
+        }
+    }
+
+    async fn send(&mut self, owner: u8, line: &str) -> Result<String, String> {
+        let (reader, writer) = match self.sessions.entry(owner) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                let stream = tls::connect(owner).await.map_err(|e| e.to_string())?;
+                let (reader, mut writer) = split(stream);
+                let handshake = match &self.registration {
+                    Some(registration) => registration.handshake(),
+                    None => "CH\n".to_string(),
+                };
+                writer
+                    .write_all(handshake.as_bytes())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                v.insert((BufReader::new(reader), writer))
+            }
+        };
+
+        writer
+            .write_all(format!("{}\n", line.trim_end()).as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        match reader.read_line(&mut response).await {
+            Ok(0) => Err("connection closed".to_string()),
+            Ok(_) => Ok(response),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod forwarder_test {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test01_single_server_serves_locally() {
+        let mut forwarder = Forwarder::new(1, Arc::new(HashRing::new(1, 4)), None);
+
+        assert_eq!(forwarder.route("ADD,7,10", 7).await, Route::Local);
+    }
+}
@@ -4,41 +4,68 @@ pub mod handlers_messager {
     use actix::Addr;
     use log::{debug, error, info, warn};
 
+    use std::collections::HashMap;
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+    use crate::structs::account::{today, Account};
+    use crate::structs::account_status::{AccountState, AccountStatus};
+    use crate::structs::audit_log::AuditLog;
+    use crate::structs::controller_auth::{ControllerAuth, ANONYMOUS};
+    use crate::structs::failure_detector::FailureDetector;
+    use crate::structs::g_counter::GCounter;
+    use crate::structs::hash_ring::HashRing;
+    use crate::structs::ledger::Ledger;
     use crate::structs::messages::{
-        AddPoints, BlockPoints, SubtractPoints, SyncAccount, SyncNextServer, UnblockPoints,
+        AddPoints, AdjustPoints, BlockPoints, ChangeStatus, CheckCredit, CoffeeOrder, CreditPoints,
+        ExpirePoints, FlaggedAccounts, GetCatalog, GetLedger, GlobalBlockedPoints, MergeEarned,
+        RegisterCoffeeMaker, StopAccepting, SubtractPoints, SyncAccount, SyncNextServer,
+        UnblockPoints,
     };
-    use std::thread;
+    use crate::structs::points::{Points, PointsError};
+    use crate::structs::registration::Registration;
+    use crate::structs::server_error::ServerError;
+    use crate::structs::shard_locks::ShardFilter;
+    use crate::structs::timing::Timing;
+    use crate::structs::transfer::{LegRole, TransferLeg};
+    use crate::utils::forwarder::{Forwarder, Route};
+    use crate::utils::protocol::{self, ProtocolError};
+    use crate::utils::signer;
+    use crate::utils::tls::{self, Stream};
     use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
-    use tokio::net::TcpStream;
     use tokio::sync::mpsc::Sender;
     use tokio::sync::{Mutex, Notify};
     use tokio::time;
 
+    const OK_RESPONSE: &str = "OK\n";
+    const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn handle_controller_connection(
-        mut reader: BufReader<io::ReadHalf<TcpStream>>,
-        mut w: io::WriteHalf<TcpStream>,
+        mut reader: BufReader<io::ReadHalf<Stream>>,
+        mut w: io::WriteHalf<Stream>,
+        server_actor_address: Addr<LocalServer>,
         sender: Sender<String>,
         state: Arc<Mutex<bool>>,
         id: u8,
         servers: u8,
+        auth: Arc<ControllerAuth>,
+        audit: Arc<Mutex<AuditLog>>,
     ) {
+        let operator = match authenticate_operator(&mut reader, &mut w, &auth, &audit).await {
+            Some(operator) => operator,
+            None => return,
+        };
         debug!("Reading from neighbor");
         loop {
             let mut line: String = String::new();
             match reader.read_line(&mut line).await {
                 Ok(u) => {
                     if u > 0 {
-                        let response = "ACK\n";
-                        w.write_all(response.as_bytes())
-                            .await
-                            .expect("Error writing tcp");
                         let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
                         let sender_copy = sender.clone();
                         debug!("Read from neigbor {:?}", parts);
-                        match parts[0] {
+                        let response = match parts[0] {
                             "KILL" => {
                                 let mut s = state.lock().await;
                                 *s = false;
@@ -48,6 +75,7 @@ pub mod handlers_messager {
                                     .await
                                     .expect("could not send recovery message");
                                 warn!("KILL received - Now this server is offline");
+                                "ACK\n".to_string()
                             }
                             "UP" => {
                                 let mut s = state.lock().await;
@@ -59,12 +87,64 @@ pub mod handlers_messager {
                                     .send(message)
                                     .await
                                     .expect("could not send recovery message");
+                                "ACK\n".to_string()
+                            }
+                            "ADJ" => {
+                                let fields = protocol::field::<u32>(&parts, 1, "customer_id")
+                                    .and_then(|customer_id| {
+                                        Ok((customer_id, protocol::field(&parts, 2, "adjustment")?))
+                                    });
+                                match fields {
+                                    Ok((customer_id, points)) => {
+                                        handle_adjust_message(
+                                            server_actor_address.clone(),
+                                            customer_id,
+                                            points,
+                                        )
+                                        .await
+                                    }
+                                    Err(e) => protocol::reject("CTRL", &e),
+                                }
+                            }
+                            "HIST" => {
+                                handle_history_message(server_actor_address.clone(), &parts, "CTRL")
+                                    .await
+                            }
+                            "FLAGS" => handle_flags_message(server_actor_address.clone()).await,
+                            "ERRORS" => handle_errors_message(),
+                            "OPEN" | "FREEZE" | "CLOSE" => {
+                                let fields =
+                                    protocol::field::<AccountState>(&parts, 0, "operation")
+                                        .and_then(|state| {
+                                            Ok((protocol::field(&parts, 1, "customer_id")?, state))
+                                        });
+                                match fields {
+                                    Ok((customer_id, state)) => {
+                                        handle_status_message(
+                                            server_actor_address.clone(),
+                                            customer_id,
+                                            state,
+                                        )
+                                        .await
+                                    }
+                                    Err(e) => protocol::reject("CTRL", &e),
+                                }
                             }
-                            _ => {
-                                error!("Unkown");
+                            command => {
+                                let error = ProtocolError::UnknownMessage(command.to_string());
+                                let response = protocol::reject("CTRL", &error);
+                                audit.lock().await.record(&operator, line.trim(), "UNKNOWN");
+                                let _ = w.write_all(response.as_bytes()).await;
                                 break;
                             }
-                        }
+                        };
+                        audit
+                            .lock()
+                            .await
+                            .record(&operator, line.trim(), response.trim());
+                        w.write_all(response.as_bytes())
+                            .await
+                            .expect("Error writing tcp");
                         line.clear();
                     }
                 }
@@ -76,23 +156,86 @@ pub mod handlers_messager {
         }
     }
 
+    /// Sends `CHALLENGE,<nonce>` and expects `AUTH,<operator>,<answer>`,
+    /// answering `ACK` or `NOT ACK,UNAUTHORIZED`. Returns the operator, or
+    /// None if the session must be closed. Without operators configured
+    /// there is no challenge.
+    async fn authenticate_operator(
+        reader: &mut BufReader<io::ReadHalf<Stream>>,
+        w: &mut io::WriteHalf<Stream>,
+        auth: &ControllerAuth,
+        audit: &Mutex<AuditLog>,
+    ) -> Option<String> {
+        if !auth.is_required() {
+            return Some(ANONYMOUS.to_string());
+        }
+        let challenge = match ControllerAuth::challenge() {
+            Ok(challenge) => challenge,
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        };
+        let message = format!("CHALLENGE,{}\n", challenge);
+        if w.write_all(message.as_bytes()).await.is_err() {
+            return None;
+        }
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return None;
+        }
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        let (operator, result) = match parts.as_slice() {
+            ["AUTH", operator, answer] => (
+                operator.to_string(),
+                auth.verify(operator, &challenge, answer),
+            ),
+            _ => (
+                ANONYMOUS.to_string(),
+                Err(ServerError::Unauthorized(ANONYMOUS.to_string())),
+            ),
+        };
+        let response = ack_response(result.clone());
+        audit
+            .lock()
+            .await
+            .record(&operator, "AUTH", response.trim());
+        let _ = w.write_all(response.as_bytes()).await;
+        match result {
+            Ok(_) => Some(operator),
+            Err(e) => {
+                warn!("Controller session rejected: {}", e);
+                None
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn handle_server_connection(
-        mut reader: BufReader<io::ReadHalf<TcpStream>>,
-        mut w: io::WriteHalf<TcpStream>,
+        mut reader: BufReader<io::ReadHalf<Stream>>,
+        mut w: io::WriteHalf<Stream>,
         token_copy: Arc<Mutex<Token>>,
         notify_copy: Arc<Notify>,
         connections: Arc<Mutex<i32>>,
         server_actor_address: Addr<LocalServer>,
         sender: Sender<String>,
         state: Arc<Mutex<bool>>,
+        timing: Timing,
     ) {
         debug!("Reading from neighbor");
         let mut cont = 0;
+        let mut detector = FailureDetector::new(
+            timing.failure_detector,
+            timing.token_timeout(),
+            Instant::now(),
+        );
+        // Kept across timeouts, which can interrupt a line half read.
+        let mut buf = Vec::new();
         loop {
-            // let mut line: String = String::new();
-            let mut buf = Vec::new();
-            let timeout =
-                time::timeout(Duration::from_secs(20), reader.read_until(b'\n', &mut buf));
+            let timeout = time::timeout(
+                detector.time_to_suspicion(Instant::now()),
+                reader.read_until(b'\n', &mut buf),
+            );
             match timeout.await {
                 Ok(result) => match result {
                     Ok(0) => {
@@ -106,7 +249,7 @@ pub mod handlers_messager {
                                 let s = state.lock().await;
                                 debug!("EL LOCK LO TIENE EL SERVER");
 
-                                if matches!(*s, false) {
+                                if !*s {
                                     alive = false
                                 }
                                 debug!("Reading mutex");
@@ -114,12 +257,37 @@ pub mod handlers_messager {
                             debug!("alive is {:?}", alive);
                             if alive {
                                 debug!("Send ack");
-                                let mut line = String::from_utf8(buf).unwrap();
+                                let received = std::mem::take(&mut buf);
+                                let line = String::from_utf8_lossy(&received);
+                                let mut line = match signer::verify(&line) {
+                                    Ok(line) => line,
+                                    Err(e) => {
+                                        error!("Rejected message from left neighbor: {}", e);
+                                        w.write_all(b"NOT OK,UNSIGNED\n")
+                                            .await
+                                            .expect("Error writing tcp");
+                                        continue;
+                                    }
+                                };
                                 let token = token_copy.clone();
                                 let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
                                 let server = server_actor_address.clone();
                                 let sender_copy = sender.clone();
                                 debug!("Read from neigbor {:?}", parts);
+                                let now = Instant::now();
+                                if parts[0] == "TOKEN" {
+                                    let phi = detector.phi(now);
+                                    if phi >= timing.failure_detector.threshold / 2.0 {
+                                        warn!("Left neighbor is slow, phi {:.1}", phi);
+                                    }
+                                }
+                                detector.observe(parts[0], now);
+                                if let Err(e) = check_ring_message(&parts) {
+                                    w.write_all(protocol::reject("SH", &e).as_bytes())
+                                        .await
+                                        .expect("Error writing tcp");
+                                    continue;
+                                }
                                 match parts[0] {
                                     "TOKEN" => {
                                         cont += 1;
@@ -135,10 +303,21 @@ pub mod handlers_messager {
                                         }
                                         info!("TOKEN received");
 
-                                        if empty {
-                                            thread::sleep(Duration::from_secs(1));
+                                        if token.lock().await.is_sharded() {
+                                            drop(guard);
+                                            pass_shard_locks(
+                                                &parts,
+                                                token,
+                                                &notify_copy,
+                                                server,
+                                                sender_copy,
+                                            )
+                                            .await;
+                                        } else if empty {
+                                            time::sleep(timing.idle_token_delay()).await;
                                             debug!("No REQ messages next server");
-                                            sync_next(server, sender_copy).await;
+                                            expire_points(&server, None).await;
+                                            sync_next(server, sender_copy, None).await;
                                             debug!("Send token to next server");
                                             sender
                                                 .send(line.clone())
@@ -146,6 +325,7 @@ pub mod handlers_messager {
                                                 .expect("could not send token through channel");
                                         } else {
                                             debug!("Token should be avaliable");
+                                            expire_points(&server, None).await;
                                             let mut t = token.lock().await;
                                             t.avaliable();
                                             info!("Token is avaliable for REQ");
@@ -153,17 +333,63 @@ pub mod handlers_messager {
                                         }
                                     }
                                     "SYNC" => {
+                                        let msg = match sync_account(&parts) {
+                                            Ok(msg) => msg,
+                                            Err(e) => {
+                                                w.write_all(protocol::reject("SH", &e).as_bytes())
+                                                    .await
+                                                    .expect("Error writing tcp");
+                                                continue;
+                                            }
+                                        };
+                                        if let Err(e) = server.send(msg).await {
+                                            error!("Could not apply SYNC {}: {}", parts[1], e);
+                                            let response = format!(
+                                                "NOT OK,{}\n",
+                                                ServerError::Unavailable.code()
+                                            );
+                                            w.write_all(response.as_bytes())
+                                                .await
+                                                .expect("Error writing tcp");
+                                            continue;
+                                        }
                                         cont += 1;
                                         let response = format!("OK,{}\n", cont);
                                         w.write_all(response.as_bytes())
                                             .await
                                             .expect("Error writing tcp");
-                                        let msg = SyncAccount {
-                                            customer_id: parts[1].parse::<u32>().expect(""),
-                                            points: parts[2].parse::<u32>().expect(""),
+                                        info!(
+                                            "SYNC account {} with {} redeemed points",
+                                            parts[1], parts[2]
+                                        );
+                                    }
+                                    "GOSSIP" => {
+                                        let msg = match merge_earned(&parts) {
+                                            Ok(msg) => msg,
+                                            Err(e) => {
+                                                w.write_all(protocol::reject("SH", &e).as_bytes())
+                                                    .await
+                                                    .expect("Error writing tcp");
+                                                continue;
+                                            }
                                         };
-                                        server.send(msg).await.unwrap();
-                                        info!("SYNC account {} with {} points", parts[1], parts[2]);
+                                        if let Err(e) = server.send(msg).await {
+                                            error!("Could not apply GOSSIP {}: {}", parts[1], e);
+                                            let response = format!(
+                                                "NOT OK,{}\n",
+                                                ServerError::Unavailable.code()
+                                            );
+                                            w.write_all(response.as_bytes())
+                                                .await
+                                                .expect("Error writing tcp");
+                                            continue;
+                                        }
+                                        cont += 1;
+                                        let response = format!("OK,{}\n", cont);
+                                        w.write_all(response.as_bytes())
+                                            .await
+                                            .expect("Error writing tcp");
+                                        debug!("GOSSIP account {} earned {}", parts[1], parts[2]);
                                     }
                                     "ELECTION" => {
                                         let response = format!("OK,{}\n", cont);
@@ -175,8 +401,22 @@ pub mod handlers_messager {
                                             .await
                                             .expect("could not send election through channel");
                                     }
-                                    _ => {
-                                        error!("Unkown");
+                                    "LEAVE" => {
+                                        let response = format!("OK,{}\n", cont);
+                                        w.write_all(response.as_bytes())
+                                            .await
+                                            .expect("Error writing tcp");
+                                        info!("Server {} is leaving the ring", parts[1]);
+                                        if sender.send(line.clone()).await.is_err() {
+                                            warn!("Ring is closed, LEAVE not forwarded");
+                                        }
+                                    }
+                                    message => {
+                                        let error =
+                                            ProtocolError::UnknownMessage(message.to_string());
+                                        let _ = w
+                                            .write_all(protocol::reject("SH", &error).as_bytes())
+                                            .await;
                                         break;
                                     }
                                 }
@@ -192,7 +432,21 @@ pub mod handlers_messager {
                     }
                 },
                 Err(_) => {
-                    error!("Timeout reached! Server with token is down.");
+                    let now = Instant::now();
+                    // Nothing is expected from the left neighbor while the
+                    // token is here.
+                    if token_copy.lock().await.is_avaliable() {
+                        detector.reset(now);
+                        continue;
+                    }
+                    if !detector.is_suspected(now) {
+                        continue;
+                    }
+                    error!(
+                        "Left neighbor suspected with phi {:.1}! Server with token is down.",
+                        detector.phi(now)
+                    );
+                    detector.reset(now);
                     let msg = String::from("ELECTION, 0");
                     sender
                         .send(msg)
@@ -203,16 +457,40 @@ pub mod handlers_messager {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn handle_coffe_connection(
-        mut reader: BufReader<io::ReadHalf<TcpStream>>,
-        mut w: io::WriteHalf<TcpStream>,
+        mut reader: BufReader<io::ReadHalf<Stream>>,
+        mut w: io::WriteHalf<Stream>,
         token_copy: Arc<Mutex<Token>>,
         notify_copy: Arc<Notify>,
         connections: Arc<Mutex<i32>>,
         server_actor_address: Addr<LocalServer>,
         sender: Sender<String>,
+        id: u8,
+        ring: Option<Arc<HashRing>>,
+        registration: Option<Registration>,
+        idle_timeout: Duration,
     ) {
+        let registered = server_actor_address
+            .send(RegisterCoffeeMaker {
+                registration: registration.clone(),
+            })
+            .await
+            .unwrap_or(Err(ServerError::Unavailable));
+        if let Err(e) = registered {
+            let _ = w.write_all(ack_response(Err(e)).as_bytes()).await;
+            return;
+        }
+        let coffee_maker = registration.as_ref().map(|r| r.machine_id.clone());
+        let machine = coffee_maker.clone().unwrap_or("-".to_string());
         let mut last_operation: Option<String> = None;
+        // Points blocked by the last REQ once promotions were applied, which
+        // is what its SUBS or UNBL settles.
+        let mut reserved: Option<Points> = None;
+        // Account and points of a REQ still waiting for its SUBS or UNBL,
+        // unblocked if the session dies before settling it.
+        let mut in_flight: Option<(u32, Points)> = None;
+        let mut forwarder = ring.map(|ring| Forwarder::new(id, ring, registration));
         debug!("waiting for messages from coffee");
         loop {
             let token = token_copy.clone();
@@ -221,333 +499,1801 @@ pub mod handlers_messager {
             let sender_copy = sender.clone();
             let mut line = String::new();
             debug!("ABOUT TO WAIT READING");
-            match reader.read_line(&mut line).await {
-                Ok(u) => {
+            match time::timeout(idle_timeout, reader.read_line(&mut line)).await {
+                Err(_) => {
+                    warn!(
+                        "Coffee maker {} idle for {:?}, closing its session",
+                        machine, idle_timeout
+                    );
+                    break;
+                }
+                Ok(Ok(u)) => {
                     if u > 0 {
                         let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
-                        let response = match parts[0] {
-                            "ADD" => {
-                                let customer_id = parts[1]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-                                let points = parts[2]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-                                let res = handle_add_message(server, customer_id, points).await;
-                                res
-                            }
-                            "REQ" => {
-                                {
-                                    let mut c = connections.lock().await;
-                                    *c += 1;
-                                }
-
-                                let customer_id = parts[1]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-                                let points = parts[2]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-
-                                let res =
-                                    handle_req_message(server, notify, customer_id, points).await;
-                                last_operation = Some(res.clone());
-                                debug!("last_operation: {:?}", last_operation);
-                                res
-                            }
-                            "SUBS" => {
-                                let customer_id = parts[1]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-                                let points = parts[2]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-
-                                let res = handle_subs_message(
-                                    server,
-                                    sender_copy,
-                                    token,
-                                    last_operation.clone(),
-                                    customer_id,
-                                    points,
-                                )
-                                .await;
-                                {
-                                    let mut c = connections.lock().await;
-                                    *c -= 1;
+                        info!("Coffee maker {} sent {:?}", machine, line.trim());
+                        if let Some(forwarder) = forwarder.as_mut() {
+                            if let Some(response) =
+                                forward_operation(forwarder, &parts, &line).await
+                            {
+                                info!("Writting forwarded response {:?}", response);
+                                if w.write_all(response.as_bytes()).await.is_err() {
+                                    error!("Could not write to coffee maker {}", machine);
+                                    break;
                                 }
-                                res
+                                continue;
                             }
-                            "UNBL" => {
-                                let customer_id = parts[1]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-                                let points = parts[2]
-                                    .parse::<u32>()
-                                    .expect("Could not parse customer_id");
-
-                                let res = handle_unblock_message(
-                                    server,
-                                    sender_copy,
-                                    token,
-                                    last_operation.clone(),
-                                    customer_id,
-                                    points,
-                                )
-                                .await;
-                                {
-                                    let mut c = connections.lock().await;
-                                    *c -= 1;
+                        }
+                        let mut close = false;
+                        let response = match parts[0] {
+                            "ADD" => match account_and_points(&parts) {
+                                Ok((customer_id, points)) => {
+                                    handle_add_message(
+                                        server,
+                                        customer_id,
+                                        points,
+                                        coffee_order(&parts, &coffee_maker),
+                                    )
+                                    .await
                                 }
+                                Err(e) => protocol::reject("CH", &e),
+                            },
+                            "REQ" => match account_and_points(&parts) {
+                                Ok((customer_id, points)) => {
+                                    {
+                                        let mut c = connections.lock().await;
+                                        *c += 1;
+                                    }
+                                    in_flight = Some((customer_id, points));
 
-                                res
-                            }
-                            "BYE" => {
-                                let mut send_token = false;
+                                    let result = handle_req_message(
+                                        server,
+                                        token,
+                                        notify,
+                                        customer_id,
+                                        points,
+                                        coffee_order(&parts, &coffee_maker),
+                                    )
+                                    .await;
+                                    reserved = result.as_ref().ok().copied();
+                                    let res = match result {
+                                        Ok(_) => OK_RESPONSE.to_string(),
+                                        Err(e) => format!("NOT OK,{}\n", e.code()),
+                                    };
+                                    last_operation = Some(res.clone());
+                                    debug!("last_operation: {:?}", last_operation);
+                                    res
+                                }
+                                Err(e) => protocol::reject("CH", &e),
+                            },
+                            "SUBS" => match account_and_points(&parts) {
+                                Ok((customer_id, points)) => {
+                                    let points = reserved.take().unwrap_or(points);
 
-                                {
-                                    let t = token.lock().await;
-                                    if t.is_avaliable() {
-                                        send_token = true;
+                                    in_flight = None;
+                                    let res = ack_response(
+                                        handle_subs_message(
+                                            server,
+                                            sender_copy,
+                                            token,
+                                            &last_operation,
+                                            customer_id,
+                                            points,
+                                            coffee_maker.clone(),
+                                        )
+                                        .await,
+                                    );
+                                    {
+                                        let mut c = connections.lock().await;
+                                        *c -= 1;
                                     }
+                                    res
                                 }
-                                {
-                                    let c = connections.lock().await;
-                                    if *c > 0 {
-                                        send_token = false;
+                                Err(e) => protocol::reject("CH", &e),
+                            },
+                            "UNBL" => match account_and_points(&parts) {
+                                Ok((customer_id, points)) => {
+                                    let points = reserved.take().unwrap_or(points);
+
+                                    in_flight = None;
+                                    let res = ack_response(
+                                        handle_unblock_message(
+                                            server,
+                                            sender_copy,
+                                            token,
+                                            &last_operation,
+                                            customer_id,
+                                            points,
+                                            coffee_maker.clone(),
+                                        )
+                                        .await,
+                                    );
+                                    {
+                                        let mut c = connections.lock().await;
+                                        *c -= 1;
                                     }
+
+                                    res
                                 }
-                                if send_token {
-                                    sender
-                                        .send("SEND\n".to_string())
-                                        .await
-                                        .expect("failed to send token");
+                                Err(e) => protocol::reject("CH", &e),
+                            },
+                            "TRANSFER" => match transfer_fields(&parts) {
+                                Ok((from, to, points)) => {
+                                    let local = LocalParticipant::new(
+                                        server,
+                                        token,
+                                        notify,
+                                        connections.clone(),
+                                        sender_copy,
+                                    );
+                                    ack_response(
+                                        handle_transfer_message(
+                                            local,
+                                            id,
+                                            forwarder.as_ref().map(|f| f.ring()),
+                                            from,
+                                            to,
+                                            points,
+                                        )
+                                        .await,
+                                    )
                                 }
-                                break;
-                            }
-                            _ => {
-                                error!("Unkown operation");
-                                let res = "UNK".to_string();
-                                res
+                                Err(e) => protocol::reject("CH", &e),
+                            },
+                            "HIST" => handle_history_message(server, &parts, "CH").await,
+                            "CATALOG" => match server.send(GetCatalog {}).await {
+                                Ok(catalog) => format!("CATALOG,{}\n", catalog),
+                                Err(_) => format!("NOT OK,{}\n", ServerError::Unavailable.code()),
+                            },
+                            "PING" => "PONG\n".to_string(),
+                            "BYE" => break,
+                            operation => {
+                                close = true;
+                                let error = ProtocolError::UnknownMessage(operation.to_string());
+                                protocol::reject("CH", &error)
                             }
                         };
                         info!("Writting response {:?}", response);
-                        w.write(response.as_bytes()).await.unwrap();
-                        if response.as_str() == "UNK" {
+                        if w.write_all(response.as_bytes()).await.is_err() {
+                            error!("Could not write to coffee maker {}", machine);
+                            break;
+                        }
+                        if close {
                             break;
                         }
                     } else {
                         break;
                     }
                 }
-                Err(_) => {
+                Ok(Err(_)) => {
                     error!("Error reading coffee connection line");
                     break;
                 }
             };
         }
+        if let Some(forwarder) = forwarder.as_mut() {
+            forwarder.close().await;
+        }
+        if let Some((customer_id, points)) = in_flight {
+            warn!(
+                "Session of coffee maker {} ended with a REQ of account {} in flight, unblocking it",
+                machine, customer_id
+            );
+            if let Err(e) = handle_unblock_message(
+                server_actor_address,
+                sender.clone(),
+                token_copy.clone(),
+                &last_operation,
+                customer_id,
+                reserved.unwrap_or(points),
+                coffee_maker,
+            )
+            .await
+            {
+                debug!("Nothing to unblock for account {}: {}", customer_id, e);
+            }
+            *connections.lock().await -= 1;
+        }
+        // An idle server holding the token must pass it on once its last
+        // coffee maker is gone.
+        let send_token = token_copy.lock().await.is_avaliable() && *connections.lock().await <= 0;
+        if send_token {
+            sender
+                .send("SEND\n".to_string())
+                .await
+                .expect("failed to send token");
+        }
     }
 
-    async fn handle_add_message(
-        server: Addr<LocalServer>,
-        customer_id: u32,
-        points: u32,
-    ) -> String {
-        info!("ADD received");
-        let msg = AddPoints {
-            customer_id,
-            points,
+    /// Accounts are only served by their owners when sharding is enabled,
+    /// other servers proxy the operation. Returns None when the operation
+    /// must be served here.
+    async fn forward_operation(
+        forwarder: &mut Forwarder,
+        parts: &[&str],
+        line: &str,
+    ) -> Option<String> {
+        if !matches!(parts[0], "ADD" | "REQ" | "SUBS" | "UNBL" | "HIST") {
+            return None;
+        }
+        let customer_id = match protocol::field::<u32>(parts, 1, "customer_id") {
+            Ok(customer_id) => customer_id,
+            Err(e) => return Some(protocol::reject("CH", &e)),
         };
-        let _res = server.send(msg).await.unwrap();
+        match forwarder.route(line, customer_id).await {
+            Route::Local => None,
+            Route::Remote(response) => Some(response),
+            Route::Unavailable => {
+                let error = ServerError::Unavailable;
+                Some(match parts[0] {
+                    "REQ" | "HIST" => format!("NOT OK,{}\n", error.code()),
+                    _ => format!("NOT ACK,{}\n", error.code()),
+                })
+            }
+        }
+    }
 
-        "ACK\n".to_string()
+    /// Receives the accounts replicated by the other owner of each account
+    /// when sharding is enabled.
+    pub async fn handle_replica_connection(
+        mut reader: BufReader<io::ReadHalf<Stream>>,
+        mut w: io::WriteHalf<Stream>,
+        server_actor_address: Addr<LocalServer>,
+    ) {
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line).await {
+                Ok(0) => {
+                    info!("Replica connection closed");
+                    break;
+                }
+                Ok(_) => {
+                    let line = match signer::verify(&line) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            error!("Rejected replication message: {}", e);
+                            break;
+                        }
+                    };
+                    let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+                    if parts[0] != "SYNC" {
+                        let error = ProtocolError::UnknownMessage(parts[0].to_string());
+                        let _ = w
+                            .write_all(protocol::reject("REPL", &error).as_bytes())
+                            .await;
+                        break;
+                    }
+                    let msg = match sync_account(&parts) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            if w.write_all(protocol::reject("REPL", &e).as_bytes())
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    let response = server_actor_address
+                        .send(msg)
+                        .await
+                        .unwrap_or("ERROR".to_string());
+                    w.write_all(format!("{}\n", response).as_bytes())
+                        .await
+                        .expect("Error writing tcp");
+                }
+                Err(_) => {
+                    error!("Could not read from TCP Stream");
+                    break;
+                }
+            }
+        }
     }
 
-    async fn handle_unblock_message(
+    /// Serves the legs of transfers on accounts of this server using the
+    /// same block, subtract and add primitives as the coffee makers.
+    #[derive(Clone)]
+    pub struct LocalParticipant {
         server: Addr<LocalServer>,
-        neighbor: Sender<String>,
         token: Arc<Mutex<Token>>,
-        last_operation: Option<String>,
-        customer_id: u32,
-        points: u32,
-    ) -> String {
-        info!("UNBL received");
-        let response = match last_operation {
-            Some(operation_result) => {
-                if operation_result == "OK" {
-                    let msg = UnblockPoints {
-                        customer_id,
-                        points,
-                    };
-                    match server.send(msg).await {
-                        Ok(blocked_points_left) => match blocked_points_left {
-                            Ok(b) => match b {
-                                b if b == 0 => {
-                                    info!("Last UNBL points substracted");
-                                    let mut t = token.lock().await;
-                                    t.not_avaliable();
-                                    info!("Token is no more avaliable");
-
-                                    sync_next(server, neighbor.clone()).await;
-                                    neighbor
-                                        .send(String::from("SEND\n"))
-                                        .await
-                                        .expect("could not send token from unblock message");
-                                    return "ACK\n".to_string();
-                                }
-                                b if b > 0 => {
-                                    info!("UNBL points substracted");
-                                    return "ACK\n".to_string();
-                                }
-                                _ => "NOT ACK\n".to_string(),
-                            },
-                            Err(_) => "NOT ACK\n".to_string(),
-                        },
-                        Err(_) => "NOT ACK\n".to_string(),
+        notify: Arc<Notify>,
+        connections: Arc<Mutex<i32>>,
+        neighbor: Sender<String>,
+    }
+
+    impl LocalParticipant {
+        pub fn new(
+            server: Addr<LocalServer>,
+            token: Arc<Mutex<Token>>,
+            notify: Arc<Notify>,
+            connections: Arc<Mutex<i32>>,
+            neighbor: Sender<String>,
+        ) -> Self {
+            Self {
+                server,
+                token,
+                notify,
+                connections,
+                neighbor,
+            }
+        }
+
+        /// The debit blocks the points, holding the token (or shard) like an
+        /// in flight REQ until the transfer is decided. The credit checks
+        /// that the account can receive them.
+        async fn prepare(&self, leg: &TransferLeg) -> Result<(), ServerError> {
+            if leg.role == LegRole::Credit {
+                return self
+                    .server
+                    .send(CheckCredit {
+                        customer_id: leg.customer_id,
+                    })
+                    .await
+                    .unwrap_or(Err(ServerError::Unavailable));
+            }
+            *self.connections.lock().await += 1;
+            let result = reserve_points(
+                self.server.clone(),
+                self.token.clone(),
+                self.notify.clone(),
+                leg.customer_id,
+                leg.points,
+                None,
+            )
+            .await
+            .map(|_| ());
+            if result.is_err() {
+                *self.connections.lock().await -= 1;
+                release_exhausted_token(
+                    self.server.clone(),
+                    self.neighbor.clone(),
+                    self.token.clone(),
+                )
+                .await;
+            }
+            result
+        }
+
+        async fn commit(&self, leg: &TransferLeg) -> Result<(), ServerError> {
+            match leg.role {
+                LegRole::Debit => {
+                    let result = handle_subs_message(
+                        self.server.clone(),
+                        self.neighbor.clone(),
+                        self.token.clone(),
+                        &Some(OK_RESPONSE.to_string()),
+                        leg.customer_id,
+                        leg.points,
+                        None,
+                    )
+                    .await;
+                    // A failed debit keeps its reservation until it is aborted.
+                    if result.is_ok() {
+                        *self.connections.lock().await -= 1;
                     }
-                } else {
-                    "NOT ACK\n".to_string()
+                    result
                 }
+                LegRole::Credit => self
+                    .server
+                    .send(CreditPoints {
+                        customer_id: leg.customer_id,
+                        points: leg.points,
+                    })
+                    .await
+                    .unwrap_or(Err(ServerError::Unavailable)),
             }
-            None => {
-                error!("NO operation result = OK");
-                "NOT ACK".to_string()
+        }
+
+        async fn abort(&self, leg: &TransferLeg) -> Result<(), ServerError> {
+            if leg.role == LegRole::Credit {
+                return Ok(());
             }
-        };
-        response
+            let result = handle_unblock_message(
+                self.server.clone(),
+                self.neighbor.clone(),
+                self.token.clone(),
+                &Some(OK_RESPONSE.to_string()),
+                leg.customer_id,
+                leg.points,
+                None,
+            )
+            .await;
+            *self.connections.lock().await -= 1;
+            result
+        }
     }
 
-    async fn handle_subs_message(
-        server: Addr<LocalServer>,
-        neighbor: Sender<String>,
-        token: Arc<Mutex<Token>>,
-        last_operation: Option<String>,
-        customer_id: u32,
-        points: u32,
-    ) -> String {
-        info!("SUBS received");
-        let response = match last_operation {
-            Some(operation) => {
-                if operation == "OK\n" {
-                    let msg = SubtractPoints {
-                        customer_id,
-                        points,
-                    };
-                    match server.send(msg).await {
-                        Ok(blocked_points_left) => match blocked_points_left {
-                            Ok(b) => match b {
-                                b if b == 0 => {
-                                    info!("Last SUBS points substracted");
-                                    let mut t = token.lock().await;
-                                    t.not_avaliable();
-                                    info!("Token is no more avaliable");
-                                    sync_next(server, neighbor.clone()).await;
-                                    neighbor
-                                        .send("SEND\n".to_string())
-                                        .await
-                                        .expect("Could not send token");
-                                    return "ACK\n".to_string();
-                                }
-                                b if b > 0 => {
-                                    info!("SUBS points substracted");
-                                    return "ACK\n".to_string();
-                                }
-                                _ => {
-                                    error!("Invalid blocked_points_left");
-                                    "NOT ACK\n".to_string()
-                                }
-                            },
-                            Err(_) => {
-                                error!("Fail sanding subs to server actor");
-                                "NOT ACK\n".to_string()
-                            }
-                        },
-                        Err(_) => {
-                            error!("Fail sanding subs to server actor");
-                            "NOT ACK\n".to_string()
+    /// Participant of a transfer leg: this server or the owner of the
+    /// account reached through a `TX` connection.
+    enum Participant {
+        Local,
+        Remote(BufReader<Stream>),
+    }
+
+    impl Participant {
+        /// Without sharding every server has every account. With sharding the
+        /// leg goes to the first owner alive, which may be this server.
+        async fn resolve(
+            id: u8,
+            ring: Option<&HashRing>,
+            customer_id: u32,
+        ) -> Result<Participant, ServerError> {
+            let ring = match ring {
+                Some(ring) => ring,
+                None => return Ok(Participant::Local),
+            };
+            for owner in ring.owners(customer_id) {
+                if owner == id {
+                    return Ok(Participant::Local);
+                }
+                match tls::connect(owner).await {
+                    Ok(mut stream) => {
+                        if stream.write_all(b"TX\n").await.is_ok() {
+                            return Ok(Participant::Remote(BufReader::new(stream)));
                         }
                     }
-                } else {
-                    error!("NO operation result = OK");
-                    "NOT ACK\n".to_string()
+                    Err(e) => warn!("Transfer participant {} unreachable: {}", owner, e),
                 }
             }
-            None => {
-                error!("NO operation result = OK");
-                "NOT ACK\n".to_string()
+            Err(ServerError::Unavailable)
+        }
+
+        async fn prepare(
+            &mut self,
+            local: &LocalParticipant,
+            transaction_id: &str,
+            leg: &TransferLeg,
+        ) -> Result<(), ServerError> {
+            match self {
+                Participant::Local => local.prepare(leg).await,
+                Participant::Remote(conn) => {
+                    remote_request(conn, &leg.prepare_message(transaction_id)).await
+                }
             }
-        };
-        response
+        }
+
+        async fn commit(
+            &mut self,
+            local: &LocalParticipant,
+            transaction_id: &str,
+            leg: &TransferLeg,
+        ) -> Result<(), ServerError> {
+            match self {
+                Participant::Local => local.commit(leg).await,
+                Participant::Remote(conn) => {
+                    remote_request(conn, &format!("COMMIT,{}\n", transaction_id)).await
+                }
+            }
+        }
+
+        async fn abort(
+            &mut self,
+            local: &LocalParticipant,
+            transaction_id: &str,
+            leg: &TransferLeg,
+        ) -> Result<(), ServerError> {
+            match self {
+                Participant::Local => local.abort(leg).await,
+                Participant::Remote(conn) => {
+                    remote_request(conn, &format!("ABORT,{}\n", transaction_id)).await
+                }
+            }
+        }
     }
 
-    async fn handle_req_message(
-        server: Addr<LocalServer>,
-        notify: Arc<Notify>,
-        customer_id: u32,
-        points: u32,
-    ) -> String {
-        info!("REQ message!");
-        notify.notified().await;
-        let msg = BlockPoints {
-            customer_id,
-            points,
+    /// Sends a transfer message and maps `YES`/`ACK` to success and
+    /// `NO,<code>`/`NOT ACK,<code>` to the rejection.
+    async fn remote_request(
+        conn: &mut BufReader<Stream>,
+        message: &str,
+    ) -> Result<(), ServerError> {
+        let mut response = String::new();
+        let read = match conn.write_all(signer::sign(message).as_bytes()).await {
+            Ok(_) => conn.read_line(&mut response).await.unwrap_or(0),
+            Err(_) => 0,
         };
-        let response: String;
-        match server.send(msg).await.unwrap() {
-            Ok(_) => {
-                response = "OK\n".to_string();
-            }
-            Err(_) => {
-                error!(
-                    "Error trying to block {} points for account {}",
-                    points, customer_id
+        if read == 0 {
+            error!("Transfer participant lost answering {}", message.trim());
+            return Err(ServerError::Unavailable);
+        }
+        let parts: Vec<&str> = response.split(',').map(|s| s.trim()).collect();
+        match parts[0] {
+            "YES" | "ACK" => Ok(()),
+            _ => {
+                warn!(
+                    "Transfer participant rejected {}: {}",
+                    message.trim(),
+                    response.trim()
                 );
-                response = "NOT OK\n".to_string();
+                match parts.get(1) {
+                    Some(code) => Err(ServerError::RemoteRejection(code.to_string())),
+                    None => Err(ServerError::Unavailable),
+                }
             }
         }
-        notify.notify_one();
-        response
     }
 
-    async fn sync_next(server_address: Addr<LocalServer>, sender: Sender<String>) {
-        match server_address.send(SyncNextServer {}).await {
-            Ok(accounts) => {
-                for account in accounts {
-                    let message = format!("SYNC,{},{}\n", account.customer_id, account.points);
-                    debug!(
-                        "Sync {} to customer id {}",
-                        account.customer_id, account.points
+    /// Coordinates `TRANSFER,<from>,<to>,<points>` with a two-phase commit:
+    /// both legs are prepared (the debit blocks the points) and only if both
+    /// accept the debit consumes the points and the credit adds them,
+    /// otherwise the prepared legs are rolled back.
+    async fn handle_transfer_message(
+        local: LocalParticipant,
+        id: u8,
+        ring: Option<&HashRing>,
+        from: u32,
+        to: u32,
+        points: Points,
+    ) -> Result<(), ServerError> {
+        info!("TRANSFER of {} points from {} to {}", points, from, to);
+        if from == to || points.is_zero() {
+            return Err(ServerError::InvalidPoints(PointsError::Invalid(format!(
+                "transfer of {} points from {} to {}",
+                points, from, to
+            ))));
+        }
+        let transaction_id = format!("{}-{}", id, transaction_nanos());
+        let legs = [
+            TransferLeg::debit(from, points),
+            TransferLeg::credit(to, points),
+        ];
+        let mut participants = vec![];
+        for leg in legs.iter() {
+            participants.push(Participant::resolve(id, ring, leg.customer_id).await?);
+        }
+
+        let mut prepared = 0;
+        let mut rejection = None;
+        for (leg, participant) in legs.iter().zip(participants.iter_mut()) {
+            match participant.prepare(&local, &transaction_id, leg).await {
+                Ok(_) => prepared += 1,
+                Err(e) => {
+                    rejection = Some(e);
+                    break;
+                }
+            }
+        }
+
+        if let Some(e) = rejection {
+            warn!("Transfer {} aborted: {}", transaction_id, e);
+            for (leg, participant) in legs.iter().zip(participants.iter_mut()).take(prepared) {
+                if let Err(abort_error) = participant.abort(&local, &transaction_id, leg).await {
+                    error!(
+                        "Could not abort {} leg of transfer {}: {}",
+                        leg.role, transaction_id, abort_error
                     );
-                    sender
-                        .send(message)
-                        .await
-                        .expect("Could not send syc message through channel");
-                    thread::sleep(Duration::from_secs(1));
                 }
-                info!("Sync accounts to next neighbor finished");
             }
-            Err(_) => error!("Fail trying to sync next server"),
+            return Err(e);
+        }
+
+        let (debit, credit) = participants.split_at_mut(1);
+        if let Err(e) = debit[0].commit(&local, &transaction_id, &legs[0]).await {
+            error!(
+                "Could not commit debit of transfer {}: {}",
+                transaction_id, e
+            );
+            for (leg, participant) in legs.iter().zip(participants.iter_mut()) {
+                if let Err(abort_error) = participant.abort(&local, &transaction_id, leg).await {
+                    error!(
+                        "Could not abort {} leg of transfer {}: {}",
+                        leg.role, transaction_id, abort_error
+                    );
+                }
+            }
+            return Err(e);
+        }
+        if let Err(e) = credit[0].commit(&local, &transaction_id, &legs[1]).await {
+            error!(
+                "Could not commit credit of transfer {}: {}",
+                transaction_id, e
+            );
+            if let Err(refund_error) =
+                refund(&mut debit[0], &local, &transaction_id, &legs[0]).await
+            {
+                error!(
+                    "Could not give back {} points to account {} after transfer {}: {}",
+                    points, from, transaction_id, refund_error
+                );
+            }
+            return Err(e);
         }
+        info!("Transfer {} committed", transaction_id);
+        Ok(())
     }
 
-    async fn recovery(id: u8, servers: u8) {
-        let mut port = id - 1;
-        if id == 1 {
-            port = servers
+    /// Credits the points of a committed debit back to the source account,
+    /// as a transfer of its own on the same participant, when the credit of
+    /// the transfer could not be committed.
+    async fn refund(
+        participant: &mut Participant,
+        local: &LocalParticipant,
+        transaction_id: &str,
+        debit: &TransferLeg,
+    ) -> Result<(), ServerError> {
+        let refund_id = format!("{}-refund", transaction_id);
+        let leg = TransferLeg::credit(debit.customer_id, debit.points);
+        participant.prepare(local, &refund_id, &leg).await?;
+        participant.commit(local, &refund_id, &leg).await?;
+        warn!(
+            "{} points given back to account {} after transfer {}",
+            debit.points, debit.customer_id, transaction_id
+        );
+        Ok(())
+    }
+
+    fn transaction_nanos() -> u128 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_nanos(),
+            Err(_) => 0,
         }
-        let socket = format!("127.0.0.1:888{}", port);
-        let message = format!("RECOVERY,{}", id);
+    }
 
-        match TcpStream::connect(socket).await {
-            Ok(mut s) => match s.write_all(message.as_bytes()).await {
+    /// Serves the legs sent by a transfer coordinator. Legs still prepared
+    /// when the coordinator disconnects are aborted.
+    pub async fn handle_transfer_connection(
+        mut reader: BufReader<io::ReadHalf<Stream>>,
+        mut w: io::WriteHalf<Stream>,
+        local: LocalParticipant,
+    ) {
+        let mut prepared: HashMap<String, TransferLeg> = HashMap::new();
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line).await {
+                Ok(0) | Err(_) => break,
                 Ok(_) => {
-                    debug!("Send RECOVERY to left neighbor");
-                }
-                Err(_) => {
-                    error!("Error sending RECOVERY to left neighbor");
-                }
+                    let line = match signer::verify(&line) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            error!("Rejected transfer message: {}", e);
+                            break;
+                        }
+                    };
+                    let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+                    let response = match parts[0] {
+                        "PREPARE" => match TransferLeg::parse_prepare(&parts) {
+                            Ok((transaction_id, leg)) => match local.prepare(&leg).await {
+                                Ok(_) => {
+                                    prepared.insert(transaction_id, leg);
+                                    "YES\n".to_string()
+                                }
+                                Err(e) => format!("NO,{}\n", e.code()),
+                            },
+                            Err(e) => {
+                                protocol::reject("TX", &ProtocolError::InvalidField("PREPARE", e))
+                            }
+                        },
+                        "COMMIT" => match protocol::field::<String>(&parts, 1, "transaction") {
+                            Ok(transaction_id) => match prepared.remove(&transaction_id) {
+                                Some(leg) => {
+                                    let result = local.commit(&leg).await;
+                                    // Kept so the coordinator can still abort it.
+                                    if result.is_err() {
+                                        prepared.insert(transaction_id, leg);
+                                    }
+                                    ack_response(result)
+                                }
+                                None => ack_response(Err(ServerError::NoReservation)),
+                            },
+                            Err(e) => protocol::reject("TX", &e),
+                        },
+                        "ABORT" => match protocol::field::<String>(&parts, 1, "transaction") {
+                            Ok(transaction_id) => match prepared.remove(&transaction_id) {
+                                Some(leg) => ack_response(local.abort(&leg).await),
+                                None => ack_response(Ok(())),
+                            },
+                            Err(e) => protocol::reject("TX", &e),
+                        },
+                        message => {
+                            let error = ProtocolError::UnknownMessage(message.to_string());
+                            let _ = w.write_all(protocol::reject("TX", &error).as_bytes()).await;
+                            break;
+                        }
+                    };
+                    if w.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        for (transaction_id, leg) in prepared.drain() {
+            warn!("Coordinator of transfer {} lost, aborting", transaction_id);
+            let _ = local.abort(&leg).await;
+        }
+    }
+
+    async fn handle_add_message(
+        server: Addr<LocalServer>,
+        customer_id: u32,
+        points: Points,
+        order: CoffeeOrder,
+    ) -> String {
+        info!("ADD received");
+        let msg = AddPoints {
+            customer_id,
+            points,
+            order: Some(order),
+        };
+        let result = server
+            .send(msg)
+            .await
+            .unwrap_or(Err(ServerError::Unavailable));
+
+        ack_response(result)
+    }
+
+    async fn handle_adjust_message(
+        server: Addr<LocalServer>,
+        customer_id: u32,
+        points: i64,
+    ) -> String {
+        info!("ADJ received");
+        let msg = AdjustPoints {
+            customer_id,
+            points,
+        };
+        let result = server
+            .send(msg)
+            .await
+            .unwrap_or(Err(ServerError::Unavailable));
+
+        ack_response(result)
+    }
+
+    async fn handle_status_message(
+        server: Addr<LocalServer>,
+        customer_id: u32,
+        state: AccountState,
+    ) -> String {
+        info!("{} received", state.code());
+        let msg = ChangeStatus { customer_id, state };
+        let result = server
+            .send(msg)
+            .await
+            .unwrap_or(Err(ServerError::Unavailable));
+
+        ack_response(result)
+    }
+
+    async fn handle_unblock_message(
+        server: Addr<LocalServer>,
+        neighbor: Sender<String>,
+        token: Arc<Mutex<Token>>,
+        last_operation: &Option<String>,
+        customer_id: u32,
+        points: Points,
+        coffee_maker: Option<String>,
+    ) -> Result<(), ServerError> {
+        info!("UNBL received");
+        let result = match check_reservation(&token, last_operation, customer_id).await {
+            Ok(_) => {
+                let msg = UnblockPoints {
+                    customer_id,
+                    points,
+                    coffee_maker,
+                };
+                server
+                    .send(msg)
+                    .await
+                    .unwrap_or(Err(ServerError::Unavailable))
+            }
+            Err(e) => Err(e),
+        };
+        finish_settlement("UNBL", customer_id, result, server, neighbor, token).await
+    }
+
+    async fn handle_subs_message(
+        server: Addr<LocalServer>,
+        neighbor: Sender<String>,
+        token: Arc<Mutex<Token>>,
+        last_operation: &Option<String>,
+        customer_id: u32,
+        points: Points,
+        coffee_maker: Option<String>,
+    ) -> Result<(), ServerError> {
+        info!("SUBS received");
+        let result = match check_reservation(&token, last_operation, customer_id).await {
+            Ok(_) => {
+                let msg = SubtractPoints {
+                    customer_id,
+                    points,
+                    coffee_maker,
+                };
+                server
+                    .send(msg)
+                    .await
+                    .unwrap_or(Err(ServerError::Unavailable))
+            }
+            Err(e) => Err(e),
+        };
+        finish_settlement("SUBS", customer_id, result, server, neighbor, token).await
+    }
+
+    /// `ADD` and `REQ` may name the product of the order as a fourth field,
+    /// used by promotions.
+    fn coffee_order(parts: &[&str], coffee_maker: &Option<String>) -> CoffeeOrder {
+        CoffeeOrder {
+            product: parts
+                .get(3)
+                .filter(|product| !product.is_empty())
+                .map(|product| product.to_string()),
+            coffee_maker: coffee_maker.clone(),
+        }
+    }
+
+    /// `HIST,<customer_id>,<entries>` answers `HIST,<entry>;...` with the
+    /// latest movements of the account, newest first.
+    async fn handle_history_message(
+        server: Addr<LocalServer>,
+        parts: &[&str],
+        connection: &'static str,
+    ) -> String {
+        info!("HIST received");
+        let fields = protocol::field::<u32>(parts, 1, "customer_id")
+            .and_then(|customer_id| Ok((customer_id, protocol::field(parts, 2, "entries")?)));
+        let (customer_id, entries) = match fields {
+            Ok(fields) => fields,
+            Err(e) => return protocol::reject(connection, &e),
+        };
+        let result = server
+            .send(GetLedger {
+                customer_id,
+                entries,
+            })
+            .await
+            .unwrap_or(Err(ServerError::Unavailable));
+        match result {
+            Ok(entries) => format!("HIST,{}\n", Ledger::encode_entries(entries.iter())),
+            Err(e) => format!("NOT OK,{}\n", e.code()),
+        }
+    }
+
+    /// `<operation>,<customer_id>,<points>` of ADD, REQ, SUBS and UNBL.
+    fn account_and_points(parts: &[&str]) -> Result<(u32, Points), ProtocolError> {
+        Ok((
+            protocol::field(parts, 1, "customer_id")?,
+            protocol::field(parts, 2, "points")?,
+        ))
+    }
+
+    /// `TRANSFER,<from>,<to>,<points>`.
+    fn transfer_fields(parts: &[&str]) -> Result<(u32, u32, Points), ProtocolError> {
+        Ok((
+            protocol::field(parts, 1, "from")?,
+            protocol::field(parts, 2, "to")?,
+            protocol::field(parts, 3, "points")?,
+        ))
+    }
+
+    /// `SYNC,<customer_id>,<redeemed>,<expired>,<orders>,<earned>,<status>,<ledger>`.
+    fn sync_account(parts: &[&str]) -> Result<SyncAccount, ProtocolError> {
+        Ok(SyncAccount {
+            customer_id: protocol::field(parts, 1, "customer_id")?,
+            redeemed: protocol::field(parts, 2, "redeemed")?,
+            expired: protocol::field(parts, 3, "expired")?,
+            orders: protocol::field(parts, 4, "orders")?,
+            earned: protocol::decode(parts, 5, "earned", GCounter::decode)?,
+            status: protocol::decode(parts, 6, "status", AccountStatus::decode)?,
+            ledger: protocol::decode(parts, 7, "ledger", Ledger::decode)?,
+        })
+    }
+
+    /// `GOSSIP,<customer_id>,<earned>,<status>,<ledger>`.
+    fn merge_earned(parts: &[&str]) -> Result<MergeEarned, ProtocolError> {
+        Ok(MergeEarned {
+            customer_id: protocol::field(parts, 1, "customer_id")?,
+            earned: protocol::decode(parts, 2, "earned", GCounter::decode)?,
+            status: protocol::decode(parts, 3, "status", AccountStatus::decode)?,
+            ledger: protocol::decode(parts, 4, "ledger", Ledger::decode)?,
+        })
+    }
+
+    /// TOKEN and ELECTION are forwarded to the right neighbor as they come,
+    /// so they are checked before the ring task parses them.
+    fn check_ring_message(parts: &[&str]) -> Result<(), ProtocolError> {
+        match parts[0] {
+            "TOKEN" => {
+                protocol::field::<u8>(parts, 1, "servers")?;
+                protocol::field::<u128>(parts, 2, "timestamp")?;
+            }
+            "ELECTION" => {
+                protocol::field::<u128>(parts, 1, "timestamp")?;
+            }
+            "LEAVE" => {
+                protocol::field::<u8>(parts, 1, "server")?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Checks the `SH,<fingerprint>` handshake of the left neighbor. Servers
+    /// with other promotions or catalog would credit the same orders
+    /// differently, so they are answered with ERR and kept out of the ring.
+    pub async fn accept_server_handshake(
+        parts: &[&str],
+        fingerprint: u64,
+        w: &mut io::WriteHalf<Stream>,
+    ) -> bool {
+        let result = protocol::field::<u64>(parts, 1, "fingerprint").and_then(|theirs| {
+            if theirs != fingerprint {
+                return Err(ProtocolError::RulesMismatch {
+                    ours: fingerprint,
+                    theirs,
+                });
+            }
+            Ok(())
+        });
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Left neighbor rejected: {}", e);
+                let _ = w.write_all(protocol::reject("SH", &e).as_bytes()).await;
+                false
+            }
+        }
+    }
+
+    /// `ERRORS` answers `ERRORS,<connection>:<count>;...` with the malformed
+    /// lines received per connection type.
+    fn handle_errors_message() -> String {
+        format!(
+            "ERRORS,{}\n",
+            protocol::failures()
+                .iter()
+                .map(|(connection, count)| format!("{}:{}", connection, count))
+                .collect::<Vec<String>>()
+                .join(";")
+        )
+    }
+
+    /// `FLAGS` answers `FLAGS,<customer_id>:<rule>;...` with the accounts
+    /// flagged for review by the anomaly rules of this server.
+    async fn handle_flags_message(server: Addr<LocalServer>) -> String {
+        info!("FLAGS received");
+        match server.send(FlaggedAccounts {}).await {
+            Ok(flagged) => format!(
+                "FLAGS,{}\n",
+                flagged
+                    .iter()
+                    .map(|(customer_id, rule)| format!("{}:{}", customer_id, rule))
+                    .collect::<Vec<String>>()
+                    .join(";")
+            ),
+            Err(_) => format!("NOT OK,{}\n", ServerError::Unavailable.code()),
+        }
+    }
+
+    /// Coffee makers register in the handshake,
+    /// `CH,<machine id>,<location>,<firmware>`, so the ledger, the history
+    /// and the logs tell which one served each order.
+    pub fn coffee_maker_registration(parts: &[&str]) -> Option<Registration> {
+        Registration::parse(parts)
+    }
+
+    async fn handle_req_message(
+        server: Addr<LocalServer>,
+        token: Arc<Mutex<Token>>,
+        notify: Arc<Notify>,
+        customer_id: u32,
+        points: Points,
+        order: CoffeeOrder,
+    ) -> Result<Points, ServerError> {
+        info!("REQ message!");
+        reserve_points(server, token, notify, customer_id, points, Some(order)).await
+    }
+
+    /// Blocks points once this server may serve the account, on its token
+    /// turn or while owning the shard of the account. Returns the points
+    /// blocked once promotions are applied.
+    async fn reserve_points(
+        server: Addr<LocalServer>,
+        token: Arc<Mutex<Token>>,
+        notify: Arc<Notify>,
+        customer_id: u32,
+        points: Points,
+        order: Option<CoffeeOrder>,
+    ) -> Result<Points, ServerError> {
+        let sharded = token.lock().await.is_sharded();
+        if sharded {
+            wait_shard_turn(&token, &notify, customer_id).await;
+        } else {
+            wait_token_turn(&token, &notify).await;
+        }
+        let msg = BlockPoints {
+            customer_id,
+            points,
+            order,
+        };
+        let result = match server
+            .send(msg)
+            .await
+            .unwrap_or(Err(ServerError::Unavailable))
+        {
+            Ok(blocked) => Ok(blocked),
+            Err(e) => {
+                error!(
+                    "Error trying to block {} points for account {}: {}",
+                    points, customer_id, e
+                );
+                if let Some(locks) = token.lock().await.locks_mut() {
+                    locks.end_reservation(customer_id);
+                }
+                Err(e)
+            }
+        };
+        if !sharded {
+            notify.notify_one();
+        }
+        result
+    }
+
+    /// Waits until the token is held and the hold policy still allows
+    /// serving another REQ on this visit, otherwise the REQ waits for the
+    /// next lap.
+    async fn wait_token_turn(token: &Arc<Mutex<Token>>, notify: &Arc<Notify>) {
+        loop {
+            notify.notified().await;
+            let mut t = token.lock().await;
+            if t.can_serve() {
+                t.register_operation();
+                return;
+            }
+            debug!("Token visit exhausted, REQ waits for the next lap");
+        }
+    }
+
+    /// Waits until this server owns the shard of the account. The shard is
+    /// requested on the next token visit and kept while a reservation on it
+    /// is in flight.
+    async fn wait_shard_turn(token: &Arc<Mutex<Token>>, notify: &Arc<Notify>, customer_id: u32) {
+        let mut waiting = false;
+        loop {
+            let notified = notify.notified();
+            {
+                let mut t = token.lock().await;
+                if let Some(locks) = t.locks_mut() {
+                    if locks.owns(customer_id) {
+                        if waiting {
+                            locks.stop_waiting(customer_id);
+                        }
+                        locks.begin_reservation(customer_id);
+                        return;
+                    }
+                    if !waiting {
+                        locks.wait_for(customer_id);
+                        waiting = true;
+                    }
+                }
+            }
+            debug!("Waiting shard of account {}", customer_id);
+            notified.await;
+        }
+    }
+
+    /// Handles a token visit in shard mode: updates the shard table it
+    /// carries, wakes the REQs whose shard was acquired, syncs the accounts
+    /// and forwards the token right away.
+    async fn pass_shard_locks(
+        parts: &[&str],
+        token: Arc<Mutex<Token>>,
+        notify: &Arc<Notify>,
+        server: Addr<LocalServer>,
+        neighbor: Sender<String>,
+    ) {
+        let table = parts.get(3).copied().unwrap_or("");
+        let mut t = token.lock().await;
+        let forward = match t.locks_mut() {
+            Some(locks) => locks.update_table(table),
+            None => Ok(table.to_string()),
+        };
+        let forward = forward.unwrap_or_else(|e| {
+            error!("Invalid shard table {}: {}", table, e);
+            table.to_string()
+        });
+        let shards = t.locks_mut().map(|locks| locks.filter());
+        expire_points(&server, shards.clone()).await;
+        drop(t);
+        notify.notify_waiters();
+        sync_next(server, neighbor.clone(), shards).await;
+        neighbor
+            .send(format!("TOKEN,{},{},{}\n", parts[1], parts[2], forward))
+            .await
+            .expect("could not send token through channel");
+    }
+
+    /// SUBS and UNBL settle the points blocked by the last REQ of the
+    /// session, which can only succeed while this server holds the token or
+    /// the shard of the account.
+    async fn check_reservation(
+        token: &Arc<Mutex<Token>>,
+        last_operation: &Option<String>,
+        customer_id: u32,
+    ) -> Result<(), ServerError> {
+        if last_operation.as_deref() != Some(OK_RESPONSE) {
+            error!("NO operation result = OK");
+            return Err(ServerError::NoReservation);
+        }
+        if !token.lock().await.can_settle(customer_id) {
+            return Err(ServerError::NotTokenHolder);
+        }
+        Ok(())
+    }
+
+    async fn finish_settlement(
+        operation: &str,
+        customer_id: u32,
+        result: Result<Points, ServerError>,
+        server: Addr<LocalServer>,
+        neighbor: Sender<String>,
+        token: Arc<Mutex<Token>>,
+    ) -> Result<(), ServerError> {
+        {
+            let mut t = token.lock().await;
+            if let Some(locks) = t.locks_mut() {
+                return match result {
+                    Ok(_) => {
+                        locks.end_reservation(customer_id);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!("{} rejected: {}", operation, e);
+                        Err(e)
+                    }
+                };
+            }
+        }
+        match result {
+            Ok(blocked_points_left) if blocked_points_left.is_zero() => {
+                info!("Last {} points substracted", operation);
+                let mut t = token.lock().await;
+                t.not_avaliable();
+                info!("Token is no more avaliable");
+                sync_next(server, neighbor.clone(), None).await;
+                neighbor
+                    .send("SEND\n".to_string())
+                    .await
+                    .expect("Could not send token");
+                Ok(())
+            }
+            Ok(_) => {
+                info!("{} points substracted", operation);
+                Ok(())
+            }
+            Err(e) => {
+                error!("{} rejected: {}", operation, e);
+                release_exhausted_token(server, neighbor, token).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// A visit that ran out of budget with nothing left blocked never
+    /// reaches a settlement that frees the token, so it is passed here.
+    async fn release_exhausted_token(
+        server: Addr<LocalServer>,
+        neighbor: Sender<String>,
+        token: Arc<Mutex<Token>>,
+    ) {
+        let mut t = token.lock().await;
+        if !t.is_avaliable() || !t.is_exhausted() {
+            return;
+        }
+        match server.send(GlobalBlockedPoints {}).await {
+            Ok(blocked_points) if blocked_points.is_zero() => {
+                t.not_avaliable();
+                drop(t);
+                info!("Token visit exhausted, passing token to next server");
+                sync_next(server, neighbor.clone(), None).await;
+                neighbor
+                    .send("SEND\n".to_string())
+                    .await
+                    .expect("Could not send token");
+            }
+            Ok(_) => debug!("Waiting in flight REQs before passing the token"),
+            Err(_) => error!("Fail asking blocked points to server actor"),
+        }
+    }
+
+    fn ack_response(result: Result<(), ServerError>) -> String {
+        match result {
+            Ok(_) => "ACK\n".to_string(),
+            Err(e) => format!("NOT ACK,{}\n", e.code()),
+        }
+    }
+
+    async fn sync_next(
+        server_address: Addr<LocalServer>,
+        sender: Sender<String>,
+        shards: Option<ShardFilter>,
+    ) {
+        match server_address.send(SyncNextServer { shards }).await {
+            Ok(accounts) => {
+                for account in accounts {
+                    let message = sync_message(&account);
+                    debug!("Sync customer id {}", account.customer_id);
+                    sender
+                        .send(message)
+                        .await
+                        .expect("Could not send syc message through channel");
+                }
+                info!("Sync accounts to next neighbor finished");
+            }
+            Err(_) => error!("Fail trying to sync next server"),
+        }
+    }
+
+    /// Takes this server out of the ring: no new reservations or coffee
+    /// makers are taken, the REQs in flight are given `drain_timeout` to
+    /// be settled, the accounts are synced to the right neighbor and the
+    /// token is passed on if held. `LEAVE,<id>` is sent last, once it went
+    /// around the ring the left neighbor no longer sends anything here.
+    pub async fn leave_ring(
+        id: u8,
+        token: Arc<Mutex<Token>>,
+        connections: Arc<Mutex<i32>>,
+        server: Addr<LocalServer>,
+        sender: Sender<String>,
+        drain_timeout: Duration,
+    ) {
+        if server.send(StopAccepting {}).await.is_err() {
+            error!("Could not stop the server actor");
+        }
+        let deadline = Instant::now() + drain_timeout;
+        loop {
+            let in_flight = *connections.lock().await;
+            if in_flight <= 0 {
+                info!("Reservations drained");
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Leaving with {} reservations in flight", in_flight);
+                break;
+            }
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        if sender.is_closed() {
+            warn!("No neighbor left to hand the accounts over to");
+            return;
+        }
+        let (send_token, shards) = {
+            let mut t = token.lock().await;
+            let held = !t.is_sharded() && t.is_avaliable();
+            if held {
+                t.not_avaliable();
+            }
+            (held, t.locks_mut().map(|locks| locks.filter()))
+        };
+        sync_next(server, sender.clone(), shards).await;
+        if send_token {
+            info!("Passing token before leaving");
+            if sender.send("SEND\n".to_string()).await.is_err() {
+                error!("Could not pass the token");
+            }
+        }
+        if sender.send(format!("LEAVE,{}\n", id)).await.is_err() {
+            error!("Could not announce LEAVE");
+        }
+    }
+
+    /// Expires old lots on a token visit, while no other server can redeem
+    /// from the swept accounts. With shard locks only the owned shards are
+    /// swept and the lock is held until the sweep ends.
+    async fn expire_points(server: &Addr<LocalServer>, shards: Option<ShardFilter>) {
+        let msg = ExpirePoints {
+            today: today(),
+            shards,
+        };
+        match server.send(msg).await {
+            Ok(points) if !points.is_zero() => info!("{} points expired", points),
+            Ok(_) => {}
+            Err(_) => error!("Fail asking server actor to expire points"),
+        }
+    }
+
+    /// `SYNC,<customer_id>,<redeemed>,<expired>,<orders>,<earned counter>,<status>,<ledger>`
+    /// sent by the token holder to its right neighbor.
+    pub fn sync_message(account: &Account) -> String {
+        format!(
+            "SYNC,{},{},{},{},{},{},{}\n",
+            account.customer_id,
+            account.redeemed,
+            account.expired,
+            account.orders,
+            account.earned.encode(),
+            account.status.encode(),
+            account.ledger.encode()
+        )
+    }
+
+    /// `GOSSIP,<customer_id>,<earned counter>,<status>,<ledger>` sent to the
+    /// right neighbor whenever the earned points, the status or the ledger
+    /// of an account change, with or without the token.
+    pub fn gossip_message(account: &Account) -> String {
+        format!(
+            "GOSSIP,{},{},{},{}\n",
+            account.customer_id,
+            account.earned.encode(),
+            account.status.encode(),
+            account.ledger.encode()
+        )
+    }
+
+    async fn recovery(id: u8, servers: u8) {
+        let mut port = id - 1;
+        if id == 1 {
+            port = servers
+        }
+        let message = format!("RECOVERY,{}", id);
+
+        match tls::connect(port).await {
+            Ok(mut s) => match s.write_all(signer::sign(&message).as_bytes()).await {
+                Ok(_) => {
+                    debug!("Send RECOVERY to left neighbor");
+                }
+                Err(_) => {
+                    error!("Error sending RECOVERY to left neighbor");
+                }
             },
             Err(_) => todo!(),
         }
     }
+
+    #[cfg(test)]
+    mod handlers_messages_test {
+        use super::*;
+        use crate::structs::controller_auth::Operator;
+        use crate::structs::token::HoldPolicy;
+        use actix::SyncArbiter;
+        use tokio::sync::mpsc::{self, Receiver};
+
+        /// Participant owning every account through a single shard, so the
+        /// legs run without waiting for a token.
+        fn local_participant() -> (LocalParticipant, Receiver<String>) {
+            let server = SyncArbiter::start(1, || LocalServer::new().unwrap());
+            let mut token = Token::with_shards(HoldPolicy::default(), 1, 1);
+            let locks = token.locks_mut().unwrap();
+            locks.wait_for(0);
+            locks.update_table("").unwrap();
+            locks.stop_waiting(0);
+            let (tx, rx) = mpsc::channel(10);
+            let local = LocalParticipant::new(
+                server,
+                Arc::new(Mutex::new(token)),
+                Arc::new(Notify::new()),
+                Arc::new(Mutex::new(0)),
+                tx,
+            );
+            (local, rx)
+        }
+
+        /// Both ends of a plain connection, the server one split as the
+        /// handlers get it.
+        async fn session() -> (
+            BufReader<io::ReadHalf<Stream>>,
+            io::WriteHalf<Stream>,
+            BufReader<tokio::net::TcpStream>,
+        ) {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let client = tokio::net::TcpStream::connect(address).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (r, w) = io::split(Stream::Plain(server));
+            (BufReader::new(r), w, BufReader::new(client))
+        }
+
+        /// Answers the challenge of the server with the given secret and
+        /// returns what the server replied.
+        async fn answer_challenge(
+            client: &mut BufReader<tokio::net::TcpStream>,
+            secret: &str,
+        ) -> String {
+            let mut challenge = String::new();
+            client.read_line(&mut challenge).await.unwrap();
+            let nonce = challenge.trim().strip_prefix("CHALLENGE,").unwrap();
+            let operator = Operator {
+                name: "alice".to_string(),
+                secret: secret.to_string(),
+            };
+            let answer = format!("AUTH,alice,{}\n", operator.answer(nonce));
+            client.get_mut().write_all(answer.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_line(&mut response).await.unwrap();
+            response
+        }
+
+        fn operators() -> ControllerAuth {
+            ControllerAuth::new(vec![Operator {
+                name: "alice".to_string(),
+                secret: "s3cret".to_string(),
+            }])
+        }
+
+        #[actix_rt::test]
+        async fn test_controller_answering_the_challenge_is_the_operator() {
+            let (mut reader, mut w, mut client) = session().await;
+            let auth = operators();
+            let audit = Mutex::new(AuditLog::disabled());
+
+            let (operator, response) = tokio::join!(
+                authenticate_operator(&mut reader, &mut w, &auth, &audit),
+                answer_challenge(&mut client, "s3cret")
+            );
+
+            assert_eq!(operator, Some("alice".to_string()));
+            assert_eq!(response, "ACK\n");
+        }
+
+        #[actix_rt::test]
+        async fn test_controller_with_a_wrong_secret_is_rejected() {
+            let (mut reader, mut w, mut client) = session().await;
+            let auth = operators();
+            let audit = Mutex::new(AuditLog::disabled());
+
+            let (operator, response) = tokio::join!(
+                authenticate_operator(&mut reader, &mut w, &auth, &audit),
+                answer_challenge(&mut client, "guess")
+            );
+
+            assert_eq!(operator, None);
+            assert_eq!(response, "NOT ACK,UNAUTHORIZED\n");
+        }
+
+        #[actix_rt::test]
+        async fn test_without_operators_there_is_no_challenge() {
+            let (mut reader, mut w, _client) = session().await;
+            let audit = Mutex::new(AuditLog::disabled());
+
+            let operator =
+                authenticate_operator(&mut reader, &mut w, &ControllerAuth::default(), &audit)
+                    .await;
+
+            assert_eq!(operator, Some(ANONYMOUS.to_string()));
+        }
+
+        async fn add(local: &LocalParticipant, customer_id: u32, points: u64) {
+            let _ = local
+                .server
+                .send(AddPoints {
+                    customer_id,
+                    points: Points::new(points),
+                    order: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        async fn block(local: &LocalParticipant, customer_id: u32, points: u64) -> bool {
+            local
+                .server
+                .send(BlockPoints {
+                    customer_id,
+                    points: Points::new(points),
+                    order: None,
+                })
+                .await
+                .unwrap()
+                .is_ok()
+        }
+
+        async fn coffee_session(local: &LocalParticipant, client_messages: &[u8]) -> Vec<String> {
+            let (reader, w, mut client) = session().await;
+            client.get_mut().write_all(client_messages).await.unwrap();
+            let read_responses = async {
+                let mut responses = Vec::new();
+                let mut line = String::new();
+                while client.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    responses.push(line.clone());
+                    line.clear();
+                }
+                responses
+            };
+            let (_, responses) = tokio::join!(
+                handle_coffe_connection(
+                    reader,
+                    w,
+                    local.token.clone(),
+                    local.notify.clone(),
+                    local.connections.clone(),
+                    local.server.clone(),
+                    local.neighbor.clone(),
+                    1,
+                    None,
+                    None,
+                    Duration::from_millis(100),
+                ),
+                read_responses
+            );
+            responses
+        }
+
+        /// Answer of the server to an `SH` handshake, empty if accepted.
+        async fn server_handshake(line: &str, fingerprint: u64) -> (bool, String) {
+            let (reader, mut w, mut client) = session().await;
+            let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+            let accepted = accept_server_handshake(&parts, fingerprint, &mut w).await;
+            drop((reader, w));
+            let mut response = String::new();
+            let _ = client.read_line(&mut response).await;
+            (accepted, response)
+        }
+
+        #[actix_rt::test]
+        async fn test_server_with_other_rules_is_rejected() {
+            assert_eq!(server_handshake("SH,42", 42).await, (true, String::new()));
+            assert_eq!(
+                server_handshake("SH,41", 42).await,
+                (
+                    false,
+                    "ERR,RULES_MISMATCH,rules fingerprint 41 differs from 42\n".to_string()
+                )
+            );
+            assert_eq!(
+                server_handshake("SH", 42).await,
+                (false, "ERR,MISSING_FIELD,missing fingerprint\n".to_string())
+            );
+        }
+
+        #[actix_rt::test]
+        async fn test_coffee_maker_heartbeat_is_answered() {
+            let (local, _rx) = local_participant();
+
+            let responses = coffee_session(&local, b"PING\n").await;
+
+            assert_eq!(responses, vec!["PONG\n".to_string()]);
+        }
+
+        #[actix_rt::test]
+        async fn test_malformed_coffee_maker_lines_are_answered_without_closing() {
+            let (local, _rx) = local_participant();
+
+            let responses = coffee_session(&local, b"REQ,abc\nADD,1\nPING\nFOO\nPING\n").await;
+
+            assert_eq!(
+                responses,
+                vec![
+                    "ERR,INVALID_FIELD,invalid customer_id abc\n".to_string(),
+                    "ERR,MISSING_FIELD,missing points\n".to_string(),
+                    "PONG\n".to_string(),
+                    "ERR,UNKNOWN_MESSAGE,unknown message FOO\n".to_string(),
+                ]
+            );
+            assert_eq!(*local.connections.lock().await, 0);
+            assert!(protocol::failures()
+                .iter()
+                .any(|(c, n)| *c == "CH" && *n >= 3));
+        }
+
+        #[actix_rt::test]
+        async fn test_coffee_makers_are_served_while_accounts_are_synced() {
+            let server = SyncArbiter::start(1, || LocalServer::new().unwrap());
+            for customer_id in 1..=3 {
+                let _ = server
+                    .send(AddPoints {
+                        customer_id,
+                        points: Points::new(10),
+                        order: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            // The SYNC stream stalls on the neighbor channel until it is read.
+            let (tx, mut rx) = mpsc::channel(1);
+            let local = LocalParticipant::new(
+                server.clone(),
+                Arc::new(Mutex::new(Token::new())),
+                Arc::new(Notify::new()),
+                Arc::new(Mutex::new(0)),
+                tx.clone(),
+            );
+            let (reader, w, mut left) = session().await;
+            let timing = Timing {
+                idle_token_delay_millis: 0,
+                ..Timing::default()
+            };
+
+            let ring = handle_server_connection(
+                reader,
+                w,
+                local.token.clone(),
+                local.notify.clone(),
+                local.connections.clone(),
+                server,
+                tx,
+                Arc::new(Mutex::new(true)),
+                timing,
+            );
+            let neighbor = async {
+                left.get_mut().write_all(b"TOKEN,2,1\n").await.unwrap();
+                let first = rx.recv().await.unwrap();
+                let started = Instant::now();
+                let responses = coffee_session(&local, b"PING\n").await;
+                let served_in = started.elapsed();
+                let mut rest = vec![];
+                while let Some(message) = rx.recv().await {
+                    let token = message.starts_with("TOKEN");
+                    rest.push(message);
+                    if token {
+                        break;
+                    }
+                }
+                drop(left);
+                (first, responses, served_in, rest)
+            };
+            let (_, (first, responses, served_in, rest)) = tokio::join!(ring, neighbor);
+
+            assert!(first.starts_with("SYNC"));
+            assert_eq!(responses, vec!["PONG\n".to_string()]);
+            assert!(served_in < Duration::from_millis(500));
+            assert_eq!(rest.len(), 3);
+            assert!(rest[2].starts_with("TOKEN"));
+        }
+
+        #[actix_rt::test]
+        async fn test_idle_coffee_maker_session_unblocks_its_reservation() {
+            let (local, _rx) = local_participant();
+            add(&local, 1, 10).await;
+
+            let responses = coffee_session(&local, b"REQ, 1, 4\n").await;
+
+            assert_eq!(responses, vec![OK_RESPONSE.to_string()]);
+            assert_eq!(*local.connections.lock().await, 0);
+            assert!(block(&local, 1, 10).await);
+        }
+
+        /// Participant of a ring with a single global token, held by it.
+        fn token_holder() -> (LocalParticipant, Receiver<String>) {
+            let server = SyncArbiter::start(1, || LocalServer::new().unwrap());
+            let mut token = Token::with_policy(HoldPolicy::default());
+            token.avaliable();
+            let (tx, rx) = mpsc::channel(10);
+            let local = LocalParticipant::new(
+                server,
+                Arc::new(Mutex::new(token)),
+                Arc::new(Notify::new()),
+                Arc::new(Mutex::new(0)),
+                tx,
+            );
+            (local, rx)
+        }
+
+        async fn leave(local: &LocalParticipant, drain_timeout: Duration) {
+            leave_ring(
+                1,
+                local.token.clone(),
+                local.connections.clone(),
+                local.server.clone(),
+                local.neighbor.clone(),
+                drain_timeout,
+            )
+            .await;
+        }
+
+        #[actix_rt::test]
+        async fn test_leaving_server_syncs_passes_the_token_and_announces_leave() {
+            let (local, mut rx) = token_holder();
+            add(&local, 1, 10).await;
+
+            leave(&local, Duration::from_secs(1)).await;
+
+            assert!(rx.recv().await.unwrap().starts_with("SYNC,1,"));
+            assert_eq!(rx.recv().await.unwrap(), "SEND\n");
+            assert_eq!(rx.recv().await.unwrap(), "LEAVE,1\n");
+            assert!(!local.token.lock().await.is_avaliable());
+            assert_eq!(
+                coffee_session(&local, b"REQ, 1, 4\n").await,
+                vec!["NOT ACK,SHUTTING_DOWN\n".to_string()]
+            );
+        }
+
+        #[actix_rt::test]
+        async fn test_leaving_server_waits_for_reservations_in_flight() {
+            let (local, mut rx) = token_holder();
+            *local.connections.lock().await = 1;
+            let connections = local.connections.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(300)).await;
+                *connections.lock().await -= 1;
+            });
+            let start = Instant::now();
+
+            leave(&local, Duration::from_secs(5)).await;
+
+            assert!(start.elapsed() >= Duration::from_millis(300));
+            assert_eq!(rx.recv().await.unwrap(), "SEND\n");
+            assert_eq!(rx.recv().await.unwrap(), "LEAVE,1\n");
+        }
+
+        #[actix_rt::test]
+        async fn test_leaving_server_stops_waiting_after_the_drain_timeout() {
+            let (local, mut rx) = local_participant();
+            *local.connections.lock().await = 1;
+            let start = Instant::now();
+
+            leave(&local, Duration::from_millis(200)).await;
+
+            assert!(start.elapsed() < Duration::from_secs(2));
+            assert_eq!(rx.recv().await.unwrap(), "LEAVE,1\n");
+            assert_eq!(*local.connections.lock().await, 1);
+        }
+
+        #[actix_rt::test]
+        async fn test01_transfer_moves_the_points() {
+            let (local, _rx) = local_participant();
+            add(&local, 1, 10).await;
+            add(&local, 2, 1).await;
+
+            let result =
+                handle_transfer_message(local.clone(), 1, None, 1, 2, Points::new(4)).await;
+
+            assert_eq!(result, Ok(()));
+            assert!(!block(&local, 1, 7).await);
+            assert!(block(&local, 1, 6).await);
+            assert!(block(&local, 2, 5).await);
+            assert_eq!(*local.connections.lock().await, 0);
+        }
+
+        #[actix_rt::test]
+        async fn test02_rejected_transfer_is_rolled_back() {
+            let (local, _rx) = local_participant();
+            add(&local, 1, 10).await;
+
+            let result =
+                handle_transfer_message(local.clone(), 1, None, 1, 2, Points::new(20)).await;
+
+            assert_eq!(
+                result.map_err(|e| e.code().to_string()),
+                Err("INSUFFICIENT_POINTS".to_string())
+            );
+            assert!(block(&local, 1, 10).await);
+            assert!(!block(&local, 2, 1).await);
+            assert_eq!(*local.connections.lock().await, 0);
+        }
+
+        #[actix_rt::test]
+        async fn test03_transfer_to_the_same_account_is_invalid() {
+            let (local, _rx) = local_participant();
+
+            let result =
+                handle_transfer_message(local.clone(), 1, None, 1, 1, Points::new(4)).await;
+
+            assert!(matches!(result, Err(ServerError::InvalidPoints(_))));
+        }
+
+        #[actix_rt::test]
+        async fn test04_transfer_to_a_closed_account_keeps_the_source_points() {
+            let (local, _rx) = local_participant();
+            add(&local, 1, 10).await;
+            add(&local, 2, 1).await;
+            let _ = local
+                .server
+                .send(ChangeStatus {
+                    customer_id: 2,
+                    state: AccountState::Closed,
+                })
+                .await
+                .unwrap();
+
+            let result =
+                handle_transfer_message(local.clone(), 1, None, 1, 2, Points::new(4)).await;
+
+            assert_eq!(result, Err(ServerError::AccountClosed(2)));
+            assert!(block(&local, 1, 10).await);
+            assert_eq!(*local.connections.lock().await, 0);
+        }
+
+        #[actix_rt::test]
+        async fn test05_transfer_to_a_missing_account_keeps_the_source_points() {
+            let (local, _rx) = local_participant();
+            add(&local, 1, 10).await;
+
+            let result =
+                handle_transfer_message(local.clone(), 1, None, 1, 2, Points::new(4)).await;
+
+            assert_eq!(result, Err(ServerError::AccountNotFound(2)));
+            assert!(block(&local, 1, 10).await);
+            assert!(!block(&local, 2, 1).await);
+            assert_eq!(*local.connections.lock().await, 0);
+        }
+    }
 }
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::structs::history::HistoryEntry;
+
+#[derive(Debug, PartialEq)]
+pub enum ViolationKind {
+    NegativeBalance,
+    SettlementWithoutReservation,
+    DivergentAccounts,
+}
+
+#[derive(Debug)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub description: String,
+    pub operations: Vec<HistoryEntry>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{:?}] {}", self.kind, self.description)?;
+        for operation in &self.operations {
+            writeln!(f, "    {}", operation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays recorded histories from servers and coffee makers and reports
+/// every invariant violation found. Histories are expected to be taken
+/// once the system is quiescent, otherwise in flight syncs show up as
+/// divergent accounts.
+pub struct HistoryChecker {
+    entries: Vec<HistoryEntry>,
+}
+
+impl HistoryChecker {
+    pub fn new(mut entries: Vec<HistoryEntry>) -> Self {
+        entries.sort_by_key(|e| e.timestamp);
+        Self { entries }
+    }
+
+    pub fn check(&self) -> Vec<Violation> {
+        let mut violations = self.check_balances();
+        violations.append(&mut self.check_settlements());
+        violations.append(&mut self.check_convergence());
+        violations
+    }
+
+    /// Points never go negative when earning, consuming and adjusting
+    /// operations of every server are applied in timestamp order. Closed
+    /// accounts have no balance, so they are not followed after CLOSE.
+    fn check_balances(&self) -> Vec<Violation> {
+        let mut violations = vec![];
+        let mut balances: HashMap<u32, i128> = HashMap::new();
+        let mut closed: HashSet<u32> = HashSet::new();
+        for entry in self.entries.iter() {
+            if !entry.is_from_server() || !entry.is_success() {
+                continue;
+            }
+            if closed.contains(&entry.customer_id) {
+                continue;
+            }
+            let delta = match entry.operation.as_str() {
+                "CLOSE" => {
+                    closed.insert(entry.customer_id);
+                    continue;
+                }
+                "ADD" | "ADJ" | "CREDIT" => entry.points as i128,
+                "SUBS" | "EXPIRE" => -(entry.points as i128),
+                _ => continue,
+            };
+            let balance = balances.entry(entry.customer_id).or_insert(0);
+            *balance += delta;
+            if *balance < 0 {
+                violations.push(Violation {
+                    kind: ViolationKind::NegativeBalance,
+                    description: format!(
+                        "Account {} reached {} points",
+                        entry.customer_id, balance
+                    ),
+                    operations: vec![entry.clone()],
+                });
+            }
+        }
+        violations
+    }
+
+    /// Every SUBS, and every successful UNBL, consumes a previous successful
+    /// REQ for the same account and amount issued by the same origin.
+    fn check_settlements(&self) -> Vec<Violation> {
+        let mut violations = vec![];
+        let mut reservations: HashMap<(&str, u32), Vec<&HistoryEntry>> = HashMap::new();
+        for entry in self.entries.iter() {
+            let key = (entry.origin.as_str(), entry.customer_id);
+            match entry.operation.as_str() {
+                "REQ" if entry.is_success() => {
+                    reservations.entry(key).or_default().push(entry);
+                }
+                "SUBS" | "UNBL" => {
+                    if entry.operation == "UNBL" && !entry.is_success() {
+                        continue;
+                    }
+                    let pending = reservations.entry(key).or_default();
+                    match pending.iter().position(|r| r.points == entry.points) {
+                        Some(position) => {
+                            pending.remove(position);
+                        }
+                        None => violations.push(Violation {
+                            kind: ViolationKind::SettlementWithoutReservation,
+                            description: format!(
+                                "{} of {} points for account {} without a successful REQ",
+                                entry.operation, entry.points, entry.customer_id
+                            ),
+                            operations: vec![entry.clone()],
+                        }),
+                    }
+                }
+                _ => {}
+            }
+        }
+        violations
+    }
+
+    /// Every server ends up with the same balance for every account.
+    fn check_convergence(&self) -> Vec<Violation> {
+        let mut last_known: BTreeMap<u32, BTreeMap<&str, &HistoryEntry>> = BTreeMap::new();
+        for entry in self.entries.iter() {
+            if entry.is_from_server() && entry.balance.is_some() {
+                last_known
+                    .entry(entry.customer_id)
+                    .or_default()
+                    .insert(entry.origin.as_str(), entry);
+            }
+        }
+
+        let mut violations = vec![];
+        for (customer_id, by_server) in last_known {
+            let balances: BTreeSet<Option<u64>> = by_server.values().map(|e| e.balance).collect();
+            if balances.len() > 1 {
+                violations.push(Violation {
+                    kind: ViolationKind::DivergentAccounts,
+                    description: format!(
+                        "Servers disagree on the balance of account {}",
+                        customer_id
+                    ),
+                    operations: by_server.values().map(|e| (*e).clone()).collect(),
+                });
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod history_checker_test {
+    use super::*;
+
+    fn entries(lines: &[&str]) -> Vec<HistoryEntry> {
+        lines
+            .iter()
+            .map(|l| HistoryEntry::parse(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test01_consistent_history_has_no_violations() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,server-1,ADD,1,10,OK,10",
+            "2,coffee-9,REQ,1,5,OK,-",
+            "3,server-1,REQ,1,5,OK,10",
+            "4,server-1,SUBS,1,5,OK,5",
+            "5,coffee-9,SUBS,1,5,OK,-",
+            "6,server-2,SYNC,1,5,OK,5",
+        ]));
+
+        assert!(checker.check().is_empty());
+    }
+
+    #[test]
+    fn test02_consuming_more_than_earned_is_reported() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,server-1,ADD,1,10,OK,10",
+            "2,server-2,SUBS,1,15,OK,0",
+        ]));
+
+        let violations = checker.check_balances();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::NegativeBalance);
+        assert_eq!(violations[0].operations[0].origin, "server-2");
+    }
+
+    #[test]
+    fn test03_negative_adjustment_below_zero_is_reported() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,server-1,ADD,1,10,OK,10",
+            "2,server-1,ADJ,1,-4,OK,6",
+            "3,server-2,ADJ,1,-7,OK,0",
+        ]));
+
+        let violations = checker.check_balances();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].operations[0].origin, "server-2");
+    }
+
+    #[test]
+    fn test04_subs_without_request_is_reported() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,coffee-9,REQ,1,5,ERR,-",
+            "2,coffee-9,SUBS,1,5,OK,-",
+        ]));
+
+        let violations = checker.check_settlements();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            violations[0].kind,
+            ViolationKind::SettlementWithoutReservation
+        );
+    }
+
+    #[test]
+    fn test05_subs_with_different_amount_is_reported() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,coffee-9,REQ,1,5,OK,-",
+            "2,coffee-9,SUBS,1,7,OK,-",
+        ]));
+
+        assert_eq!(checker.check_settlements().len(), 1);
+    }
+
+    #[test]
+    fn test06_failed_unblock_after_failed_request_is_allowed() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,coffee-9,REQ,1,5,ERR,-",
+            "2,coffee-9,UNBL,1,5,ERR,-",
+        ]));
+
+        assert!(checker.check_settlements().is_empty());
+    }
+
+    #[test]
+    fn test07_divergent_servers_are_reported() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,server-1,ADD,1,10,OK,10",
+            "2,server-2,SYNC,1,10,OK,10",
+            "3,server-1,ADD,1,5,OK,15",
+        ]));
+
+        let violations = checker.check_convergence();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::DivergentAccounts);
+        assert_eq!(violations[0].operations.len(), 2);
+    }
+
+    #[test]
+    fn test08_settlements_after_close_are_not_balanced() {
+        let checker = HistoryChecker::new(entries(&[
+            "1,server-1,ADD,1,10,OK,10",
+            "2,server-1,REQ,1,10,OK,10",
+            "3,server-2,CLOSE,1,0,OK,0",
+            "4,server-1,SUBS,1,10,OK,0",
+            "5,server-1,SUBS,1,10,OK,0",
+        ]));
+
+        assert!(checker.check_balances().is_empty());
+    }
+}
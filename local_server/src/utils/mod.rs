@@ -1 +1,7 @@
-pub mod handlers_messages;
+pub mod config;
+pub mod forwarder;
+pub mod handlers_messages;
+pub mod history_checker;
+pub mod protocol;
+pub mod signer;
+pub mod tls;
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use log::warn;
+
+/// Line that does not follow the protocol. It is answered with
+/// `ERR,<code>,<reason>` instead of bringing the connection down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    MissingField(&'static str),
+    InvalidField(&'static str, String),
+    UnknownMessage(String),
+    /// A server handshake with promotions or catalog other than ours.
+    RulesMismatch {
+        ours: u64,
+        theirs: u64,
+    },
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProtocolError::MissingField(_) => "MISSING_FIELD",
+            ProtocolError::InvalidField(_, _) => "INVALID_FIELD",
+            ProtocolError::UnknownMessage(_) => "UNKNOWN_MESSAGE",
+            ProtocolError::RulesMismatch { .. } => "RULES_MISMATCH",
+        }
+    }
+
+    /// `ERR,<code>,<reason>` line, with the reason stripped of the
+    /// separators of the protocol.
+    pub fn reply(&self) -> String {
+        let reason: String = self
+            .to_string()
+            .chars()
+            .map(|c| if c == ',' || c.is_control() { ' ' } else { c })
+            .collect();
+        format!("ERR,{},{}\n", self.code(), reason.trim())
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::MissingField(name) => write!(f, "missing {}", name),
+            ProtocolError::InvalidField(name, value) => write!(f, "invalid {} {}", name, value),
+            ProtocolError::UnknownMessage(message) => write!(f, "unknown message {}", message),
+            ProtocolError::RulesMismatch { ours, theirs } => {
+                write!(f, "rules fingerprint {} differs from {}", theirs, ours)
+            }
+        }
+    }
+}
+
+/// Field `index` of a message split by commas.
+pub fn field<T: FromStr>(
+    parts: &[&str],
+    index: usize,
+    name: &'static str,
+) -> Result<T, ProtocolError> {
+    decode(parts, index, name, |value| value.parse::<T>())
+}
+
+/// Field `index` of a message, decoded with one of the encodings of the
+/// protocol.
+pub fn decode<T, E>(
+    parts: &[&str],
+    index: usize,
+    name: &'static str,
+    decoder: impl Fn(&str) -> Result<T, E>,
+) -> Result<T, ProtocolError> {
+    let value = parts.get(index).ok_or(ProtocolError::MissingField(name))?;
+    decoder(value).map_err(|_| ProtocolError::InvalidField(name, value.to_string()))
+}
+
+static FAILURES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+/// Logs and counts a malformed line received on a connection of the given
+/// type (`CH`, `SH`, `CTRL`, ...).
+pub fn record(connection: &'static str, error: &ProtocolError) {
+    warn!("Malformed {} message: {}", connection, error);
+    if let Ok(mut failures) = FAILURES.lock() {
+        *failures.entry(connection).or_insert(0) += 1;
+    }
+}
+
+/// Records the error and returns the line answering it.
+pub fn reject(connection: &'static str, error: &ProtocolError) -> String {
+    record(connection, error);
+    error.reply()
+}
+
+/// Malformed lines received by this process per connection type.
+pub fn failures() -> Vec<(&'static str, u64)> {
+    match FAILURES.lock() {
+        Ok(failures) => failures.iter().map(|(c, n)| (*c, *n)).collect(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod protocol_test {
+    use super::*;
+    use crate::structs::g_counter::GCounter;
+    use crate::structs::points::Points;
+
+    #[test]
+    fn test_fields_are_parsed_or_reported() {
+        let parts = ["REQ", "abc"];
+
+        assert_eq!(
+            field::<String>(&parts, 0, "operation"),
+            Ok("REQ".to_string())
+        );
+        assert_eq!(
+            field::<u32>(&parts, 1, "customer_id"),
+            Err(ProtocolError::InvalidField(
+                "customer_id",
+                "abc".to_string()
+            ))
+        );
+        assert_eq!(
+            field::<Points>(&parts, 2, "points"),
+            Err(ProtocolError::MissingField("points"))
+        );
+        assert!(decode(&["GOSSIP", "1", "x"], 2, "earned", GCounter::decode).is_err());
+    }
+
+    #[test]
+    fn test_replies_keep_the_line_format() {
+        let error = ProtocolError::InvalidField("customer_id", "a,b\n".to_string());
+
+        assert_eq!(error.reply(), "ERR,INVALID_FIELD,invalid customer_id a b\n");
+        assert_eq!(
+            ProtocolError::MissingField("points").reply(),
+            "ERR,MISSING_FIELD,missing points\n"
+        );
+    }
+
+    #[test]
+    fn test_failures_are_counted_per_connection() {
+        let error = ProtocolError::UnknownMessage("FOO".to_string());
+
+        assert_eq!(
+            reject("TEST", &error),
+            "ERR,UNKNOWN_MESSAGE,unknown message FOO\n"
+        );
+        record("TEST", &error);
+
+        assert!(failures().contains(&("TEST", 2)));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use crate::structs::controller_auth::{from_hex, to_hex};
+
+const SEPARATOR: char = '|';
+
+/// How far behind the newest sequence number of its sender, or behind our
+/// clock, a line may be and still be accepted once.
+const REPLAY_WINDOW_MICROS: u64 = 5 * 60 * 1_000_000;
+
+/// Signs the messages servers send each other with the key of the cluster,
+/// as `<message>|<sender>|<sequence>|<HMAC-SHA256 of the rest in hex>`.
+///
+/// Sequence numbers are microseconds since the epoch, bumped by one when
+/// the clock has not moved, so they keep growing across restarts. Each
+/// sequence of a sender is accepted once; lines from its other connections
+/// may arrive out of order within the replay window.
+pub struct Signer {
+    key: hmac::Key,
+    id: u8,
+    sequence: AtomicU64,
+    seen: Mutex<HashMap<u8, SeenSequences>>,
+}
+
+/// Sequence numbers accepted from one sender within the replay window.
+#[derive(Default)]
+struct SeenSequences {
+    newest: u64,
+    recent: BTreeSet<u64>,
+}
+
+impl SeenSequences {
+    fn accept(&mut self, sequence: u64, now: u64) -> Result<(), &'static str> {
+        if sequence <= self.oldest(now) {
+            return Err("expired sequence number");
+        }
+        if !self.recent.insert(sequence) {
+            return Err("replayed sequence number");
+        }
+        self.newest = self.newest.max(sequence);
+        self.recent = self.recent.split_off(&(self.oldest(now) + 1));
+        Ok(())
+    }
+
+    fn oldest(&self, now: u64) -> u64 {
+        self.newest.max(now).saturating_sub(REPLAY_WINDOW_MICROS)
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+impl Signer {
+    pub fn new(cluster_key: &str, id: u8) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, cluster_key.as_bytes()),
+            id,
+            sequence: AtomicU64::new(0),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Signed line, ending with a new line.
+    pub fn sign(&self, message: &str) -> String {
+        self.sign_at(message, now_micros())
+    }
+
+    /// The message of a signed line, without its signature.
+    pub fn verify(&self, line: &str) -> Result<String, String> {
+        self.verify_at(line, now_micros())
+    }
+
+    fn sign_at(&self, message: &str, now: u64) -> String {
+        let next = |last: u64| now.max(last + 1);
+        let sequence =
+            match self
+                .sequence
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            {
+                Ok(last) | Err(last) => next(last),
+            };
+        let payload = format!(
+            "{}{}{}{}{}",
+            message.trim_end(),
+            SEPARATOR,
+            self.id,
+            SEPARATOR,
+            sequence
+        );
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        format!("{}{}{}\n", payload, SEPARATOR, to_hex(tag.as_ref()))
+    }
+
+    fn verify_at(&self, line: &str, now: u64) -> Result<String, String> {
+        let (payload, tag) = line
+            .trim_end()
+            .rsplit_once(SEPARATOR)
+            .ok_or("unsigned message")?;
+        let tag = from_hex(tag).ok_or("malformed signature")?;
+        hmac::verify(&self.key, payload.as_bytes(), &tag).map_err(|_| "invalid signature")?;
+        let (rest, sequence) = payload
+            .rsplit_once(SEPARATOR)
+            .ok_or("missing sequence number")?;
+        let (message, sender) = rest.rsplit_once(SEPARATOR).ok_or("missing sender")?;
+        let sender = sender.parse::<u8>().map_err(|_| "invalid sender")?;
+        let sequence = sequence
+            .parse::<u64>()
+            .map_err(|_| "invalid sequence number")?;
+        self.seen
+            .lock()
+            .map_err(|_| "replay state unavailable")?
+            .entry(sender)
+            .or_default()
+            .accept(sequence, now)?;
+        Ok(format!("{}\n", message))
+    }
+}
+
+static SIGNER: OnceLock<Signer> = OnceLock::new();
+
+/// Makes this process sign and require signatures on the SH, REPL, TX and
+/// RECOVERY connections. Set once at startup.
+pub fn install(signer: Signer) -> Result<(), String> {
+    SIGNER
+        .set(signer)
+        .map_err(|_| "Signer already installed".to_string())
+}
+
+/// Signs a message for another server, unchanged without cluster key.
+pub fn sign(message: &str) -> String {
+    match SIGNER.get() {
+        Some(signer) => signer.sign(message),
+        None => message.to_string(),
+    }
+}
+
+/// Checks a line received from another server. Without cluster key every
+/// line is accepted as is.
+pub fn verify(line: &str) -> Result<String, String> {
+    match SIGNER.get() {
+        Some(signer) => signer.verify(line),
+        None => Ok(line.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod signer_test {
+    use super::*;
+
+    #[test]
+    fn test_signed_messages_are_verified_without_signature() {
+        let signer = Signer::new("cluster", 1);
+
+        let signed = signer.sign("SYNC,1,10,0,1,1@0:10,OPEN@0@0,\n");
+
+        assert!(signed.starts_with("SYNC,1,10,0,1,1@0:10,OPEN@0@0,|"));
+        assert_eq!(
+            signer.verify(&signed),
+            Ok("SYNC,1,10,0,1,1@0:10,OPEN@0@0,\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unsigned_and_tampered_messages_are_rejected() {
+        let signer = Signer::new("cluster", 3);
+        let signed = signer.sign("TOKEN,3,1000");
+
+        assert!(signer.verify("TOKEN,3,1000\n").is_err());
+        assert!(signer
+            .verify(&signed.replace("TOKEN,3", "TOKEN,2"))
+            .is_err());
+        assert!(signer.verify("TOKEN,3,1000|zz\n").is_err());
+    }
+
+    #[test]
+    fn test_messages_signed_with_another_key_are_rejected() {
+        let signed = Signer::new("other", 1).sign("ELECTION, 10");
+
+        assert!(Signer::new("cluster", 2).verify(&signed).is_err());
+    }
+
+    #[test]
+    fn test_replayed_messages_are_rejected() {
+        let sender = Signer::new("cluster", 1);
+        let receiver = Signer::new("cluster", 2);
+        let now = 10 * REPLAY_WINDOW_MICROS;
+        let first = sender.sign_at("TOKEN,3,1000", now);
+        let second = sender.sign_at("TOKEN,3,1000", now);
+
+        assert!(receiver.verify_at(&first, now).is_ok());
+        assert!(receiver.verify_at(&second, now).is_ok());
+        assert_eq!(
+            receiver.verify_at(&first, now),
+            Err("replayed sequence number".to_string())
+        );
+        assert!(receiver.verify_at(&second, now + 1).is_err());
+    }
+
+    #[test]
+    fn test_reordered_messages_are_accepted_within_the_window() {
+        let sender = Signer::new("cluster", 1);
+        let receiver = Signer::new("cluster", 2);
+        let now = 10 * REPLAY_WINDOW_MICROS;
+        let sync = sender.sign_at("SYNC,1,10,0,1,1@0:10,OPEN@0@0,", now);
+        let token = sender.sign_at("TOKEN,3,1000", now + 1);
+        let late = sender.sign_at("PREPARE,1-1,CREDIT,2,10", now + 2);
+
+        assert!(receiver.verify_at(&token, now + 1).is_ok());
+        assert!(receiver.verify_at(&sync, now + 1).is_ok());
+        assert_eq!(
+            receiver.verify_at(&late, now + 2 + REPLAY_WINDOW_MICROS),
+            Err("expired sequence number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers_grow_across_restarts() {
+        let now = 10 * REPLAY_WINDOW_MICROS;
+        let before = Signer::new("cluster", 1).sign_at("TOKEN,3,1000", now);
+        let after = Signer::new("cluster", 1).sign_at("TOKEN,3,1000", now + 1);
+        let receiver = Signer::new("cluster", 2);
+
+        assert!(receiver.verify_at(&before, now + 1).is_ok());
+        assert!(receiver.verify_at(&after, now + 1).is_ok());
+    }
+}
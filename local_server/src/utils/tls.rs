@@ -0,0 +1,367 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use serde_derive::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// Certificate of this process, with its private key, and the certificate
+/// of the authority that signs every peer of the ring. All PEM files.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    pub ca_file: String,
+}
+
+/// What a peer may do, given by the DNS name of its certificate:
+/// `server-<id>`, `coffee-<anything>` or `controller[-<anything>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    CoffeeMaker,
+    Controller,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Role> {
+        let kind = name.split(['-', '.']).next()?;
+        match kind {
+            "server" => Some(Role::Server),
+            "coffee" => Some(Role::CoffeeMaker),
+            "controller" => Some(Role::Controller),
+            _ => None,
+        }
+    }
+
+    /// Whether a peer with this role may open a connection of the given
+    /// type. Servers open coffee sessions when they forward orders of
+    /// accounts they do not own.
+    pub fn allows(&self, connection: &str) -> bool {
+        match connection {
+            "CH" => matches!(self, Role::CoffeeMaker | Role::Server),
+            "SH" | "RECOVERY" | "REPL" | "TX" => *self == Role::Server,
+            "CTRL" => *self == Role::Controller,
+            _ => false,
+        }
+    }
+}
+
+/// Name every server must have in its certificate, checked by whoever
+/// connects to it.
+pub fn server_name(id: u8) -> String {
+    format!("server-{}", id)
+}
+
+/// Connection with a peer, encrypted if the ring runs with TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Accepts peers only with a certificate signed by the authority of the
+/// ring, and connects to servers checking they are who they should be.
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl Tls {
+    pub fn from_settings(settings: &TlsSettings) -> Result<Tls, String> {
+        let provider = Arc::new(ring::default_provider());
+        let roots = Arc::new(load_roots(&settings.ca_file)?);
+        let certs = load_certs(&settings.cert_file)?;
+        let key = load_key(&settings.key_file)?;
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(client_config(settings)?),
+        })
+    }
+
+    /// Runs the handshake with a new peer and returns the role given by
+    /// its certificate.
+    pub async fn accept(&self, tcp: TcpStream) -> Result<(Stream, Role), String> {
+        let stream = self.acceptor.accept(tcp).await.map_err(|e| e.to_string())?;
+        let role = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(role_of)
+            .ok_or("Peer certificate has no known role")?;
+        Ok((Stream::Tls(Box::new(stream.into())), role))
+    }
+
+    pub async fn connect(&self, tcp: TcpStream, server_id: u8) -> io::Result<Stream> {
+        let name = ServerName::try_from(server_name(server_id))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = self.connector.connect(name, tcp).await?;
+        Ok(Stream::Tls(Box::new(stream.into())))
+    }
+}
+
+/// Client side configuration, also used by the controller.
+pub fn client_config(settings: &TlsSettings) -> Result<Arc<ClientConfig>, String> {
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    let roots = Arc::new(load_roots(&settings.ca_file)?);
+    let verifier = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_webpki_verifier(verifier)
+        .with_client_auth_cert(
+            load_certs(&settings.cert_file)?,
+            load_key(&settings.key_file)?,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(Arc::new(config))
+}
+
+static TLS: OnceLock<Tls> = OnceLock::new();
+
+/// Makes every connection of this process to other servers use TLS. Set
+/// once at startup, before the first connection.
+pub fn install(tls: Tls) -> Result<(), String> {
+    TLS.set(tls)
+        .map_err(|_| "TLS already installed".to_string())
+}
+
+/// Connects to the server with the given id, with TLS if installed.
+pub async fn connect(server_id: u8) -> io::Result<Stream> {
+    let tcp = TcpStream::connect(format!("127.0.0.1:888{}", server_id)).await?;
+    match TLS.get() {
+        Some(tls) => tls.connect(tcp, server_id).await,
+        None => Ok(Stream::Plain(tcp)),
+    }
+}
+
+/// Accepts a new peer. Without TLS every peer may open any connection.
+pub async fn accept(tcp: TcpStream) -> Result<(Stream, Option<Role>), String> {
+    match TLS.get() {
+        Some(tls) => tls
+            .accept(tcp)
+            .await
+            .map(|(stream, role)| (stream, Some(role))),
+        None => Ok((Stream::Plain(tcp), None)),
+    }
+}
+
+fn role_of(cert: &CertificateDer<'_>) -> Option<Role> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    let role = cert.valid_dns_names().find_map(Role::from_name);
+    role
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| e.to_string())?;
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path, e))?
+        .ok_or(format!("{}: no private key", path))
+}
+
+#[cfg(test)]
+mod tls_test {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    struct Authority {
+        cert: Certificate,
+        key: KeyPair,
+        dir: PathBuf,
+    }
+
+    impl Authority {
+        fn new(name: &str) -> Authority {
+            let dir = std::env::temp_dir().join(format!("tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::default();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join("ca.pem"), cert.pem()).unwrap();
+            Authority { cert, key, dir }
+        }
+
+        /// Settings of a peer with a certificate for `name`.
+        fn issue(&self, name: &str) -> TlsSettings {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &self.cert, &self.key)
+                .unwrap();
+            let path = |ext: &str| self.dir.join(format!("{}.{}", name, ext));
+            std::fs::write(path("pem"), cert.pem()).unwrap();
+            std::fs::write(path("key"), key.serialize_pem()).unwrap();
+            TlsSettings {
+                cert_file: path("pem").to_string_lossy().to_string(),
+                key_file: path("key").to_string_lossy().to_string(),
+                ca_file: self.dir.join("ca.pem").to_string_lossy().to_string(),
+            }
+        }
+    }
+
+    /// Accepts one peer with the certificate of `server-1` and answers the
+    /// first line with the role of the peer.
+    async fn serve(authority: &Authority) -> String {
+        let server = Tls::from_settings(&authority.issue("server-1")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            if let Ok((stream, role)) = server.accept(tcp).await {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let answer = format!("{:?}\n", role);
+                stream.get_mut().write_all(answer.as_bytes()).await.unwrap();
+            }
+        });
+        address
+    }
+
+    async fn ask_role(address: &str, server_id: u8, settings: &TlsSettings) -> io::Result<String> {
+        let client = Tls::from_settings(settings).unwrap();
+        let tcp = TcpStream::connect(address).await?;
+        let mut stream = BufReader::new(client.connect(tcp, server_id).await?);
+        stream.get_mut().write_all(b"HELLO\n").await?;
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        Ok(line.trim().to_string())
+    }
+
+    #[test]
+    fn test_role_is_given_by_the_certificate_name() {
+        assert_eq!(Role::from_name("server-2"), Some(Role::Server));
+        assert_eq!(Role::from_name("coffee-17"), Some(Role::CoffeeMaker));
+        assert_eq!(Role::from_name("controller"), Some(Role::Controller));
+        assert_eq!(Role::from_name("servers-2"), None);
+        assert_eq!(Role::from_name("intruder"), None);
+    }
+
+    #[test]
+    fn test_roles_open_only_their_connections() {
+        assert!(Role::CoffeeMaker.allows("CH"));
+        assert!(!Role::CoffeeMaker.allows("SH"));
+        assert!(!Role::CoffeeMaker.allows("CTRL"));
+        assert!(Role::Server.allows("CH"));
+        assert!(Role::Server.allows("REPL"));
+        assert!(!Role::Server.allows("CTRL"));
+        assert!(Role::Controller.allows("CTRL"));
+        assert!(!Role::Controller.allows("SH"));
+    }
+
+    #[tokio::test]
+    async fn test_peer_role_comes_from_its_client_certificate() {
+        let authority = Authority::new("roles");
+        let address = serve(&authority).await;
+
+        let role = ask_role(&address, 1, &authority.issue("coffee-1")).await;
+
+        assert_eq!(role.unwrap(), "CoffeeMaker");
+    }
+
+    #[tokio::test]
+    async fn test_peers_of_another_authority_are_rejected() {
+        let authority = Authority::new("ring");
+        let intruder = Authority::new("intruder");
+        let address = serve(&authority).await;
+
+        let role = ask_role(&address, 1, &intruder.issue("controller")).await;
+
+        assert!(role.is_err() || role.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_must_have_the_name_of_its_id() {
+        let authority = Authority::new("names");
+        let address = serve(&authority).await;
+
+        let role = ask_role(&address, 2, &authority.issue("server-3")).await;
+
+        assert!(role.is_err());
+    }
+}